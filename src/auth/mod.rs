@@ -0,0 +1,89 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+// Decides whether a client may connect, based on the login and passcode headers of its
+// CONNECT frame
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    fn authenticate(&self, login: Option<&str>, passcode: Option<&str>) -> bool;
+}
+
+// Lets everyone in
+#[derive(Debug)]
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _login: Option<&str>, _passcode: Option<&str>) -> bool {
+        true
+    }
+}
+
+// Checks credentials against a fixed set of logins
+pub struct StaticCredentials {
+    users: HashMap<String, String>,
+}
+
+impl Default for StaticCredentials {
+    fn default() -> StaticCredentials {
+        StaticCredentials::new()
+    }
+}
+
+impl StaticCredentials {
+    pub fn new() -> StaticCredentials {
+        StaticCredentials {
+            users: HashMap::new(),
+        }
+    }
+
+    // Allow a login with the given passcode
+    pub fn add(&mut self, login: &str, passcode: &str) {
+        self.users.insert(String::from(login), String::from(passcode));
+    }
+
+    // Read credentials from a file with one login:passcode pair per line
+    // Blank lines and lines starting with # are skipped.
+    pub fn from_file(path: &Path) -> Result<StaticCredentials, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let mut credentials = StaticCredentials::new();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find(':') {
+                Some(i) => credentials.add(&line[..i], &line[i + 1..]),
+                None => {
+                    return Err(format!("{}:{}: expected login:passcode", path.display(), n + 1));
+                },
+            }
+        }
+        Ok(credentials)
+    }
+}
+
+impl Authenticator for StaticCredentials {
+    fn authenticate(&self, login: Option<&str>, passcode: Option<&str>) -> bool {
+        match (login, passcode) {
+            (Some(login), Some(passcode)) => self.users.get(login).is_some_and(|p| p == passcode),
+            _ => false,
+        }
+    }
+}
+
+// Passcodes are left out so they don't end up in the logs
+impl fmt::Debug for StaticCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StaticCredentials")
+            .field("logins", &self.users.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
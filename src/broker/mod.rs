@@ -0,0 +1,1043 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::stomp::{Frame, StompCommand, is_reserved_header, newest_version};
+use super::config::{Config, SlowConsumerPolicy};
+use super::client::{frame_size, ClientSender};
+use super::metrics::Metrics;
+
+pub mod registry;
+use self::registry::{AckMode, DestinationRegistry, Subscription, is_queue, is_pattern, matches};
+
+const WRITER_CHECK_MS: u64 = 5;     // How often to see whether slow writers have caught up
+
+// Routes frames between connected clients
+pub struct Broker {
+    config: Config,
+    clients: HashMap<usize, ClientSender>,
+    registry: DestinationRegistry,
+    pending: HashMap<String, VecDeque<PendingMessage>>,     // Queued messages with no consumer
+    unacked: HashMap<u64, Unacked>,                         // Delivered messages, by ack id
+    ack_deadlines: VecDeque<u64>,                           // Ack ids, oldest delivery first
+    expired_acks: HashMap<u64, usize>,                      // Timed-out ack ids, to client
+    transactions: HashMap<(usize, String), Vec<Frame>>,     // Frames held until COMMIT
+    receipts: Vec<PendingReceipt>,                          // romp-sync receipts not sent yet
+    backlogs: HashMap<usize, Backlog>,                      // Frames waiting for a slow client
+    held: HashMap<usize, Held>,                             // Unacked and transaction frames
+    next_consumer: HashMap<String, usize>,                  // Round-robin position, by queue
+    next_message_id: u64,
+    next_ack_id: u64,
+    next_backlog_id: u64,
+    metrics: Arc<Metrics>,
+}
+
+// A message sent to a queue that had no subscribers, waiting for one
+struct PendingMessage {
+    message_id: String,
+    frame: Frame,           // The SEND frame it came from
+    redeliveries: usize,    // Times it has been delivered again after a NACK
+}
+
+// What became of a frame sent to a client
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sent {
+    Queued(usize),      // Handed to the client's writer, with its write ticket
+    Backlogged(u64),    // Held until the client has room for it, with its place in the backlog
+}
+
+// Frames held for a client whose write queue is full, in the order they were sent
+// They're handed to the client's writer as it makes room, so a slow client never holds up the
+// broker.
+#[derive(Default)]
+struct Backlog {
+    frames: VecDeque<(u64, Frame)>,
+    bytes: usize,       // Approximate memory held by the frames
+}
+
+// What's kept for a client until it acknowledges messages or finishes its transactions
+#[derive(Default)]
+struct Held {
+    count: usize,       // Unacked messages, open transactions and the frames they hold
+    bytes: usize,       // Approximate memory held by the frames
+}
+
+// The things a connection's budget (max_connection_budget) is shared between
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resource {
+    Writes,         // Frames waiting to be written to the client
+    Acks,           // Messages waiting for the client to acknowledge them
+    Transactions,   // Open transactions and the frames held in them
+    Receipts,       // romp-sync receipts waiting for their message to be written
+}
+
+impl Resource {
+    // Value of the romp-error-code header when this is what used up the budget
+    fn error_code(self) -> &'static str {
+        match self {
+            Resource::Writes => "BUDGET_WRITES",
+            Resource::Acks => "BUDGET_ACKS",
+            Resource::Transactions => "BUDGET_TRANSACTIONS",
+            Resource::Receipts => "BUDGET_RECEIPTS",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Resource::Writes => "Connection budget used up by frames waiting to be written.",
+            Resource::Acks => "Connection budget used up by unacknowledged messages.",
+            Resource::Transactions => "Connection budget used up by transactions.",
+            Resource::Receipts => "Connection budget used up by pending receipts.",
+        }
+    }
+}
+
+// The receipt for a romp-sync SEND, held until every subscriber has written the message
+struct PendingReceipt {
+    client: usize,                  // The sender
+    receipt: Frame,
+    deliveries: Vec<(usize, Sent)>, // Each subscriber the message went to
+}
+
+// A message delivered to a subscription that has to acknowledge it, waiting for an ACK or NACK
+struct Unacked {
+    client: usize,
+    subscription: String,
+    cumulative: bool,       // An ACK or NACK of a later message also covers this one
+    route: String,          // Where the message was sent, for redelivery
+    message_id: String,
+    frame: Frame,           // The SEND frame it came from
+    redeliveries: usize,
+    delivered: Instant,     // When it was sent, for the ack timeout
+}
+
+impl Broker {
+    pub fn new(config: Config, metrics: Arc<Metrics>) -> Broker {
+        Broker {
+            config,
+            clients: HashMap::new(),
+            registry: DestinationRegistry::new(),
+            pending: HashMap::new(),
+            unacked: HashMap::new(),
+            ack_deadlines: VecDeque::new(),
+            expired_acks: HashMap::new(),
+            transactions: HashMap::new(),
+            receipts: Vec::new(),
+            backlogs: HashMap::new(),
+            held: HashMap::new(),
+            next_consumer: HashMap::new(),
+            next_message_id: 0,
+            next_ack_id: 0,
+            next_backlog_id: 0,
+            metrics,
+        }
+    }
+
+    // Register a newly connected client
+    pub fn add_client(&mut self, client: usize, tx: ClientSender) {
+        self.clients.insert(client, tx);
+    }
+
+    // Forget about a client and drop its subscriptions
+    // Queue messages it never acknowledged go to the queue's other subscribers
+    pub fn remove_client(&mut self, client: usize) {
+        self.registry.remove_client(client);
+        self.clients.remove(&client);
+        self.backlogs.remove(&client);
+        self.metrics.set_subscribers(self.registry.counts());
+        self.forget_idle_queues();
+        // Transactions still open when a client goes away are aborted
+        self.transactions.retain(|&(owner, _), _| owner != client);
+
+        let mut ids: Vec<u64> = self.unacked.iter()
+            .filter(|&(_, message)| message.client == client)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort();
+        for id in ids {
+            if let Some(message) = self.unacked.remove(&id) {
+                self.redeliver(client, message);
+            }
+        }
+        self.expired_acks.retain(|_, &mut owner| owner != client);
+        self.held.remove(&client);
+        self.metrics.set_messages_unacked(self.unacked.len());
+    }
+
+    // Disconnect every client ahead of a shutdown
+    // Each one gets an ERROR, including the redirect target if one is configured
+    pub fn drain(&mut self) {
+        for tx in self.clients.values() {
+            let mut error = Frame::error("server shutting down", "Server is shutting down.");
+            if let Some(ref target) = self.config.redirect {
+                error.header_mut().set("romp-redirect", target);
+            }
+            if tx.send(error).is_err() {
+                debug!("[client {}] Went away before drain", tx.name());
+            }
+        }
+    }
+
+    // Determine whether anyone is subscribed to a destination, so applications embedding the
+    // broker can skip building messages nobody will receive
+    pub fn has_subscribers(&self, destination: &str) -> bool {
+        !self.registry.subscribers(&self.route(destination)).is_empty()
+    }
+
+    // Do the work that waits on client writers: hand backlogged frames to clients that have
+    // made room for them, and send the romp-sync receipts whose messages have all been written
+    // The server calls this between frames, and as often as next_poll asks while it's idle, so
+    // the broker never has to wait on a writer.
+    pub fn poll(&mut self) {
+        self.expire_unacked(Instant::now());
+        self.flush_backlogs();
+        let memory = self.clients.keys().map(|&client| self.memory(client)).sum();
+        self.metrics.set_connection_memory(memory);
+        if self.receipts.is_empty() {
+            return;
+        }
+        let receipts: Vec<PendingReceipt> = self.receipts.drain(..).collect();
+        for pending in receipts {
+            let clients = &self.clients;
+            // A client that's gone, or whose writer has stopped, will never write the message
+            let written = pending.deliveries.iter().all(|&(client, sent)| {
+                clients.get(&client).is_none_or(|tx| match sent {
+                    Sent::Queued(ticket) => tx.is_written(ticket),
+                    Sent::Backlogged(_) => tx.is_closed(),
+                })
+            });
+            if written {
+                self.send_to(pending.client, pending.receipt);
+            } else {
+                self.receipts.push(pending);
+            }
+        }
+    }
+
+    // How long the server can wait before calling poll, or None if poll has nothing to do
+    // Writers don't say when they've caught up, so while frames are backlogged or receipts are
+    // waiting on writes they're checked on every few milliseconds. Otherwise poll is only
+    // needed when the oldest unacknowledged message times out.
+    pub fn next_poll(&self) -> Option<Duration> {
+        let writers = if self.backlogs.is_empty() && self.receipts.is_empty() {
+            None
+        } else {
+            Some(Duration::from_millis(WRITER_CHECK_MS))
+        };
+        let acks = self.next_ack_deadline().map(|deadline| {
+            deadline.saturating_duration_since(Instant::now())
+        });
+        match (writers, acks) {
+            (Some(writers), Some(acks)) => Some(writers.min(acks)),
+            (writers, acks) => writers.or(acks),
+        }
+    }
+
+    // Deliver again the messages that haven't been acknowledged within the ack timeout, as of
+    // the given time
+    // Each goes to another of the queue's subscribers if it can, marked as redelivered, like
+    // a NACKed message. A late ACK or NACK for it is ignored.
+    pub fn expire_unacked(&mut self, now: Instant) {
+        let timeout = match self.config.ack_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let mut expired = Vec::new();
+        while let Some(&id) = self.ack_deadlines.front() {
+            match self.unacked.get(&id) {
+                Some(message) if now < message.delivered + timeout => break,
+                Some(_) => expired.push(id),
+                // Already acknowledged
+                None => {},
+            }
+            self.ack_deadlines.pop_front();
+        }
+        for id in expired {
+            let message = match self.unacked.remove(&id) {
+                Some(message) => message,
+                None => continue,
+            };
+            info!("Message {} wasn't acknowledged in time; redelivering", message.message_id);
+            self.expired_acks.insert(id, message.client);
+            self.metrics.set_messages_unacked(self.unacked.len());
+            self.release_held(message.client, 1, frame_size(&message.frame));
+            let client = message.client;
+            let route = message.route.clone();
+            self.redeliver(client, message);
+            // The subscription it timed out on has room for another message
+            if is_queue(&route) {
+                self.drain_pending(&route);
+            }
+        }
+    }
+
+    // When the oldest unacknowledged message times out, if there's an ack timeout
+    // An id at the front that's already been acknowledged times out right away, which just
+    // means it's cleared out a little early.
+    fn next_ack_deadline(&self) -> Option<Instant> {
+        let timeout = self.config.ack_timeout?;
+        let id = self.ack_deadlines.front()?;
+        Some(self.unacked.get(id).map_or_else(Instant::now, |message| message.delivered + timeout))
+    }
+
+    // Handle a frame received from a client
+    pub fn handle_frame(&mut self, client: usize, frame: Frame) {
+        self.metrics.frame_processed();
+        // Frames missing a header their command requires, in the protocol version the client
+        // connected with, can't be routed
+        let version = self.clients.get(&client).map_or(newest_version(), |tx| tx.version());
+        if let Err(e) = frame.validate(version) {
+            self.send_to(client, Frame::error("malformed frame", &e.to_string()));
+            return;
+        }
+
+        let result = match frame.command() {
+            StompCommand::Begin => self.do_begin(client, &frame),
+            StompCommand::Commit => self.do_commit(client, &frame),
+            StompCommand::Abort => self.do_abort(client, &frame),
+            // The receipt for a romp-sync SEND is sent by poll once the message has been written
+            StompCommand::Send if is_sync(&frame) => {
+                return self.do_sync_send(client, &frame);
+            },
+            StompCommand::Send | StompCommand::Ack | StompCommand::Nack
+                    if frame.header().contains_key("transaction") => {
+                self.hold(client, &frame)
+            },
+            _ => self.apply(client, &frame),
+        };
+
+        match result {
+            Ok(_) => {
+                // Acknowledge the frame if the client asked for a receipt
+                if let Some(receipt) = frame.header().get("receipt") {
+                    let response = Frame::builder(StompCommand::Receipt)
+                        .header("receipt-id", receipt)
+                        .build();
+                    self.send_to(client, response);
+                }
+            },
+            Err(e) => {
+                self.send_to(client, Frame::error("request failed", e));
+            },
+        }
+    }
+
+    // Carry out a frame that isn't part of a transaction (or whose transaction was committed)
+    fn apply(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        match frame.command() {
+            StompCommand::Send => self.do_send(frame).map(|_| ()),
+            StompCommand::Subscribe => self.do_subscribe(client, frame),
+            StompCommand::Unsubscribe => self.do_unsubscribe(client, frame),
+            StompCommand::Ack => self.do_ack(client, frame),
+            StompCommand::Nack => self.do_nack(client, frame),
+            _ => Ok(()),
+        }
+    }
+
+    // Start a transaction
+    fn do_begin(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        let key = transaction_key(client, frame)?;
+        if self.transactions.contains_key(&key) {
+            return Err("Transaction already started.");
+        }
+        self.transactions.insert(key, Vec::new());
+        self.count_held(client, Resource::Transactions, 1, 0);
+        Ok(())
+    }
+
+    // Carry out every frame held in a transaction, in the order they were sent
+    // The first failure is reported, but the rest of the transaction still goes through
+    fn do_commit(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        let key = transaction_key(client, frame)?;
+        let held = self.transactions.remove(&key).ok_or("No transaction with that id.")?;
+        self.release_held(client, held.len() + 1, held.iter().map(frame_size).sum());
+        let mut result = Ok(());
+        for frame in held {
+            let applied = self.apply(client, &frame);
+            if result.is_ok() {
+                result = applied;
+            }
+        }
+        result
+    }
+
+    // Throw away a transaction; nothing it held takes effect, so ACKed messages stay unacked
+    fn do_abort(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        let key = transaction_key(client, frame)?;
+        let held = self.transactions.remove(&key).ok_or("No transaction with that id.")?;
+        self.release_held(client, held.len() + 1, held.iter().map(frame_size).sum());
+        Ok(())
+    }
+
+    // Hold a SEND, ACK or NACK until its transaction is committed
+    fn hold(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        let key = transaction_key(client, frame)?;
+        let held = self.transactions.get_mut(&key).ok_or("No transaction with that id.")?;
+        held.push(frame.clone());
+        self.count_held(client, Resource::Transactions, 1, frame_size(frame));
+        Ok(())
+    }
+
+    // Deliver a romp-sync SEND, holding its receipt until every subscriber has written it
+    fn do_sync_send(&mut self, client: usize, frame: &Frame) {
+        match self.do_send(frame) {
+            Ok(deliveries) => {
+                if let Some(receipt) = frame.header().get("receipt") {
+                    let receipt = Frame::builder(StompCommand::Receipt)
+                        .header("receipt-id", receipt)
+                        .build();
+                    self.receipts.push(PendingReceipt { client, receipt, deliveries });
+                    self.check_budget(client, Resource::Receipts);
+                }
+            },
+            Err(e) => {
+                self.send_to(client, Frame::error("request failed", e));
+            },
+        }
+    }
+
+    // Deliver a message to its destination's subscribers
+    // Every subscriber to a topic gets the message, but a queue message goes to just one of the
+    // queue's subscribers, taking turns between them
+    // Returns each subscriber the message went to, and what became of it
+    fn do_send(&mut self, frame: &Frame) -> Result<Vec<(usize, Sent)>, &'static str> {
+        let destination = match frame.header().get("destination") {
+            Some(d) => d,
+            None => return Ok(Vec::new()),
+        };
+        let route = self.route(destination);
+        let subs: Vec<Subscription> = self.registry.subscribers(&route)
+            .into_iter().cloned().collect();
+
+        // Messages already waiting for a queue go first, and queues nobody is consuming from
+        // yet hold on to their messages, so in both cases the message waits its turn
+        let waits = is_queue(&route) && (subs.is_empty() || self.pending.contains_key(&route));
+        if waits && self.is_full(&route) {
+            return self.refuse_full(&route, frame);
+        }
+
+        self.count_published(&route);
+        self.next_message_id += 1;
+        let message_id = self.next_message_id.to_string();
+        let mut deliveries = Vec::new();
+
+        if is_queue(&route) {
+            let delivered = if waits {
+                None
+            } else {
+                self.deliver_once(&route, &subs, frame, &message_id, 0)
+            };
+            match delivered {
+                Some(delivered) => deliveries.push(delivered),
+                // Nobody could take it, so it waits like it would with no subscribers at all
+                None => {
+                    self.pending.entry(route.clone()).or_default().push_back(PendingMessage {
+                        message_id,
+                        frame: frame.clone(),
+                        redeliveries: 0,
+                    });
+                    self.metrics.message_held();
+                    if !subs.is_empty() {
+                        self.drain_pending(&route);
+                    }
+                },
+            }
+        } else {
+            for sub in subs {
+                // Topic messages aren't kept for later, so a subscription that's had as many
+                // as it can take without acknowledging them goes without
+                if !self.has_capacity(&sub) {
+                    debug!("Subscription {} is at its prefetch limit; skipping message", sub.id);
+                    continue;
+                }
+                if let Some(sent) = self.deliver(&sub, frame, &message_id, 0) {
+                    deliveries.push((sub.client, sent));
+                }
+            }
+        }
+
+        Ok(deliveries)
+    }
+
+    // Determine whether a queue is already holding as many messages as --max-queue-depth allows
+    fn is_full(&self, route: &str) -> bool {
+        self.config.max_queue_depth.is_some_and(|max| {
+            self.pending.get(route).is_some_and(|queue| queue.len() >= max)
+        })
+    }
+
+    // Turn away a message for a full queue, sending it to the dead-letter destination if
+    // there is one
+    fn refuse_full(&mut self, route: &str, frame: &Frame)
+            -> Result<Vec<(usize, Sent)>, &'static str> {
+        match self.dead_letter_for(route) {
+            Some(dead_letter) => self.send_dead_letter(frame, &dead_letter),
+            None => Err("Queue is full."),
+        }
+    }
+
+    // Deliver a queue message to the next of the queue's subscribers in turn
+    // Subscribers with room in their write queues go first, so a slow consumer doesn't sit on
+    // messages that others could be working on. A subscriber that can't take the message at all
+    // (e.g. because it's being disconnected, or is at its prefetch limit) is skipped. Returns
+    // the client the message went to and what became of it, if it went anywhere.
+    fn deliver_once(&mut self, route: &str, subs: &[Subscription], frame: &Frame,
+                    message_id: &str, redeliveries: usize) -> Option<(usize, Sent)> {
+        let start = self.next_consumer.get(route).cloned().unwrap_or(0);
+        let (ready, busy): (Vec<usize>, Vec<usize>) = (0..subs.len())
+            .map(|i| (start + i) % subs.len())
+            .filter(|&i| self.has_capacity(&subs[i]))
+            .partition(|&i| self.has_room(subs[i].client));
+        for i in ready.into_iter().chain(busy) {
+            let sub = &subs[i];
+            if let Some(sent) = self.deliver(sub, frame, message_id, redeliveries) {
+                self.next_consumer.insert(String::from(route), (i + 1) % subs.len());
+                return Some((sub.client, sent));
+            }
+        }
+        None
+    }
+
+    // Hand messages waiting for a queue to its subscribers, in order, for as long as one of
+    // them can take the next message
+    fn drain_pending(&mut self, route: &str) {
+        let subs: Vec<Subscription> = self.registry.subscribers(route)
+            .into_iter().cloned().collect();
+        while let Some(pending) = self.pending.get_mut(route).and_then(|queue| queue.pop_front()) {
+            self.metrics.messages_released(1);
+            let delivered = self.deliver_once(route, &subs, &pending.frame, &pending.message_id,
+                                              pending.redeliveries);
+            if delivered.is_none() {
+                self.pending.entry(String::from(route)).or_default().push_front(pending);
+                self.metrics.message_held();
+                break;
+            }
+        }
+        if self.pending.get(route).is_some_and(|queue| queue.is_empty()) {
+            self.pending.remove(route);
+        }
+    }
+
+    // Determine whether a subscription can be sent another message without going over its
+    // prefetch limit
+    fn has_capacity(&self, sub: &Subscription) -> bool {
+        sub.prefetch.is_none_or(|max| {
+            self.unacked.values()
+                .filter(|message| message.client == sub.client && message.subscription == sub.id)
+                .count() < max
+        })
+    }
+
+    // Count a message accepted for a destination
+    fn count_published(&mut self, route: &str) {
+        let counts = self.registry.message_published(route);
+        self.metrics.set_message_counts(route, counts);
+    }
+
+    // Send a message to one subscription, keeping track of it until it's acknowledged if the
+    // subscription has to acknowledge messages
+    // Returns what became of the frame, unless it was dropped
+    fn deliver(&mut self, sub: &Subscription, frame: &Frame, message_id: &str,
+               redeliveries: usize) -> Option<Sent> {
+        let destination = frame.header().get("destination").map_or("", |d| &d[..]);
+        let route = self.route(destination);
+        let mut message = build_message(frame, destination, message_id, &sub.id);
+        if redeliveries > 0 {
+            message.header_mut().set("redelivered", "true");
+            message.header_mut().set("redelivery-count", &redeliveries.to_string());
+        }
+        let ack_id = if sub.ack == AckMode::Auto {
+            None
+        } else {
+            self.next_ack_id += 1;
+            message.header_mut().set("ack", &self.next_ack_id.to_string());
+            Some(self.next_ack_id)
+        };
+
+        let sent = self.send_to(sub.client, message)?;
+        self.metrics.message_delivered();
+        let counts = self.registry.message_delivered(&route);
+        self.metrics.set_message_counts(&route, counts);
+        if let Some(ack_id) = ack_id {
+            self.unacked.insert(ack_id, Unacked {
+                client: sub.client,
+                subscription: sub.id.clone(),
+                cumulative: sub.ack == AckMode::Client,
+                route,
+                message_id: String::from(message_id),
+                frame: frame.clone(),
+                redeliveries,
+                delivered: Instant::now(),
+            });
+            if self.config.ack_timeout.is_some() {
+                self.ack_deadlines.push_back(ack_id);
+            }
+            self.metrics.set_messages_unacked(self.unacked.len());
+            self.count_held(sub.client, Resource::Acks, 1, frame_size(frame));
+        }
+        Some(sent)
+    }
+
+    // Acknowledge messages delivered to one of the client's subscriptions
+    fn do_ack(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        let taken = self.take_unacked(client, frame)?;
+        self.refill(&taken);
+        Ok(())
+    }
+
+    // Turn down messages delivered to one of the client's subscriptions
+    fn do_nack(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        let taken = self.take_unacked(client, frame)?;
+        self.refill(&taken);
+        for message in taken {
+            self.redeliver(client, message);
+        }
+        Ok(())
+    }
+
+    // Send the next waiting messages to queues whose subscribers have made room for them by
+    // acknowledging (or turning down) messages
+    fn refill(&mut self, taken: &[Unacked]) {
+        let mut routes: Vec<String> = taken.iter()
+            .filter(|message| is_queue(&message.route))
+            .map(|message| message.route.clone())
+            .collect();
+        routes.dedup();
+        for route in routes {
+            self.drain_pending(&route);
+        }
+    }
+
+    // Remove the messages an ACK or NACK covers from the unacknowledged messages
+    fn take_unacked(&mut self, client: usize, frame: &Frame)
+            -> Result<Vec<Unacked>, &'static str> {
+        let unknown = "No message with that ack id.";
+        let id = frame.header().get("id").and_then(|id| id.parse::<u64>().ok()).ok_or(unknown)?;
+        // The message timed out and went to someone else, which the client can't have known
+        if self.expired_acks.get(&id) == Some(&client) {
+            self.expired_acks.remove(&id);
+            return Ok(Vec::new());
+        }
+        let (subscription, cumulative) = match self.unacked.get(&id) {
+            Some(message) if message.client == client => {
+                (message.subscription.clone(), message.cumulative)
+            },
+            _ => return Err(unknown),
+        };
+        let mut ids: Vec<u64> = if cumulative {
+            self.unacked.iter()
+                .filter(|&(&other, message)| {
+                    other <= id && message.client == client && message.subscription == subscription
+                })
+                .map(|(&other, _)| other)
+                .collect()
+        } else {
+            vec![id]
+        };
+        ids.sort();
+        let taken: Vec<Unacked> = ids.into_iter()
+            .filter_map(|id| self.unacked.remove(&id))
+            .collect();
+        self.metrics.set_messages_unacked(self.unacked.len());
+        let bytes = taken.iter().map(|message| frame_size(&message.frame)).sum();
+        self.release_held(client, taken.len(), bytes);
+        Ok(taken)
+    }
+
+    // Deliver a message that a client turned down (or never acknowledged) again
+    // Queue messages go to another of the queue's subscribers if there is one, and are held
+    // for the next subscriber if there isn't. After max_redeliveries they go to the dead-letter
+    // destination instead. Topic messages were only meant for the client, so they're dropped.
+    fn redeliver(&mut self, client: usize, message: Unacked) {
+        if !is_queue(&message.route) {
+            return;
+        }
+        let redeliveries = message.redeliveries + 1;
+        if self.config.max_redeliveries.is_some_and(|max| redeliveries > max) {
+            let result = match self.dead_letter_for(&message.route) {
+                Some(dead_letter) => self.send_dead_letter(&message.frame, &dead_letter),
+                None => Err("No dead-letter destination."),
+            };
+            if let Err(e) = result {
+                warn!("Dropping message {} after {} redeliveries: {}",
+                      message.message_id, message.redeliveries, e);
+            }
+            return;
+        }
+
+        let subs: Vec<Subscription> = self.registry.subscribers(&message.route)
+            .into_iter()
+            .filter(|s| self.has_capacity(s))
+            .cloned()
+            .collect();
+        // Prefer anyone but the subscription that turned it down
+        let target = subs.iter()
+            .find(|s| !(s.client == client && s.id == message.subscription))
+            .or_else(|| subs.first());
+        let delivered = match target {
+            Some(sub) => self.deliver(sub, &message.frame, &message.message_id, redeliveries),
+            None => None,
+        };
+        // Nobody could take it (e.g. the subscriber it went to is on its way out), so it waits
+        // for the next one
+        if delivered.is_none() {
+            self.pending.entry(message.route).or_default().push_back(PendingMessage {
+                message_id: message.message_id,
+                frame: message.frame,
+                redeliveries,
+            });
+            self.metrics.message_held();
+        }
+    }
+
+    // Get where messages go when they can't be delivered to a route, if anywhere
+    // A full dead-letter destination can't be its own dead-letter destination
+    fn dead_letter_for(&self, route: &str) -> Option<String> {
+        match self.config.dead_letter_destination {
+            Some(ref d) if self.route(d) != route => Some(d.clone()),
+            _ => None,
+        }
+    }
+
+    // Send a message that couldn't be delivered to the dead-letter destination, noting where it
+    // was meant to go in an original-destination header
+    fn send_dead_letter(&mut self, frame: &Frame, dead_letter: &str)
+            -> Result<Vec<(usize, Sent)>, &'static str> {
+        let original = frame.header().get("destination").map_or("", |d| &d[..]);
+        info!("Message for {} can't be delivered; sending it to {}", original, dead_letter);
+        let mut letter = frame.clone();
+        letter.header_mut().replace("original-destination", original);
+        letter.header_mut().replace("destination", dead_letter);
+        self.do_send(&letter)
+    }
+
+    // Subscribe a client to a destination
+    fn do_subscribe(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        let header = frame.header();
+        let (id, destination) = match (header.get("id"), header.get("destination")) {
+            (Some(id), Some(destination)) => (id, destination),
+            _ => return Ok(()),
+        };
+
+        let route = self.route(destination);
+        let mut sub = Subscription::new(client, id, &route);
+        // Only queues can have exclusive consumers
+        sub.exclusive = is_queue(&route) &&
+                        frame.header().get("romp-exclusive").is_some_and(|v| v == "true");
+        sub.pattern = self.config.wildcard_subscriptions && is_pattern(&route);
+        sub.ack = AckMode::from_header(frame.header().get("ack").map(|a| &a[..]))
+            .ok_or("Invalid ack mode.")?;
+        // Nothing is left unacknowledged with ack:auto, so only the other modes have a limit
+        if sub.ack != AckMode::Auto {
+            sub.prefetch = match frame.header().get("prefetch-count") {
+                Some(count) => match count.parse::<usize>() {
+                    Ok(0) => None,
+                    Ok(count) => Some(count),
+                    Err(_) => return Err("Invalid prefetch count."),
+                },
+                None => self.config.default_prefetch,
+            };
+        }
+        let pattern = sub.pattern;
+        self.registry.subscribe(sub.clone(), self.config.max_subscribers_per_destination)?;
+        self.metrics.set_subscribers(self.registry.counts());
+
+        // The first subscriber to a queue gets everything that was sent while it was empty, as
+        // far as its prefetch limit allows
+        let queues: Vec<String> = if pattern {
+            self.pending.keys().filter(|queue| matches(&route, queue)).cloned().collect()
+        } else {
+            vec![route]
+        };
+        for queue in queues {
+            self.drain_pending(&queue);
+        }
+        Ok(())
+    }
+
+    // Remove one of a client's subscriptions
+    fn do_unsubscribe(&mut self, client: usize, frame: &Frame) -> Result<(), &'static str> {
+        let id = match frame.header().get("id") {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        match self.registry.unsubscribe(client, id) {
+            Some(_) => {
+                self.metrics.set_subscribers(self.registry.counts());
+                self.forget_idle_queues();
+                Ok(())
+            },
+            None => Err("No subscription with that id."),
+        }
+    }
+
+    // Drop the round-robin position of queues nobody is subscribed to any more
+    fn forget_idle_queues(&mut self) {
+        let registry = &self.registry;
+        self.next_consumer.retain(|route, _| !registry.subscribers(route).is_empty());
+    }
+
+    // Get the name a destination is routed by
+    fn route(&self, destination: &str) -> String {
+        if self.config.case_insensitive_destinations {
+            destination.to_lowercase()
+        } else {
+            String::from(destination)
+        }
+    }
+
+    // Whether a client can take another frame right away
+    fn has_room(&self, client: usize) -> bool {
+        let tx = match self.clients.get(&client) {
+            Some(tx) => tx,
+            None => return false,
+        };
+        let backlogged = self.backlogs.get(&client).is_some_and(|b| !b.frames.is_empty());
+        !backlogged && self.config.max_in_flight.is_none_or(|max| tx.in_flight() < max)
+    }
+
+    // Send a frame to a client, applying the slow consumer policy if its queue is full
+    // Returns what became of the frame, unless it was dropped
+    fn send_to(&mut self, client: usize, frame: Frame) -> Option<Sent> {
+        let has_room = self.has_room(client);
+        let tx = self.clients.get(&client)?.clone();
+
+        if !has_room {
+            let policy = self.config.slow_consumer_policy;
+            let reason = match policy {
+                SlowConsumerPolicy::Disconnect => {
+                    Some("Slow consumer; too many frames in flight.")
+                },
+                // Frames wait in the client's backlog, which is bounded too. When it's full,
+                // topic messages (which are only worth anything while they're fresh) make room
+                // for newer frames if the policy allows it, oldest first.
+                _ if self.backlog_is_full(client) => {
+                    if policy == SlowConsumerPolicy::Drop && self.drop_oldest(client) {
+                        debug!("[client {}] Backlog is full; dropped oldest message", tx.name());
+                        None
+                    } else {
+                        Some("Slow consumer; too many frames waiting to be written.")
+                    }
+                },
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                if tx.disconnect(Frame::error("disconnected", reason)) {
+                    warn!("[client {}] Write queue is full; disconnecting", tx.name());
+                }
+                return None;
+            }
+            self.next_backlog_id += 1;
+            let backlog = self.backlogs.entry(client).or_default();
+            backlog.bytes += frame_size(&frame);
+            backlog.frames.push_back((self.next_backlog_id, frame));
+            self.check_memory(client);
+            self.check_budget(client, Resource::Writes);
+            return Some(Sent::Backlogged(self.next_backlog_id));
+        }
+
+        let sent = match tx.send_tracked(frame) {
+            Ok(ticket) => Some(Sent::Queued(ticket)),
+            Err(_) => {
+                debug!("[client {}] Went away before delivery", tx.name());
+                None
+            },
+        };
+        self.check_memory(client);
+        self.check_budget(client, Resource::Writes);
+        sent
+    }
+
+    // Determine whether a client's backlog holds as many frames as max_backlog allows
+    fn backlog_is_full(&self, client: usize) -> bool {
+        self.config.max_backlog.is_some_and(|max| {
+            self.backlogs.get(&client).is_some_and(|backlog| backlog.frames.len() >= max)
+        })
+    }
+
+    // Make room in a client's backlog by dropping the oldest topic message in it
+    // Returns false if there's no topic message to drop
+    fn drop_oldest(&mut self, client: usize) -> bool {
+        let backlog = match self.backlogs.get_mut(&client) {
+            Some(backlog) => backlog,
+            None => return false,
+        };
+        let oldest = backlog.frames.iter().position(|(_, frame)| is_topic_message(frame));
+        let (id, frame) = match oldest.and_then(|i| backlog.frames.remove(i)) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        backlog.bytes -= frame_size(&frame);
+        // Nobody waits on the message being written any more, or for it to be acknowledged
+        for receipt in self.receipts.iter_mut() {
+            receipt.deliveries.retain(|&(_, sent)| sent != Sent::Backlogged(id));
+        }
+        let ack = frame.header().get("ack").and_then(|ack| ack.parse::<u64>().ok());
+        if let Some(message) = ack.and_then(|ack| self.unacked.remove(&ack)) {
+            self.metrics.set_messages_unacked(self.unacked.len());
+            self.release_held(client, 1, frame_size(&message.frame));
+        }
+        true
+    }
+
+    // Hand backlogged frames to the clients that have made room for them
+    fn flush_backlogs(&mut self) {
+        let max = match self.config.max_in_flight {
+            Some(max) => max,
+            None => return,
+        };
+        for (client, backlog) in self.backlogs.iter_mut() {
+            let tx = match self.clients.get(client) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            while tx.in_flight() < max {
+                let (id, frame) = match backlog.frames.pop_front() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                backlog.bytes -= frame_size(&frame);
+                match tx.send_tracked(frame) {
+                    Ok(ticket) => {
+                        // Anyone waiting on the frame can now wait on its write instead
+                        for receipt in self.receipts.iter_mut() {
+                            for delivery in receipt.deliveries.iter_mut() {
+                                if delivery.1 == Sent::Backlogged(id) {
+                                    delivery.1 = Sent::Queued(ticket);
+                                }
+                            }
+                        }
+                    },
+                    Err(_) => {
+                        debug!("[client {}] Went away before delivery", tx.name());
+                        backlog.frames.clear();
+                        backlog.bytes = 0;
+                    },
+                }
+            }
+        }
+        self.backlogs.retain(|_, backlog| !backlog.frames.is_empty());
+    }
+
+    // Approximate memory held for a client: frames waiting to be written, in its write queue or
+    // its backlog, messages it hasn't acknowledged and frames held in its transactions
+    fn memory(&self, client: usize) -> usize {
+        let queued = self.clients.get(&client).map_or(0, |tx| tx.memory());
+        let backlogged = self.backlogs.get(&client).map_or(0, |b| b.bytes);
+        let held = self.held.get(&client).map_or(0, |held| held.bytes);
+        queued + backlogged + held
+    }
+
+    // Count things kept for a client until it acknowledges or commits them
+    fn count_held(&mut self, client: usize, resource: Resource, count: usize, bytes: usize) {
+        let held = self.held.entry(client).or_default();
+        held.count += count;
+        held.bytes += bytes;
+        self.check_memory(client);
+        self.check_budget(client, resource);
+    }
+
+    // Stop counting things the client has acknowledged, committed or aborted
+    fn release_held(&mut self, client: usize, count: usize, bytes: usize) {
+        if let Some(held) = self.held.get_mut(&client) {
+            held.count = held.count.saturating_sub(count);
+            held.bytes = held.bytes.saturating_sub(bytes);
+            if held.count == 0 {
+                self.held.remove(&client);
+            }
+        }
+    }
+
+    // Disconnect a client that's holding more memory than it's allowed
+    fn check_memory(&self, client: usize) {
+        let max = match self.config.max_connection_memory {
+            Some(max) => max,
+            None => return,
+        };
+        let memory = self.memory(client);
+        if let Some(tx) = self.clients.get(&client) {
+            let error = Frame::error("disconnected", "Connection is using too much memory.");
+            if memory > max && tx.disconnect(error) {
+                warn!("[client {}] Holding {} bytes; disconnecting", tx.name(), memory);
+            }
+        }
+    }
+
+    // Everything counted against a client's budget: frames waiting to be written, unacked
+    // messages, open transactions and their frames, and pending romp-sync receipts
+    fn budget_used(&self, client: usize) -> usize {
+        let queued = self.clients.get(&client).map_or(0, |tx| tx.in_flight());
+        let backlogged = self.backlogs.get(&client).map_or(0, |b| b.frames.len());
+        let held = self.held.get(&client).map_or(0, |held| held.count);
+        let receipts = self.receipts.iter().filter(|r| r.client == client).count();
+        queued + backlogged + held + receipts
+    }
+
+    // Disconnect a client that's gone over its budget, saying what used the last of it
+    fn check_budget(&self, client: usize, resource: Resource) {
+        let max = match self.config.max_connection_budget {
+            Some(max) => max,
+            None => return,
+        };
+        let used = self.budget_used(client);
+        if used <= max {
+            return;
+        }
+        if let Some(tx) = self.clients.get(&client) {
+            let mut error = Frame::error("disconnected", resource.description());
+            error.header_mut().set("romp-error-code", resource.error_code());
+            if tx.disconnect(error) {
+                warn!("[client {}] Over budget with {} held; disconnecting", tx.name(), used);
+            }
+        }
+    }
+}
+
+// Determine whether a SEND's receipt should wait until its message has been written to every
+// subscriber (romp-sync). Inside a transaction the receipt only says the SEND was held, so it
+// isn't delayed.
+fn is_sync(frame: &Frame) -> bool {
+    frame.header().get("romp-sync").is_some_and(|v| v == "true") &&
+        !frame.header().contains_key("transaction")
+}
+
+// Transactions are named by the client, so the same id from two clients is two transactions
+fn transaction_key(client: usize, frame: &Frame) -> Result<(usize, String), &'static str> {
+    match frame.header().get("transaction") {
+        Some(id) => Ok((client, id.clone())),
+        None => Err("Missing transaction header."),
+    }
+}
+
+// Determine whether a frame is a topic message, which a slow consumer can go without
+fn is_topic_message(frame: &Frame) -> bool {
+    frame.command() == StompCommand::Message &&
+        frame.header().get("destination").is_some_and(|d| !is_queue(d))
+}
+
+// Build the MESSAGE frame that delivers a SEND to one subscription
+fn build_message(frame: &Frame, destination: &str, message_id: &str, subscription: &str) -> Frame {
+    let mut message = Frame::builder(StompCommand::Message)
+        .header("destination", destination)
+        .header("message-id", message_id)
+        .header("subscription", subscription);
+    if let Some(content_type) = frame.header().get("content-type") {
+        message = message.header("content-type", content_type);
+    }
+    // Headers the application made up are passed along untouched
+    for (key, value) in frame.header().iter() {
+        if !is_reserved_header(key) {
+            message = message.header(key, value);
+        }
+    }
+    // Relay the sender's choice of framing
+    if frame.content_length().is_none() {
+        message = message.without_content_length();
+    }
+    message.body(frame.body()).build()
+}
@@ -0,0 +1,225 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::collections::HashMap;
+
+use super::super::metrics::MessageCounts;
+
+// How a subscription acknowledges the messages it receives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AckMode {
+    Auto,               // Messages are consumed as soon as they're sent
+    Client,             // An ACK or NACK covers the message and every earlier one
+    ClientIndividual,   // An ACK or NACK covers just the one message
+}
+
+impl AckMode {
+    // Read the ack header of a SUBSCRIBE frame (None if it isn't a valid mode)
+    pub fn from_header(value: Option<&str>) -> Option<AckMode> {
+        match value {
+            None | Some("auto") => Some(AckMode::Auto),
+            Some("client") => Some(AckMode::Client),
+            Some("client-individual") => Some(AckMode::ClientIndividual),
+            Some(_) => None,
+        }
+    }
+}
+
+// A client's subscription to a destination
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub client: usize,              // Connection the subscription belongs to
+    pub id: String,                 // Subscription id chosen by the client
+    pub destination: String,
+    pub exclusive: bool,            // No one else may subscribe while this is held
+    pub pattern: bool,              // The destination is a wildcard pattern
+    pub ack: AckMode,
+    pub prefetch: Option<usize>,    // Most unacknowledged messages at once (None for no limit)
+}
+
+impl Subscription {
+    pub fn new(client: usize, id: &str, destination: &str) -> Subscription {
+        Subscription {
+            client,
+            id: String::from(id),
+            destination: String::from(destination),
+            exclusive: false,
+            pattern: false,
+            ack: AckMode::Auto,
+            prefetch: None,
+        }
+    }
+}
+
+// Determine whether a destination is a point-to-point queue
+pub fn is_queue(destination: &str) -> bool {
+    destination.starts_with("/queue/")
+}
+
+// Determine whether a destination contains wildcards
+// Destinations are made up of segments separated by '/' or '.'. In a pattern, a '*' segment
+// matches any one segment and a '#' segment at the end matches one or more segments, so
+// /topic/prices.* matches /topic/prices.gold but not /topic/prices.gold.spot, while
+// /topic/prices.# matches both.
+pub fn is_pattern(destination: &str) -> bool {
+    split_segments(destination).0.iter().any(|seg| *seg == "*" || *seg == "#")
+}
+
+// Determine whether a destination matches a wildcard pattern
+pub fn matches(pattern: &str, destination: &str) -> bool {
+    let (pattern_segs, pattern_seps) = split_segments(pattern);
+    let (dest_segs, dest_seps) = split_segments(destination);
+    for (i, seg) in pattern_segs.iter().enumerate() {
+        if i >= dest_segs.len() || (i > 0 && pattern_seps[i - 1] != dest_seps[i - 1]) {
+            return false;
+        }
+        if *seg == "#" && i == pattern_segs.len() - 1 {
+            return true;
+        }
+        if *seg != "*" && *seg != dest_segs[i] {
+            return false;
+        }
+    }
+    pattern_segs.len() == dest_segs.len()
+}
+
+// Split a destination into its segments and the separators between them
+fn split_segments(destination: &str) -> (Vec<&str>, Vec<char>) {
+    let mut segments = Vec::new();
+    let mut separators = Vec::new();
+    let mut start = 0;
+    for (i, c) in destination.char_indices() {
+        if c == '/' || c == '.' {
+            segments.push(&destination[start..i]);
+            separators.push(c);
+            start = i + 1;
+        }
+    }
+    segments.push(&destination[start..]);
+    (segments, separators)
+}
+
+// Keeps track of the subscriptions to each destination, and how many messages each has seen
+// Subscriptions to wildcard patterns are kept apart, since every message has to be checked
+// against all of them
+#[derive(Debug)]
+pub struct DestinationRegistry {
+    destinations: HashMap<String, Vec<Subscription>>,
+    patterns: HashMap<String, Vec<Subscription>>,
+    messages: HashMap<String, MessageCounts>,
+}
+
+impl Default for DestinationRegistry {
+    fn default() -> DestinationRegistry {
+        DestinationRegistry::new()
+    }
+}
+
+impl DestinationRegistry {
+    pub fn new() -> DestinationRegistry {
+        DestinationRegistry {
+            destinations: HashMap::new(),
+            patterns: HashMap::new(),
+            messages: HashMap::new(),
+        }
+    }
+
+    // Add a subscription, refusing it if the destination already has the maximum number of
+    // subscribers or the subscription conflicts with an exclusive consumer
+    // Exclusive consumers are only allowed on exact destinations, but a pattern that matches
+    // one of them still counts as another consumer of it.
+    pub fn subscribe(&mut self, sub: Subscription, max: Option<usize>) -> Result<(), &'static str> {
+        if sub.pattern && sub.exclusive {
+            return Err("Pattern subscriptions cannot be exclusive.");
+        }
+        let exclusive_held = if sub.pattern {
+            self.destinations.iter().any(|(destination, subs)| {
+                matches(&sub.destination, destination) && subs.iter().any(|s| s.exclusive)
+            })
+        } else {
+            self.destinations.get(&sub.destination)
+                .is_some_and(|subs| subs.iter().any(|s| s.exclusive))
+        };
+        if exclusive_held {
+            return Err("Destination has an exclusive consumer.");
+        }
+        if sub.exclusive && !self.subscribers(&sub.destination).is_empty() {
+            return Err("Destination already has consumers; cannot subscribe exclusively.");
+        }
+
+        // The entry is only made once the subscription is accepted, so a refusal doesn't leave
+        // an empty one behind
+        let map = if sub.pattern { &mut self.patterns } else { &mut self.destinations };
+        let count = map.get(&sub.destination).map_or(0, |subs| subs.len());
+        if max.is_some_and(|max| count >= max) {
+            return Err("Too many subscribers for destination.");
+        }
+        map.entry(sub.destination.clone()).or_default().push(sub);
+        Ok(())
+    }
+
+    // Remove a client's subscription by id
+    pub fn unsubscribe(&mut self, client: usize, id: &str) -> Option<Subscription> {
+        let mut removed = None;
+        for subs in self.destinations.values_mut().chain(self.patterns.values_mut()) {
+            if let Some(i) = subs.iter().position(|s| s.client == client && s.id == id) {
+                removed = Some(subs.remove(i));
+                break;
+            }
+        }
+        self.destinations.retain(|_, subs| !subs.is_empty());
+        self.patterns.retain(|_, subs| !subs.is_empty());
+        removed
+    }
+
+    // Remove all of a client's subscriptions
+    pub fn remove_client(&mut self, client: usize) {
+        for subs in self.destinations.values_mut().chain(self.patterns.values_mut()) {
+            subs.retain(|s| s.client != client);
+        }
+        self.destinations.retain(|_, subs| !subs.is_empty());
+        self.patterns.retain(|_, subs| !subs.is_empty());
+    }
+
+    // Get the subscriptions to a destination, including any patterns that match it
+    pub fn subscribers(&self, destination: &str) -> Vec<&Subscription> {
+        let mut subs: Vec<&Subscription> = match self.destinations.get(destination) {
+            Some(subs) => subs.iter().collect(),
+            None => Vec::new(),
+        };
+        for (pattern, pattern_subs) in self.patterns.iter() {
+            if matches(pattern, destination) {
+                subs.extend(pattern_subs.iter());
+            }
+        }
+        subs
+    }
+
+    // Count a message sent to a destination, returning the destination's new counts
+    pub fn message_published(&mut self, destination: &str) -> MessageCounts {
+        let counts = self.messages.entry(String::from(destination)).or_default();
+        counts.published += 1;
+        *counts
+    }
+
+    // Count a message handed to one of a destination's subscribers
+    pub fn message_delivered(&mut self, destination: &str) -> MessageCounts {
+        let counts = self.messages.entry(String::from(destination)).or_default();
+        counts.delivered += 1;
+        *counts
+    }
+
+    // Get how many messages have gone through a destination
+    pub fn message_counts(&self, destination: &str) -> MessageCounts {
+        self.messages.get(destination).cloned().unwrap_or_default()
+    }
+
+    // Get the number of subscribers to each destination and pattern
+    pub fn counts(&self) -> HashMap<String, usize> {
+        self.destinations.iter().chain(self.patterns.iter())
+            .map(|(dest, subs)| (dest.clone(), subs.len()))
+            .collect()
+    }
+}
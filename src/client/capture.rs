@@ -0,0 +1,148 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::stream::Stream;
+
+// Which way a chunk of traffic was going
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+// A record of the raw bytes exchanged with one client
+// Each chunk is written as a line with its direction ('>' from the client, '<' to the client)
+// and length, followed by the bytes themselves and a line break. Clones share the same sink so
+// the reader and writer threads can both record into it.
+#[derive(Clone)]
+pub struct Capture {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Capture {
+    // Record into any writer
+    pub fn new<W: Write + Send + 'static>(sink: W) -> Capture {
+        Capture {
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+        }
+    }
+
+    // Record into a new file in the given directory, named after the client (its address,
+    // port and session) and the time
+    // Session ids start again when the server restarts, so a name that's already taken gets a
+    // number added rather than mixing two connections' traffic in one file.
+    pub fn create(dir: &Path, client_ip: &str) -> io::Result<Capture> {
+        fs::create_dir_all(dir)?;
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let base = format!("{}-{}", client_ip.replace(':', "_"), secs);
+        let mut attempt = 0;
+        loop {
+            let name = match attempt {
+                0 => format!("{}.cap", base),
+                n => format!("{}-{}.cap", base, n),
+            };
+            match OpenOptions::new().write(true).create_new(true).open(dir.join(name)) {
+                Ok(file) => return Ok(Capture::new(file)),
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Append a chunk of traffic
+    pub fn record(&self, direction: Direction, bytes: &[u8]) {
+        let marker = match direction {
+            Direction::Received => '>',
+            Direction::Sent => '<',
+        };
+        let mut sink = match self.sink.lock() {
+            Ok(sink) => sink,
+            Err(_) => return,
+        };
+        let result = writeln!(sink, "{} {}", marker, bytes.len())
+            .and_then(|_| sink.write_all(bytes))
+            .and_then(|_| sink.write_all(b"\n"))
+            .and_then(|_| sink.flush());
+        // A broken capture shouldn't take the connection down with it
+        if let Err(e) = result {
+            debug!("Failed to record traffic: {}", e);
+        }
+    }
+}
+
+// A stream whose traffic is recorded if a capture is attached
+pub struct Recorded<S> {
+    inner: S,
+    capture: Option<Capture>,
+}
+
+impl<S> Recorded<S> {
+    pub fn new(inner: S, capture: Option<Capture>) -> Recorded<S> {
+        Recorded {
+            inner,
+            capture,
+        }
+    }
+}
+
+// Clones record into the same capture
+impl<S: Stream> Stream for Recorded<S> {
+    fn try_clone(&self) -> io::Result<Recorded<S>> {
+        Ok(Recorded::new(self.inner.try_clone()?, self.capture.clone()))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl<S: Read> Read for Recorded<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(ref capture) = self.capture {
+            if n > 0 {
+                capture.record(Direction::Received, &buf[..n]);
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Recorded<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(ref capture) = self.capture {
+            capture.record(Direction::Sent, &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+use super::super::stomp::Frame;
+use super::super::stomp::parse::parse_heart_beat;
+use super::super::config::Config;
+
+// Work out how often we have to send heart-beats to a client from its CONNECT frame
+// The client's heart-beat header says how often it wants to hear from us; we send at that rate
+// or our own, whichever is slower. Either side asking for 0 means no heart-beats.
+pub fn send_interval(connect: &Frame, config: &Config) -> Option<Duration> {
+    let ours = config.heart_beat?;
+    let theirs = match connect.header().get("heart-beat").map(|h| parse_heart_beat(h)) {
+        Some(Ok((_, 0))) | None | Some(Err(_)) => return None,
+        Some(Ok((_, y))) => Duration::from_millis(y),
+    };
+    Some(ours.max(theirs))
+}
+
+// Work out how often a client has to send us heart-beats from its CONNECT frame
+// We ask for them at the same rate we send our own; the client sends at that rate or its own,
+// whichever is slower, and either side saying 0 means it won't happen.
+pub fn receive_interval(connect: &Frame, config: &Config) -> Option<Duration> {
+    let ours = config.heart_beat?;
+    let theirs = match connect.header().get("heart-beat").map(|h| parse_heart_beat(h)) {
+        Some(Ok((0, _))) | None | Some(Err(_)) => return None,
+        Some(Ok((x, _))) => Duration::from_millis(x),
+    };
+    Some(ours.max(theirs))
+}
+
+// Keeps track of when the next heart-beat is due on a connection
+// Anything written to the client counts, so heart-beats only go out when the connection has
+// been quiet for a whole interval.
+#[derive(Debug)]
+pub struct HeartBeatClock {
+    interval: Option<Duration>,
+    last_write: Instant,
+}
+
+impl HeartBeatClock {
+    pub fn new(interval: Option<Duration>, now: Instant) -> HeartBeatClock {
+        HeartBeatClock {
+            interval,
+            last_write: now,
+        }
+    }
+
+    // Note that something was written to the client
+    pub fn wrote(&mut self, now: Instant) {
+        self.last_write = now;
+    }
+
+    // How long until the next heart-beat is due (None if heart-beats are off)
+    pub fn until_due(&self, now: Instant) -> Option<Duration> {
+        let interval = self.interval?;
+        Some((self.last_write + interval).saturating_duration_since(now))
+    }
+}
+
+// Keeps track of when a client that promised heart-beats was last heard from
+// Network delays mean heart-beats can arrive late, so the client is only given up on once it's
+// been silent for twice the interval.
+#[derive(Debug)]
+pub struct ReceiveClock {
+    interval: Option<Duration>,
+    last_read: Instant,
+}
+
+impl ReceiveClock {
+    pub fn new(interval: Option<Duration>, now: Instant) -> ReceiveClock {
+        ReceiveClock {
+            interval,
+            last_read: now,
+        }
+    }
+
+    // Note that something was read from the client
+    pub fn read(&mut self, now: Instant) {
+        self.last_read = self.last_read.max(now);
+    }
+
+    // Whether the client has been silent for too long (never if heart-beats are off)
+    pub fn expired(&self, now: Instant) -> bool {
+        self.interval.is_some_and(|interval| {
+            now.saturating_duration_since(self.last_read) > interval * 2
+        })
+    }
+}
+
+// A reader that remembers when it last got any bytes
+// The parser quietly skips heart-beats, so this is how the reader thread knows they arrived.
+pub struct Watched<R> {
+    inner: R,
+    last_read: Instant,
+}
+
+impl<R> Watched<R> {
+    pub fn new(inner: R) -> Watched<R> {
+        Watched {
+            inner,
+            last_read: Instant::now(),
+        }
+    }
+
+    pub fn last_read(&self) -> Instant {
+        self.last_read
+    }
+}
+
+impl<R: Read> Read for Watched<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.last_read = Instant::now();
+        }
+        Ok(n)
+    }
+}
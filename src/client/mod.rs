@@ -3,112 +3,687 @@
  * This file is part of Romp, the simple Rust STOMP server
  * Licensed under the GPLv3, see the LICENSE file for details
  */
-use std::net::{TcpStream, Shutdown};
-use std::io::{Write, Read};
-use std::time::Duration;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use std::sync::mpsc::{Sender, Receiver};
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, Receiver, SendError, RecvTimeoutError, TryRecvError};
 
-use super::stomp::{Frame, StompCommand};
-use super::stomp::{PROTO_VERS, SERVER_STR};
-use super::stomp::parse::parse_frame;
+use super::stomp::{Frame, StompCommand, StompVersion};
+use super::stomp::{negotiate_version, newest_version, supported_versions};
+use super::stomp::parse::{parse_frame_with_extensions, parse_heart_beat, ParseError};
+use super::config::Config;
+
+pub mod capture;
+use self::capture::{Capture, Recorded};
+
+pub mod heartbeat;
+use self::heartbeat::{HeartBeatClock, ReceiveClock, Watched};
+
+pub mod stream;
+use self::stream::Stream;
+
+pub mod throttle;
+use self::throttle::SendThrottle;
+
+pub mod tls;
+
+pub mod websocket;
+
+// Something a client's thread has to tell the broker
+pub enum ClientEvent {
+    Frame(usize, Frame),    // A frame read from the client with the given id
+    Closed(usize),          // The client with the given id has hung up
+}
+
+// Sending half of the channel from a client's thread to the broker
+// Every client shares one channel, so the broker can wait on all of them at once; events are
+// tagged with the client they came from. Dropping the sender tells the broker the client is
+// gone, even if the client's thread panicked.
+pub struct BrokerSender {
+    id: usize,
+    tx: Sender<ClientEvent>,
+}
+
+impl BrokerSender {
+    pub fn new(id: usize, tx: Sender<ClientEvent>) -> BrokerSender {
+        BrokerSender { id, tx }
+    }
+
+    // Pass a frame from the client to the broker
+    pub fn send(&self, frame: Frame) -> Result<(), SendError<Frame>> {
+        if let Err(SendError(ClientEvent::Frame(_, frame))) =
+                self.tx.send(ClientEvent::Frame(self.id, frame)) {
+            return Err(SendError(frame));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BrokerSender {
+    fn drop(&mut self) {
+        // If the broker has already gone there's nobody left to tell
+        if self.tx.send(ClientEvent::Closed(self.id)).is_err() {
+            debug!("[client {}] Broker is already gone", self.id);
+        }
+    }
+}
+
+// Sending half of a client's write queue
+// Keeps count of the frames (and approximate bytes) that have been queued but not yet
+// written to the socket
+#[derive(Clone)]
+pub struct ClientSender {
+    tx: Sender<Frame>,
+    state: Arc<WriteState>,
+}
+
+// State shared between a client's senders and its writer thread
+struct WriteState {
+    name: String,                               // Peer address and session, for logging
+    in_flight: AtomicUsize,
+    queued_bytes: AtomicUsize,
+    queued: Mutex<usize>,                       // Frames ever queued; numbers them in queue order
+    written: AtomicUsize,                       // Frames the writer has finished with
+    closed: AtomicBool,                         // The writer has finished
+    disconnect: Mutex<Option<Frame>>,           // The ERROR to drop the client with, if any
+    version: Mutex<StompVersion>,               // Protocol version frames are checked against
+}
+
+impl ClientSender {
+    pub fn new(tx: Sender<Frame>, name: &str) -> ClientSender {
+        ClientSender {
+            tx,
+            state: Arc::new(WriteState {
+                name: String::from(name),
+                in_flight: AtomicUsize::new(0),
+                queued_bytes: AtomicUsize::new(0),
+                queued: Mutex::new(0),
+                written: AtomicUsize::new(0),
+                closed: AtomicBool::new(false),
+                disconnect: Mutex::new(None),
+                version: Mutex::new(newest_version()),
+            }),
+        }
+    }
+
+    // Queue a frame to be written
+    pub fn send(&self, frame: Frame) -> Result<(), SendError<Frame>> {
+        self.send_tracked(frame).map(|_| ())
+    }
+
+    // Queue a frame to be written, returning a ticket that can be passed to is_written
+    pub fn send_tracked(&self, frame: Frame) -> Result<usize, SendError<Frame>> {
+        let size = frame_size(&frame);
+        let mut queued = self.state.queued.lock().unwrap();
+        self.state.in_flight.fetch_add(1, Ordering::SeqCst);
+        self.state.queued_bytes.fetch_add(size, Ordering::SeqCst);
+        if let Err(e) = self.tx.send(frame) {
+            self.state.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.state.queued_bytes.fetch_sub(size, Ordering::SeqCst);
+            return Err(e);
+        }
+        *queued += 1;
+        Ok(*queued)
+    }
+
+    // Whether the frame with the given ticket has been written, or never will be because the
+    // writer has stopped
+    pub fn is_written(&self, ticket: usize) -> bool {
+        self.state.written.load(Ordering::SeqCst) >= ticket || self.is_closed()
+    }
+
+    // Describe the client for logging
+    pub fn name(&self) -> &str {
+        &self.state.name
+    }
+
+    // Number of frames queued but not yet written
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Approximate number of bytes held by frames that haven't been written yet
+    pub fn memory(&self) -> usize {
+        self.state.queued_bytes.load(Ordering::SeqCst)
+    }
+
+    // The protocol version the client connected with; until it has connected, the newest
+    // version we speak
+    pub fn version(&self) -> StompVersion {
+        *self.state.version.lock().unwrap()
+    }
+
+    // Check the frames written to the client against the version it negotiated
+    pub fn set_version(&self, version: StompVersion) {
+        *self.state.version.lock().unwrap() = version;
+    }
+
+    // Whether the writer has stopped writing frames
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::SeqCst)
+    }
+
+    // Have the writer close the connection with the given ERROR instead of writing the rest of
+    // the queue. Returns false if the client was already marked for disconnection.
+    pub fn disconnect(&self, error: Frame) -> bool {
+        let mut disconnect = self.state.disconnect.lock().unwrap();
+        if disconnect.is_some() {
+            return false;
+        }
+        *disconnect = Some(error.clone());
+        // The writer may be waiting for something to write, so give it something; it notices
+        // the disconnect before writing it
+        if self.tx.send(error).is_err() {
+            debug!("[client {}] Writer has already finished", self.state.name);
+        }
+        true
+    }
+}
+
+// Approximate memory held by a frame
+pub fn frame_size(frame: &Frame) -> usize {
+    let header: usize = frame.header().iter().map(|(key, value)| key.len() + value.len()).sum();
+    header + frame.body_len()
+}
+
+// How long a read from a client may wait
+// Idle connections are only noticed when a read times out, so reads can't wait any longer than
+// the idle timeout
+fn read_timeout(config: &Config) -> Option<Duration> {
+    match (config.read_timeout, config.idle_timeout) {
+        (Some(read), Some(idle)) => Some(read.min(idle)),
+        (read, idle) => read.or(idle),
+    }
+}
+
+// Apply the socket options from the configuration to a client connection
+// Failures are only logged, since the connection still works without them
+pub fn configure_stream<S: Stream>(stream: &S, config: &Config, client_ip: &str) {
+    if let Err(e) = stream.set_read_timeout(read_timeout(config)) {
+        warn!("[client {}] Failed to set read timeout: {}", client_ip, e);
+    }
+    if let Err(e) = stream.set_write_timeout(config.write_timeout) {
+        warn!("[client {}] Failed to set write timeout: {}", client_ip, e);
+    }
+    if let Err(e) = stream.set_nodelay(config.tcp_nodelay) {
+        warn!("[client {}] Failed to set TCP_NODELAY: {}", client_ip, e);
+    }
+}
 
 // Service a client connection
-pub fn handle_client(mut stream: TcpStream, tx: Sender<Frame>, rx: Receiver<Frame>) {
-    // Set read/write timeouts
-    let default_read_timeout = Some(Duration::new(10, 0));
-    let default_write_timeout = Some(Duration::new(10, 0));
+// Frames from the client are read and passed to the broker on this thread while a second
+// thread writes whatever the broker sends back (`out` feeds the same queue as `rx`).
+// The session id is sent to the client in CONNECTED and tags every log line for the connection.
+pub fn handle_client<S: Stream>(stream: S, session: &str, tx: BrokerSender,
+                                rx: Receiver<Frame>, out: ClientSender, config: Config) {
+    let client_ip = format!("{}#{}", peer_name(&stream), session);
+    configure_stream(&stream, &config, &client_ip);
+
+    info!("[client {}] Started thread", client_ip);
 
-    stream.set_read_timeout(default_read_timeout);
-    stream.set_write_timeout(default_write_timeout);
+    // Record the connection's traffic if the operator asked for it
+    let mut capture = None;
+    if let Some(ref dir) = config.capture_dir {
+        if config.should_capture(stream.peer_addr().map(|addr| addr.ip())) {
+            match Capture::create(dir, &client_ip) {
+                Ok(c) => capture = Some(c),
+                Err(e) => warn!("[client {}] Failed to start capture: {}", client_ip, e),
+            }
+        }
+    }
+    let mut stream = Recorded::new(stream, capture);
+
+    // Frames are decoded from a buffered reader over a clone of the stream so that bytes
+    // read past the end of one frame are kept for the next
+    let mut reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(Watched::new(s)),
+        Err(e) => {
+            error!("[client {}] Failed to clone stream: {}", client_ip, e);
+            close_connection(&stream, &client_ip);
+            return;
+        },
+    };
 
-    let client_ip = stream.peer_addr().unwrap();
-    info!("Started thread for client {:?}", client_ip);
     // Get the first frame from the client
-    let request = parse_frame(&mut stream);
+    let request = parse_frame_with_extensions(&mut reader, &config.extensions,
+                                              &config.parse_limits);
 
-    let mut response = Frame::new();
-    match request {
+    let (heart_beat, receive_interval) = match request {
         Ok(r) => {
-            info!("Got request {:?}", r);
-            response = do_connect(&r);
-            stream.write(&response.to_bytes()[..]).unwrap();
+            // The headers may carry a passcode, so they are left out of the log
+            info!("[client {}] Got {} frame", client_ip, r.command());
+            let response = do_connect(&r, &config, session);
+            if response.command() == StompCommand::Error {
+                write_fatal_error(&mut stream, &response, out.version(), &client_ip);
+                return;
+            }
+            // From here on, frames are written in the version the client and server agreed on
+            if let Some(version) = response.header().get("version")
+                    .and_then(|v| StompVersion::from_string(v)) {
+                out.set_version(version);
+            }
+            if let Err(e) = write_frame(&mut stream, &response, out.version()) {
+                info!("[client {}] Failed to write: {}", client_ip, e);
+                close_connection(&stream, &client_ip);
+                return;
+            }
+            // A receipt on the connect frame is answered like any other, with a RECEIPT right
+            // after CONNECTED; a refused connection only gets the ERROR
+            if let Some(receipt) = r.header().get("receipt") {
+                let response = Frame::builder(StompCommand::Receipt)
+                    .header("receipt-id", receipt)
+                    .build();
+                if let Err(e) = write_frame(&mut stream, &response, out.version()) {
+                    info!("[client {}] Failed to write: {}", client_ip, e);
+                    close_connection(&stream, &client_ip);
+                    return;
+                }
+            }
+            (heartbeat::send_interval(&r, &config), heartbeat::receive_interval(&r, &config))
+        },
+        Err(ParseError::ReadTimeout) if !config.error_on_read_timeout => {
+            info!("[client {}] Read timeout while parsing frame; closing connection", client_ip);
+            close_connection(&stream, &client_ip);
+            return;
+        },
+        Err(ParseError::IdleTimeout) | Err(ParseError::Disconnected) | Err(ParseError::Io(_)) => {
+            info!("[client {}] No frame received; closing connection", client_ip);
+            close_connection(&stream, &client_ip);
+            return;
         },
         Err(e) => {
-            response = Frame::with_body(StompCommand::Error, e);
-            stream.write(&response.to_bytes()[..]).unwrap();
+            let response = Frame::error("malformed frame", &e.to_string());
+            write_fatal_error(&mut stream, &response, out.version(), &client_ip);
             return;
         },
     };
-    
+
+    let write_stream = match stream.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("[client {}] Failed to clone stream: {}", client_ip, e);
+            close_connection(&stream, &client_ip);
+            return;
+        },
+    };
+    let write_state = out.state.clone();
+    let write_ip = client_ip.clone();
+    let writer = thread::spawn(move|| {
+        write_frames(write_stream, rx, heart_beat, &write_state, &write_ip);
+        write_state.closed.store(true, Ordering::SeqCst);
+    });
+
+    // A client that sends heart-beats has to be checked on at least that often
+    if let Some(interval) = receive_interval {
+        let timeout = read_timeout(&config).map_or(interval, |t| t.min(interval));
+        if let Err(e) = stream.set_read_timeout(Some(timeout)) {
+            warn!("[client {}] Failed to set read timeout: {}", client_ip, e);
+        }
+    }
+
     // Listen until the client disconnects or something goes wrong
+    let mut last_frame = Instant::now();
+    let mut heard_from = ReceiveClock::new(receive_interval, Instant::now());
+    let mut throttle = config.max_send_rate.map(|rate| SendThrottle::new(rate, Instant::now()));
     loop {
-        let request = parse_frame(&mut stream);
-        
-        let mut response = Frame::new();
+        let request = parse_frame_with_extensions(&mut reader, &config.extensions,
+                                                  &config.parse_limits);
+        if request.is_ok() {
+            last_frame = Instant::now();
+        }
+
         match request {
+            Ok(ref r) if !r.command().is_client_command() => {
+                info!("[client {}] Got server command {}", client_ip, r.command());
+                let message = format!("Clients may not send {} frames.", r.command());
+                let error = Frame::error("unexpected command", &message);
+                queue_fatal_error(&out, &stream, error, &client_ip);
+                break;
+            },
             Ok(r) => {
-                info!("Got request {:?}", r);
+                info!("[client {}] Got request {:?}", client_ip, r);
+                // Application-specific commands are answered here rather than by the broker
+                if let StompCommand::Extension(name) = r.command() {
+                    if let Some(response) = config.extensions.handler(name).and_then(|h| h(&r)) {
+                        if out.send(response).is_err() {
+                            debug!("[client {}] Writer has already finished", client_ip);
+                        }
+                    }
+                    continue;
+                }
+                // A client sending faster than it's allowed to is held up here, which also stops
+                // us reading from it until it's caught up. Its heart-beats wait in the socket in
+                // the meantime and count once they're read, so the wait can't get it disconnected
+                // for missing them; ours come from the writer thread and keep going out.
+                if let Some(ref mut throttle) = throttle {
+                    if r.command() == StompCommand::Send {
+                        let wait = throttle.delay(Instant::now());
+                        if wait > Duration::from_secs(0) {
+                            debug!("[client {}] Sending too fast; waiting {:?}", client_ip, wait);
+                            thread::sleep(wait);
+                        }
+                    }
+                }
+                let disconnect = r.command() == StompCommand::Disconnect;
                 // send the request to the main thread for processing
-                tx.send(r).unwrap();
-                response = rx.recv().unwrap();
+                // If the broker has already let go of the client (e.g. because the server is
+                // shutting down) there's nothing more it can do, so the client is told and
+                // the connection closed
+                if tx.send(r).is_err() {
+                    info!("[client {}] Broker is gone; closing connection", client_ip);
+                    let error = Frame::error("server unavailable", "Server is unavailable.");
+                    queue_fatal_error(&out, &stream, error, &client_ip);
+                    break;
+                }
+                if disconnect {
+                    break;
+                }
+            },
+            Err(ParseError::ReadTimeout) if !config.error_on_read_timeout => {
+                info!("[client {}] Read timeout while parsing frame; closing connection",
+                      client_ip);
+                close_connection(&stream, &client_ip);
+                break;
+            },
+            // Nothing to read yet; the client is allowed to sit idle between frames, up to the
+            // idle timeout, as long as it keeps up any heart-beats it promised
+            Err(ParseError::IdleTimeout) => {
+                heard_from.read(reader.get_ref().last_read());
+                if heard_from.expired(Instant::now()) {
+                    info!("[client {}] Missed heart-beats; closing connection", client_ip);
+                    let message = "No heart-beat received from client.";
+                    let error = Frame::error("heart-beat timeout", message);
+                    queue_fatal_error(&out, &stream, error, &client_ip);
+                    break;
+                }
+                if config.idle_timeout.is_some_and(|idle| last_frame.elapsed() >= idle) {
+                    info!("[client {}] Idle for too long; closing connection", client_ip);
+                    let message = "Connection was idle for too long.";
+                    let error = Frame::error("idle timeout", message);
+                    queue_fatal_error(&out, &stream, error, &client_ip);
+                    break;
+                }
+            },
+            // The client hung up between frames, which is a normal way to leave
+            Err(ParseError::Disconnected) => {
+                info!("[client {}] Client disconnected", client_ip);
+                close_connection(&stream, &client_ip);
+                break;
+            },
+            // Writing an ERROR is pointless if the stream is broken
+            Err(ParseError::Io(kind)) => {
+                info!("[client {}] Failed to read ({:?}); closing connection", client_ip, kind);
+                close_connection(&stream, &client_ip);
+                break;
             },
             Err(e) => {
-                response = Frame::with_body(StompCommand::Error, e);
+                let error = Frame::error("malformed frame", &e.to_string());
+                queue_fatal_error(&out, &stream, error, &client_ip);
+                break;
             },
         };
-        stream.write(&response.to_bytes()[..]).unwrap();
-        // As soon as we write an error to the client, we have to close the connection
-        if response.command == StompCommand::Error {
-            info!("Error sent; closing connection");
-            match stream.shutdown(Shutdown::Both) {
-                Ok(_) => {
-                    info!("Closed connection to client {:?}", client_ip);
-                },
-                Err(e) => {
-                    debug!("Failed to close connection to client {:?}: {:?}", client_ip, e);
-                },
+    }
+
+    // Hanging up on the broker lets it drop the client, which in turn stops the writer
+    drop(tx);
+    drop(out);
+    if writer.join().is_err() {
+        error!("[client {}] Writer thread panicked", client_ip);
+    }
+    info!("[client {}] Ended thread", client_ip);
+}
+
+// Send an ERROR to a client and close the connection, before the writer thread has started
+fn write_fatal_error<S: Stream>(stream: &mut S, error: &Frame, version: StompVersion,
+                               client_ip: &str) {
+    if let Err(e) = write_frame(stream, error, version) {
+        info!("[client {}] Failed to write: {}", client_ip, e);
+    }
+    close_connection(stream, client_ip);
+}
+
+// Hand an ERROR to the writer thread, which closes the connection once it's sent
+// If the writer has already finished the ERROR can't be sent, but the connection still has
+// to be closed
+fn queue_fatal_error<S: Stream>(out: &ClientSender, stream: &S, error: Frame, client_ip: &str) {
+    if out.send(error).is_err() {
+        debug!("[client {}] Writer has already finished", client_ip);
+        close_connection(stream, client_ip);
+    }
+}
+
+// Write frames from the broker to a client until the broker hangs up
+// Frames are buffered and flushed whenever the queue runs dry, so a burst of frames goes out in
+// as few writes as possible without holding any frame back while we wait for the next.
+// If the client asked for heart-beats, one is sent whenever nothing else has been written for
+// the heart-beat interval.
+fn write_frames<S: Stream>(stream: S, rx: Receiver<Frame>, heart_beat: Option<Duration>,
+                state: &WriteState, client_ip: &str) {
+    let mut writer = FrameWriter::new(stream, state);
+    let mut clock = HeartBeatClock::new(heart_beat, Instant::now());
+    loop {
+        let next = match rx.try_recv() {
+            Ok(frame) => Ok(frame),
+            Err(TryRecvError::Disconnected) => Err(RecvTimeoutError::Disconnected),
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = writer.flush() {
+                    info!("[client {}] Failed to write: {}", client_ip, e);
+                    close_connection(writer.stream(), client_ip);
+                    return;
+                }
+                match clock.until_due(Instant::now()) {
+                    Some(wait) => rx.recv_timeout(wait),
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                }
+            },
+        };
+        let frame = match next {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(e) = writer.heart_beat() {
+                    info!("[client {}] Failed to write heart-beat: {}", client_ip, e);
+                    close_connection(writer.stream(), client_ip);
+                    return;
+                }
+                clock.wrote(Instant::now());
+                continue;
+            },
+            // The broker is done with the client; anything buffered still goes out
+            Err(RecvTimeoutError::Disconnected) => {
+                if let Err(e) = writer.flush() {
+                    debug!("[client {}] Failed to write buffered frames: {}", client_ip, e);
+                }
+                return;
+            },
+        };
+        // The broker gave up on the client; drop whatever is still queued
+        if let Some(ref error) = *state.disconnect.lock().unwrap() {
+            info!("[client {}] Disconnecting: {}", client_ip,
+                  String::from_utf8_lossy(error.body()));
+            if writer.write(error).is_err() {
+                debug!("[client {}] Failed to send disconnect error", client_ip);
             }
             break;
         }
+        let size = frame_size(&frame);
+        match writer.write(&frame) {
+            Ok(()) => clock.wrote(Instant::now()),
+            // A frame that breaks the protocol is a bug in the server, so it's left unsent
+            // rather than handed to the client; the rest of the queue still goes out
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                error!("[client {}] Not sending invalid frame: {}", client_ip, e);
+            },
+            // A client going away mid-response is normal; closing the connection makes the
+            // reader hang up on the broker, which drops the client's subscriptions
+            Err(e) => {
+                info!("[client {}] Failed to write: {}", client_ip, e);
+                close_connection(writer.stream(), client_ip);
+                return;
+            },
+        }
+        state.in_flight.fetch_sub(1, Ordering::SeqCst);
+        state.queued_bytes.fetch_sub(size, Ordering::SeqCst);
+        // As soon as we write an error to the client, we have to close the connection
+        if frame.command() == StompCommand::Error {
+            info!("[client {}] Error sent; closing connection", client_ip);
+            break;
+        }
+    }
+    // The ERROR goes out before the connection is closed
+    if let Err(e) = writer.flush() {
+        debug!("[client {}] Failed to write buffered frames: {}", client_ip, e);
+    }
+    close_connection(writer.stream(), client_ip);
+}
+
+// Buffers the frames written to a client
+// A frame only counts as written once it has been flushed to the stream, since that's what
+// anyone waiting on it (e.g. romp-sync) cares about. Each flush is one write to the stream, so
+// over WebSocket several STOMP frames can arrive in one message; STOMP clients split them at
+// the NULs.
+struct FrameWriter<'a, S: Stream> {
+    stream: BufWriter<S>,
+    state: &'a WriteState,
+    unflushed: usize,           // Frames in the buffer
+}
+
+impl<'a, S: Stream> FrameWriter<'a, S> {
+    fn new(stream: S, state: &'a WriteState) -> FrameWriter<'a, S> {
+        FrameWriter {
+            stream: BufWriter::new(stream),
+            state,
+            unflushed: 0,
+        }
+    }
+
+    // An invalid frame is never going to be written, so the writer is finished with it too
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let result = write_frame(&mut self.stream, frame, *self.state.version.lock().unwrap());
+        match result {
+            Err(ref e) if e.kind() != ErrorKind::InvalidData => {},
+            _ => self.unflushed += 1,
+        }
+        result
+    }
+
+    // Send a heart-beat right away
+    fn heart_beat(&mut self) -> io::Result<()> {
+        self.stream.write_all(b"\n")?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()?;
+        self.state.written.fetch_add(self.unflushed, Ordering::SeqCst);
+        self.unflushed = 0;
+        Ok(())
+    }
+
+    fn stream(&self) -> &S {
+        self.stream.get_ref()
+    }
+}
+
+// Write a frame to a client that speaks the given protocol version
+// A frame that breaks the protocol isn't written; the error for it is of kind InvalidData
+fn write_frame<W: Write>(stream: &mut W, frame: &Frame, version: StompVersion) -> io::Result<()> {
+    if let Err(e) = frame.validate(version) {
+        return Err(io::Error::new(ErrorKind::InvalidData, e.to_string()));
+    }
+    stream.write_all(&frame.to_bytes()[..])
+}
+
+// Describe the remote end of a stream for logging
+pub fn peer_name<S: Stream>(stream: &S) -> String {
+    match stream.peer_addr() {
+        Some(addr) => addr.to_string(),
+        None => String::from("unknown"),
     }
-    info!("Ended thread for client {:?}", client_ip);
+}
+
+// Shut down both halves of a client connection
+fn close_connection<S: Stream>(stream: &S, client_ip: &str) {
+    match stream.shutdown() {
+        Ok(_) => {
+            info!("[client {}] Closed connection", client_ip);
+        },
+        Err(e) => {
+            debug!("[client {}] Failed to close connection: {}", client_ip, e);
+        },
+    }
+}
+
+// Determine whether clients may connect to a virtual host
+fn host_allowed(host: &str, config: &Config) -> bool {
+    match config.allowed_hosts {
+        Some(ref hosts) => hosts.iter().any(|h| h == host),
+        None => true,
+    }
+}
+
+// Check a connecting client's credentials
+fn authenticated(r: &Frame, config: &Config) -> bool {
+    let login = r.header().get("login").map(|l| &l[..]);
+    let passcode = r.header().get("passcode").map(|p| &p[..]);
+    config.authenticator.authenticate(login, passcode)
+}
+
+// Heart-beat header for CONNECTED: how often we can send heart-beats, and how often we'd like
+// to get them from the client
+fn heart_beat_header(config: &Config) -> String {
+    let ours = config.heart_beat.map_or(0, |d| d.as_millis());
+    format!("{},{}", ours, ours)
 }
 
 // Handle a new client
-fn do_connect(r: &Frame) -> Frame {
-    let mut response = Frame::new();
+fn do_connect(r: &Frame, config: &Config, session: &str) -> Frame {
+    let mut response;
     // We expect all new connections to begin with a STOMP frame; anything else is invalid
-    if r.command != StompCommand::Stomp {
-        response = Frame::with_body(
-            StompCommand::Error,
-            "Invalid command; expected STOMP or CONNECT."
+    if r.command() != StompCommand::Stomp && config.detailed_connect_errors {
+        let message = format!(
+            "Invalid command; expected STOMP or CONNECT. The first frame on a connection must \
+             be STOMP or CONNECT, but this one was {}. Connect before sending anything else; \
+             the connection will now be closed.",
+            r.command()
         );
+        response = Frame::error("must connect first", &message);
+        response.header_mut().set("romp-error-code", "MUST_CONNECT_FIRST");
+    } else if r.command() != StompCommand::Stomp {
+        response = Frame::error("must connect first",
+                                "Invalid command; expected STOMP or CONNECT.");
 
     // Right type of frame; let's see if we can start talking
     } else {
         // We MUST have accept-version and host
-        if !r.header.contains_key("accept-version") {
-            response = Frame::with_body(
-                StompCommand::Error,
-                "Invalid frame; expected 'accept-version' header."
-            );
-        } else if !r.header.contains_key("host") {
-            response = Frame::with_body(
-                StompCommand::Error,
-                "Invalid frame; expected 'host' header."
-            );
-        } else if r.header.get("accept-version").unwrap() != PROTO_VERS {
-            response = Frame::with_body(
-                StompCommand::Error,
-                "Invalid protocol version."
-            );
+        if !r.header().contains_key("accept-version") {
+            response = Frame::error("malformed frame",
+                                    "Invalid frame; expected 'accept-version' header.");
+        } else if !r.header().contains_key("host") {
+            response = Frame::error("malformed frame", "Invalid frame; expected 'host' header.");
+        } else if !host_allowed(r.header().get("host").unwrap(), config) {
+            response = Frame::error("unknown host", "Unknown virtual host.");
+        } else if negotiate_version(r.header().get("accept-version").unwrap()).is_none() {
+            // Tell the client what we do speak so it can try again
+            response = Frame::error("unsupported version", "Invalid protocol version.");
+            response.header_mut().set("version", &supported_versions());
+        } else if let Some(Err(e)) = r.header().get("heart-beat").map(|h| parse_heart_beat(h)) {
+            response = Frame::error("invalid heart-beat", e);
+        } else if !authenticated(r, config) {
+            response = Frame::error("authentication failed", "Authentication failed.");
         // Respond with a CONNECTED frame
         } else {
-            response = Frame::from_command(StompCommand::Connected);
-            response.header.set("version", "1.2");
-            response.header.set("server", SERVER_STR);
+            let version = negotiate_version(r.header().get("accept-version").unwrap()).unwrap();
+            let mut connected = Frame::builder(StompCommand::Connected)
+                .header("version", version.as_str())
+                .header("session", session)
+                .header("heart-beat", &heart_beat_header(config));
+            if let Some(ref name) = config.server_name {
+                connected = connected.header("server", name);
+            }
+            response = connected.build();
         }
     }
     response
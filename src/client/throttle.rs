@@ -0,0 +1,39 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::time::{Duration, Instant};
+
+// Limits how fast a client's messages are handed to the broker
+// This is a token bucket holding up to a second's worth of messages, so short bursts go
+// straight through and only a sustained rate over the limit is slowed down.
+#[derive(Debug)]
+pub struct SendThrottle {
+    rate: f64,              // Messages per second
+    tokens: f64,            // Messages that can go through right now; negative when behind
+    last: Instant,
+}
+
+impl SendThrottle {
+    pub fn new(rate: u32, now: Instant) -> SendThrottle {
+        SendThrottle {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last: now,
+        }
+    }
+
+    // Take a message's token, returning how long to wait before handling the message
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
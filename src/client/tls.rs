@@ -0,0 +1,169 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::{ServerConfig, ServerConnection};
+use rustls::crypto::ring;
+
+use super::stream::Stream;
+
+const READ_BUFFER: usize = 16 * 1024;      // Encrypted bytes read from the socket at a time
+
+// Load a certificate chain and private key from PEM files into a TLS configuration
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, String> {
+    let mut certs = BufReader::new(File::open(cert)
+        .map_err(|e| format!("Failed to open {}: {}", cert.display(), e))?);
+    let certs = rustls_pemfile::certs(&mut certs)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to read {}: {}", cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", cert.display()));
+    }
+    let mut key_file = BufReader::new(File::open(key)
+        .map_err(|e| format!("Failed to open {}: {}", key.display(), e))?);
+    let key = rustls_pemfile::private_key(&mut key_file)
+        .map_err(|e| format!("Failed to read {}: {}", key.display(), e))?
+        .ok_or_else(|| format!("No private key in {}", key.display()))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Failed to set up TLS: {}", e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+    Ok(Arc::new(config))
+}
+
+// STOMP carried over TLS
+// The handshake happens on the first read. Clones share the TLS session, so the reader and
+// writer threads can both use it. The session is only locked while bytes are going in or out
+// of it; a read waits for the socket without it, so a blocked reader never holds up the writer.
+pub struct TlsStream<S> {
+    inner: S,
+    session: Arc<Mutex<ServerConnection>>,
+    buffer: Vec<u8>,
+}
+
+impl<S: Stream> TlsStream<S> {
+    pub fn new(inner: S, config: Arc<ServerConfig>) -> io::Result<TlsStream<S>> {
+        let session = ServerConnection::new(config)
+            .map_err(io::Error::other)?;
+        Ok(TlsStream {
+            inner,
+            session: Arc::new(Mutex::new(session)),
+            buffer: vec![0; READ_BUFFER],
+        })
+    }
+
+    // Send whatever the session has ready for the client: handshake messages, alerts and
+    // encrypted data
+    fn send_tls(session: &mut ServerConnection, inner: &mut S) -> io::Result<()> {
+        while session.wants_write() {
+            session.write_tls(inner)?;
+        }
+        Ok(())
+    }
+}
+
+// Take the TLS session shared by a connection's handles
+fn lock(session: &Mutex<ServerConnection>) -> io::Result<MutexGuard<'_, ServerConnection>> {
+    session.lock().map_err(|_| io::Error::other("TLS session poisoned"))
+}
+
+impl<S: Stream> Stream for TlsStream<S> {
+    fn try_clone(&self) -> io::Result<TlsStream<S>> {
+        Ok(TlsStream {
+            inner: self.inner.try_clone()?,
+            session: self.session.clone(),
+            buffer: vec![0; READ_BUFFER],
+        })
+    }
+
+    // The client is told the session is over before the connection closes
+    fn shutdown(&self) -> io::Result<()> {
+        if let Ok(mut session) = lock(&self.session) {
+            session.send_close_notify();
+            if let Ok(mut inner) = self.inner.try_clone() {
+                if TlsStream::send_tls(&mut session, &mut inner).is_err() {
+                    debug!("Failed to send TLS close_notify");
+                }
+            }
+        }
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl<S: Stream> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = lock(&self.session)?;
+                match session.reader().read(buf) {
+                    // Zero means the client ended the session cleanly
+                    Ok(n) => return Ok(n),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e),
+                }
+            }
+            // Nothing decrypted yet, so wait for more from the client; a read timeout on the
+            // socket comes back to the caller like any other
+            let n = self.inner.read(&mut self.buffer)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut session = lock(&self.session)?;
+            let mut received = &self.buffer[..n];
+            while !received.is_empty() {
+                session.read_tls(&mut received)?;
+                if let Err(e) = session.process_new_packets() {
+                    // Let the client know why before giving up on it
+                    if TlsStream::send_tls(&mut session, &mut self.inner).is_err() {
+                        debug!("Failed to send TLS alert");
+                    }
+                    return Err(io::Error::new(ErrorKind::InvalidData, e));
+                }
+            }
+            TlsStream::send_tls(&mut session, &mut self.inner)?;
+        }
+    }
+}
+
+impl<S: Stream> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = lock(&self.session)?;
+        let n = session.writer().write(buf)?;
+        TlsStream::send_tls(&mut session, &mut self.inner)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = lock(&self.session)?;
+        session.writer().flush()?;
+        TlsStream::send_tls(&mut session, &mut self.inner)?;
+        self.inner.flush()
+    }
+}
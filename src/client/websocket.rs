@@ -0,0 +1,272 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha1::{Digest, Sha1};
+
+use super::stream::Stream;
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";   // From RFC 6455
+const MAX_HANDSHAKE: usize = 8192;          // Longest upgrade request we'll read
+const STOMP_PROTOCOL: &str = "v12.stomp";   // Subprotocol name used by STOMP-over-WebSocket clients
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+// STOMP carried inside WebSocket messages
+// The upgrade handshake happens on the first read. After that, reads return the payload of the
+// client's data frames and every write is sent as a single data frame. Clones share a lock
+// so that pongs sent while reading can't land in the middle of a frame being written.
+pub struct WsStream<S> {
+    inner: S,
+    handshake_done: bool,
+    remaining: u64,             // Payload bytes left in the data frame being read
+    mask: [u8; 4],
+    mask_pos: usize,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl<S: Stream> WsStream<S> {
+    pub fn new(inner: S) -> WsStream<S> {
+        WsStream {
+            inner,
+            handshake_done: false,
+            remaining: 0,
+            mask: [0; 4],
+            mask_pos: 0,
+            write_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    // Read the client's HTTP upgrade request and accept it
+    fn handshake(&mut self) -> io::Result<()> {
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            if request.len() >= MAX_HANDSHAKE {
+                return self.refuse("Upgrade request is too long");
+            }
+            self.inner.read_exact(&mut byte)?;
+            request.push(byte[0]);
+        }
+        let request = String::from_utf8_lossy(&request).into_owned();
+
+        let mut key = None;
+        let mut stomp = false;
+        for line in request.split("\r\n").skip(1) {
+            let (name, value) = match line.find(':') {
+                Some(i) => (line[..i].trim().to_lowercase(), line[i + 1..].trim()),
+                None => continue,
+            };
+            match &name[..] {
+                "sec-websocket-key" => key = Some(String::from(value)),
+                "sec-websocket-protocol" => {
+                    stomp = value.split(',').any(|p| p.trim() == STOMP_PROTOCOL);
+                },
+                _ => { },
+            }
+        }
+        let key = match key {
+            Some(key) => key,
+            None => return self.refuse("Missing Sec-WebSocket-Key"),
+        };
+
+        let mut response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                    Upgrade: websocket\r\n\
+                                    Connection: Upgrade\r\n\
+                                    Sec-WebSocket-Accept: {}\r\n", accept_key(&key));
+        if stomp {
+            response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", STOMP_PROTOCOL));
+        }
+        response.push_str("\r\n");
+        let _lock = self.write_lock.lock().unwrap();
+        self.inner.write_all(response.as_bytes())
+    }
+
+    // Turn down a bad upgrade request
+    fn refuse(&mut self, reason: &str) -> io::Result<()> {
+        let response = b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n";
+        if self.inner.write_all(response).is_err() {
+            debug!("Failed to refuse WebSocket upgrade");
+        }
+        Err(io::Error::new(ErrorKind::InvalidData, reason))
+    }
+
+    // Read the header of the next frame; returns the opcode and payload length
+    fn read_frame_header(&mut self) -> io::Result<(u8, u64)> {
+        let mut header = [0u8; 2];
+        self.inner.read_exact(&mut header)?;
+        let opcode = header[0] & 0x0F;
+        let masked = header[1] & 0x80 != 0;
+        let length = match header[1] & 0x7F {
+            126 => {
+                let mut ext = [0u8; 2];
+                self.inner.read_exact(&mut ext)?;
+                u64::from(u16::from_be_bytes(ext))
+            },
+            127 => {
+                let mut ext = [0u8; 8];
+                self.inner.read_exact(&mut ext)?;
+                u64::from_be_bytes(ext)
+            },
+            n => u64::from(n),
+        };
+        // Clients are required to mask everything they send
+        if !masked {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Unmasked WebSocket frame"));
+        }
+        self.inner.read_exact(&mut self.mask)?;
+        self.mask_pos = 0;
+        Ok((opcode, length))
+    }
+
+    // Read and unmask the payload of a control frame
+    fn read_control_payload(&mut self, length: u64) -> io::Result<Vec<u8>> {
+        if length > 125 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Control frame is too long"));
+        }
+        let mut payload = vec![0u8; length as usize];
+        self.inner.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= self.mask[i % 4];
+        }
+        Ok(payload)
+    }
+
+    // Send one unmasked frame
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        } else if payload.len() <= 0xFFFF {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            frame.push(127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+        let _lock = self.write_lock.lock().unwrap();
+        self.inner.write_all(&frame)
+    }
+}
+
+// Compute the Sec-WebSocket-Accept value for a client's key
+fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(WS_GUID.as_bytes());
+    BASE64.encode(sha.finalize())
+}
+
+impl<S: Stream> Read for WsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.handshake_done {
+            self.handshake()?;
+            self.handshake_done = true;
+        }
+
+        // Skip over control frames until there's data to return
+        while self.remaining == 0 {
+            let (opcode, length) = self.read_frame_header()?;
+            match opcode {
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                    self.remaining = length;
+                },
+                OP_PING => {
+                    let payload = self.read_control_payload(length)?;
+                    self.write_frame(OP_PONG, &payload)?;
+                },
+                OP_PONG => {
+                    self.read_control_payload(length)?;
+                },
+                // The client is going away; answer the close and report the end of the stream
+                OP_CLOSE => {
+                    self.read_control_payload(length)?;
+                    if self.write_frame(OP_CLOSE, &[]).is_err() {
+                        debug!("Failed to answer WebSocket close");
+                    }
+                    return Ok(0);
+                },
+                _ => {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "Unknown WebSocket opcode"));
+                },
+            }
+        }
+
+        let want = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Ok(0);
+        }
+        for b in buf[..n].iter_mut() {
+            *b ^= self.mask[self.mask_pos];
+            self.mask_pos = (self.mask_pos + 1) % 4;
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// Each write goes out as a whole message, so a STOMP frame is never split across messages as
+// long as it's written in one call. A write can hold several frames, e.g. when the client
+// writer flushes its buffer. Text messages have to be UTF-8, so anything else (i.e. a frame
+// with a binary body) goes out as a binary message instead.
+impl<S: Stream> Write for WsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let opcode = if str::from_utf8(buf).is_ok() { OP_TEXT } else { OP_BINARY };
+        self.write_frame(opcode, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Clones share the write lock but keep their own read state; only one of them should read
+impl<S: Stream> Stream for WsStream<S> {
+    fn try_clone(&self) -> io::Result<WsStream<S>> {
+        Ok(WsStream {
+            inner: self.inner.try_clone()?,
+            handshake_done: self.handshake_done,
+            remaining: 0,
+            mask: [0; 4],
+            mask_pos: 0,
+            write_lock: self.write_lock.clone(),
+        })
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
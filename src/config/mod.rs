@@ -0,0 +1,440 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::stomp::{ExtensionRegistry, SERVER_STR};
+use super::stomp::parse::ParseLimits;
+use super::auth::{Authenticator, AllowAll, StaticCredentials};
+
+const DEFAULT_PORT: u16 = 61616;            // Default port for STOMP clients
+const DEFAULT_BACKLOG: i32 = 128;           // Same as the standard library's listeners
+const DEFAULT_TIMEOUT_SECS: u64 = 10;       // Default read/write timeout
+const DEFAULT_METRICS_SECS: u64 = 60;       // Default time between metrics log lines
+const DEFAULT_MAX_QUEUE_DEPTH: usize = 1000; // Default limit on messages held for a queue
+const DEFAULT_MAX_REDELIVERIES: usize = 5;  // Default times a NACKed message is sent again
+const DEFAULT_MAX_BACKLOG: usize = 1000;    // Default limit on frames held for a slow consumer
+
+// What to do when a client's write queue reaches max_in_flight
+// Frames that are held for a client wait in a backlog in the broker, which hands them over as the
+// client catches up; other clients are served in the meantime. Queue messages go to subscribers
+// with room first. A client whose backlog reaches max_backlog is disconnected, unless the policy
+// lets it make room.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowConsumerPolicy {
+    Block,          // Hold frames for the client until it catches up
+    Disconnect,     // Close the connection with an ERROR
+    Drop,           // Hold frames too, but drop the oldest topic message when the backlog is full
+}
+
+// Server configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Port to accept STOMP clients on (0 to have the OS pick one)
+    pub port: u16,
+    // Connections the OS holds for us until they're accepted
+    pub listen_backlog: i32,
+    // Allow binding our ports again while old connections to them are still closing
+    // (SO_REUSEADDR; only used on Unix, where the standard library sets it too)
+    pub reuse_address: bool,
+    // Write frames to clients as soon as they're ready instead of letting the OS batch small
+    // ones together (TCP_NODELAY). On by default, since waiting to batch can hold a small frame
+    // back for tens of milliseconds.
+    pub tcp_nodelay: bool,
+    // Socket timeouts for client connections (None for no timeout)
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    // Send an ERROR frame before closing a connection that stalls mid-frame past the read
+    // timeout. Off by default since the write to a stalled socket may time out as well.
+    pub error_on_read_timeout: bool,
+    // Close connections that haven't sent a frame for this long, with an ERROR (None to let
+    // connections sit idle forever)
+    pub idle_timeout: Option<Duration>,
+    // Shortest interval at which we'll send heart-beats to clients that ask for them, and at
+    // which we ask clients to send theirs (None for no heart-beats either way)
+    pub heart_beat: Option<Duration>,
+    // Sent to clients in the server header of CONNECTED (None to leave the header out)
+    pub server_name: Option<String>,
+    // Also accept STOMP-over-WebSocket clients on this port (None for no WebSocket listener)
+    pub ws_port: Option<u16>,
+    // Also accept clients on a Unix domain socket at this path (None for TCP only)
+    pub unix_socket: Option<PathBuf>,
+    // PEM files with the certificate chain and private key to serve TCP and WebSocket clients
+    // over TLS with (None for plain connections); both or neither are set
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // Checks the login and passcode of connecting clients
+    pub authenticator: Arc<dyn Authenticator>,
+    // Virtual hosts clients may connect to; anything else is refused (None to accept any host)
+    pub allowed_hosts: Option<Vec<String>>,
+    // Explain in more detail why a connection was refused when its first frame isn't STOMP or
+    // CONNECT, with a romp-error-code header that clients can check for
+    pub detailed_connect_errors: bool,
+    // Number of worker threads servicing client connections; connections beyond this get an
+    // ERROR and are closed (None for a thread per connection). Each connection being serviced
+    // also has a thread writing to it.
+    pub worker_threads: Option<usize>,
+    // Maximum number of clients connected at once; further connections get an ERROR and are
+    // closed (None for no limit)
+    pub max_connections: Option<usize>,
+    // Route destinations that differ only in case to the same subscribers
+    pub case_insensitive_destinations: bool,
+    // Treat SUBSCRIBE destinations containing '*' or '#' segments as patterns that match many
+    // destinations (see broker::registry::matches); otherwise they only match themselves
+    pub wildcard_subscriptions: bool,
+    // Maximum number of subscribers to a single destination (None for no limit)
+    pub max_subscribers_per_destination: Option<usize>,
+    // Most unacknowledged messages a subscription that acknowledges messages may have at once,
+    // unless its SUBSCRIBE has a prefetch-count header (None for no limit)
+    pub default_prefetch: Option<usize>,
+    // Maximum number of messages held for a queue with no subscribers; further SENDs to it get
+    // an ERROR (None for no limit)
+    pub max_queue_depth: Option<usize>,
+    // Where messages go when they can't be delivered, e.g. because their queue is full (None
+    // to refuse them with an ERROR)
+    pub dead_letter_destination: Option<String>,
+    // Times a queue message that's NACKed is delivered again before it goes to the dead-letter
+    // destination (None to keep trying forever)
+    pub max_redeliveries: Option<usize>,
+    // How long a subscription that acknowledges messages has to ACK or NACK one before it's
+    // delivered again (None to wait forever)
+    pub ack_timeout: Option<Duration>,
+    // Maximum number of frames queued for a client but not yet written (None for no limit)
+    pub max_in_flight: Option<usize>,
+    // Maximum number of frames held back for a client that's reached max_in_flight (None for
+    // no limit)
+    pub max_backlog: Option<usize>,
+    // Maximum messages per second a single connection may send; faster senders are slowed
+    // down (None for no limit)
+    pub max_send_rate: Option<u32>,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    // Maximum approximate bytes held for a single connection before it's dropped, counting
+    // frames waiting to be written, unacknowledged messages and open transactions (None for no
+    // limit)
+    pub max_connection_memory: Option<usize>,
+    // Maximum number of things held for a single connection at once: frames waiting to be
+    // written, unacknowledged messages, open transactions and the frames they hold, and
+    // romp-sync receipts not sent yet. A client over the budget is dropped with an ERROR whose
+    // romp-error-code says which of them used the last of it (None for no limit)
+    pub max_connection_budget: Option<usize>,
+    // host:port sent to clients in a romp-redirect header when the server drains, so they
+    // can reconnect elsewhere
+    pub redirect: Option<String>,
+    // How often to log a summary of the server's metrics (None to never log it)
+    pub metrics_interval: Option<Duration>,
+    // Application-specific commands and their handlers
+    pub extensions: ExtensionRegistry,
+    // Limits on the headers and body of frames from clients
+    pub parse_limits: ParseLimits,
+    // Directory to record the raw traffic of client connections into, for debugging (None
+    // to record nothing)
+    pub capture_dir: Option<PathBuf>,
+    // Only record connections from these addresses; empty to record every connection
+    pub capture_peers: Vec<IpAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+impl Config {
+    // Create a configuration with the default settings
+    pub fn new() -> Config {
+        Config {
+            port: DEFAULT_PORT,
+            listen_backlog: DEFAULT_BACKLOG,
+            reuse_address: true,
+            tcp_nodelay: true,
+            read_timeout: Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)),
+            write_timeout: Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)),
+            error_on_read_timeout: false,
+            idle_timeout: None,
+            heart_beat: None,
+            server_name: Some(String::from(SERVER_STR)),
+            ws_port: None,
+            unix_socket: None,
+            tls_cert: None,
+            tls_key: None,
+            authenticator: Arc::new(AllowAll),
+            allowed_hosts: None,
+            detailed_connect_errors: false,
+            worker_threads: None,
+            max_connections: None,
+            case_insensitive_destinations: false,
+            wildcard_subscriptions: false,
+            max_subscribers_per_destination: None,
+            default_prefetch: None,
+            max_queue_depth: Some(DEFAULT_MAX_QUEUE_DEPTH),
+            dead_letter_destination: None,
+            max_redeliveries: Some(DEFAULT_MAX_REDELIVERIES),
+            ack_timeout: None,
+            max_in_flight: None,
+            max_backlog: Some(DEFAULT_MAX_BACKLOG),
+            max_send_rate: None,
+            slow_consumer_policy: SlowConsumerPolicy::Block,
+            max_connection_memory: None,
+            max_connection_budget: None,
+            redirect: None,
+            metrics_interval: Some(Duration::from_secs(DEFAULT_METRICS_SECS)),
+            extensions: ExtensionRegistry::new(),
+            parse_limits: ParseLimits::new(),
+            capture_dir: None,
+            capture_peers: Vec::new(),
+        }
+    }
+
+    // Create a configuration from command line arguments (not including the program name)
+    //   --port PORT             Accept clients on PORT; 0 for any free port
+    //   --backlog N             Let the OS hold up to N connections waiting to be accepted
+    //   --no-reuse-address      Don't set SO_REUSEADDR on the listening sockets
+    //   --nodelay               Set TCP_NODELAY on client connections (the default)
+    //   --no-nodelay            Let the OS batch small writes to clients (no TCP_NODELAY)
+    //   --read-timeout SECS     Read timeout for client connections; 0 for no timeout
+    //   --write-timeout SECS    Write timeout for client connections; 0 for no timeout
+    //   --idle-timeout SECS     Close connections that send no frames for SECS; 0 for never
+    //   --error-on-read-timeout Send an ERROR before closing connections that stall mid-frame
+    //   --heart-beat MS         Send heart-beats no more often than every MS milliseconds, and
+    //                           expect them from clients at the same rate
+    //   --server-name NAME      Identify the server as NAME to clients; empty to not identify it
+    //   --detailed-connect-errors
+    //                           Explain refused connections in more detail, with an error code
+    //   --workers N             Service at most N clients at once, each on a worker thread;
+    //                           0 for a thread per client
+    //   --max-connections N     Allow at most N clients at once; 0 for no limit
+    //   --case-insensitive      Treat destinations that differ only in case as the same
+    //   --wildcards             Allow wildcard patterns in SUBSCRIBE destinations
+    //   --max-subscribers N     Allow at most N subscribers to each destination; 0 for no limit
+    //   --prefetch N            Send each subscription at most N unacknowledged messages at a
+    //                           time unless it asks otherwise; 0 for no limit
+    //   --max-queue-depth N     Hold at most N messages for a queue; 0 for no limit
+    //   --dead-letter DEST      Send undeliverable messages to DEST
+    //   --max-redeliveries N    Redeliver a NACKed message at most N times; 0 to send NACKed
+    //                           messages straight to the dead-letter destination
+    //   --ack-timeout SECS      Redeliver messages that aren't acknowledged within SECS; 0 to
+    //                           wait forever
+    //   --max-body-size BYTES   Refuse frames with bodies larger than BYTES; 0 for no limit
+    //   --max-headers N         Refuse frames with more than N headers; 0 for no limit
+    //   --max-header-line BYTES Refuse frames with a command or header line longer than BYTES;
+    //                           0 for no limit
+    //   --max-send-rate N       Slow down connections sending more than N messages a second
+    //   --max-in-flight N       Allow at most N frames waiting to be written to a client; 0 for
+    //                           no limit
+    //   --max-backlog N         Hold back at most N more frames for a client at that limit; 0
+    //                           for no limit
+    //   --slow-consumer POLICY  What to do when a client is full: block, disconnect or drop
+    //   --max-connection-memory BYTES
+    //                           Disconnect clients the server is holding over BYTES for; 0
+    //                           for no limit
+    //   --connection-budget N   Disconnect clients the server is holding over N frames,
+    //                           messages, transactions and receipts for; 0 for no limit
+    //   --redirect HOST:PORT    Send draining clients to HOST:PORT
+    //   --metrics-interval SECS Log a summary of the metrics every SECS; 0 for never
+    //   --ws-port PORT          Also accept WebSocket clients on PORT
+    //   --unix-socket PATH      Also accept clients on a Unix domain socket at PATH
+    //   --tls-cert FILE         Serve TCP and WebSocket clients over TLS with the certificate
+    //                           chain in FILE (PEM); needs --tls-key
+    //   --tls-key FILE          Private key (PEM) for --tls-cert
+    //   --credentials FILE      Only accept clients with a login:passcode listed in FILE
+    //   --allowed-host HOST     Only accept clients connecting to virtual host HOST; may be
+    //                           repeated
+    //   --capture DIR           Record the raw traffic of client connections into DIR
+    //   --capture-peer ADDR     Only record connections from ADDR; may be repeated
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut config = Config::new();
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--port" => {
+                    config.port = parse_number(&arg, args.next())?;
+                },
+                "--backlog" => {
+                    let value = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    match value.parse::<i32>() {
+                        Ok(backlog) if backlog > 0 => config.listen_backlog = backlog,
+                        _ => return Err(format!("Invalid value for {}: {}", arg, value)),
+                    }
+                },
+                "--no-reuse-address" => {
+                    config.reuse_address = false;
+                },
+                "--nodelay" => {
+                    config.tcp_nodelay = true;
+                },
+                "--no-nodelay" => {
+                    config.tcp_nodelay = false;
+                },
+                "--read-timeout" => {
+                    config.read_timeout = parse_timeout(&arg, args.next())?;
+                },
+                "--write-timeout" => {
+                    config.write_timeout = parse_timeout(&arg, args.next())?;
+                },
+                "--idle-timeout" => {
+                    config.idle_timeout = parse_timeout(&arg, args.next())?;
+                },
+                "--error-on-read-timeout" => {
+                    config.error_on_read_timeout = true;
+                },
+                "--heart-beat" => {
+                    let ms = parse_limit(&arg, args.next())?;
+                    config.heart_beat = ms.map(Duration::from_millis);
+                },
+                "--server-name" => {
+                    let name = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.server_name = if name.is_empty() { None } else { Some(name) };
+                },
+                "--detailed-connect-errors" => {
+                    config.detailed_connect_errors = true;
+                },
+                "--workers" => {
+                    config.worker_threads = parse_limit(&arg, args.next())?;
+                },
+                "--max-connections" => {
+                    config.max_connections = parse_limit(&arg, args.next())?;
+                },
+                "--case-insensitive" => {
+                    config.case_insensitive_destinations = true;
+                },
+                "--wildcards" => {
+                    config.wildcard_subscriptions = true;
+                },
+                "--max-subscribers" => {
+                    config.max_subscribers_per_destination = parse_limit(&arg, args.next())?;
+                },
+                "--prefetch" => {
+                    config.default_prefetch = parse_limit(&arg, args.next())?;
+                },
+                "--max-queue-depth" => {
+                    config.max_queue_depth = parse_limit(&arg, args.next())?;
+                },
+                "--dead-letter" => {
+                    let dest = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.dead_letter_destination = Some(dest);
+                },
+                "--max-redeliveries" => {
+                    config.max_redeliveries = Some(parse_number(&arg, args.next())?);
+                },
+                "--ack-timeout" => {
+                    config.ack_timeout = parse_timeout(&arg, args.next())?;
+                },
+                "--max-body-size" => {
+                    config.parse_limits.max_body_size = parse_limit(&arg, args.next())?;
+                },
+                "--max-headers" => {
+                    let max = parse_limit(&arg, args.next())?;
+                    config.parse_limits.max_headers = max.unwrap_or(usize::MAX);
+                },
+                "--max-header-line" => {
+                    let max = parse_limit(&arg, args.next())?;
+                    config.parse_limits.max_header_line = max.unwrap_or(usize::MAX);
+                },
+                "--max-send-rate" => {
+                    config.max_send_rate = parse_limit(&arg, args.next())?;
+                },
+                "--max-in-flight" => {
+                    config.max_in_flight = parse_limit(&arg, args.next())?;
+                },
+                "--max-backlog" => {
+                    config.max_backlog = parse_limit(&arg, args.next())?;
+                },
+                "--slow-consumer" => {
+                    let value = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.slow_consumer_policy = match &value[..] {
+                        "block" => SlowConsumerPolicy::Block,
+                        "disconnect" => SlowConsumerPolicy::Disconnect,
+                        "drop" => SlowConsumerPolicy::Drop,
+                        _ => return Err(format!("Invalid value for {}: {}", arg, value)),
+                    };
+                },
+                "--max-connection-memory" => {
+                    config.max_connection_memory = parse_limit(&arg, args.next())?;
+                },
+                "--connection-budget" => {
+                    config.max_connection_budget = parse_limit(&arg, args.next())?;
+                },
+                "--redirect" => {
+                    let addr = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.redirect = Some(addr);
+                },
+                "--metrics-interval" => {
+                    config.metrics_interval = parse_timeout(&arg, args.next())?;
+                },
+                "--ws-port" => {
+                    config.ws_port = Some(parse_number(&arg, args.next())?);
+                },
+                "--unix-socket" => {
+                    let path = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.unix_socket = Some(PathBuf::from(path));
+                },
+                "--tls-cert" => {
+                    let path = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.tls_cert = Some(PathBuf::from(path));
+                },
+                "--tls-key" => {
+                    let path = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.tls_key = Some(PathBuf::from(path));
+                },
+                "--credentials" => {
+                    let path = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    let credentials = StaticCredentials::from_file(Path::new(&path))?;
+                    config.authenticator = Arc::new(credentials);
+                },
+                "--allowed-host" => {
+                    let host = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.allowed_hosts.get_or_insert_with(Vec::new).push(host);
+                },
+                "--capture" => {
+                    let dir = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    config.capture_dir = Some(PathBuf::from(dir));
+                },
+                "--capture-peer" => {
+                    let value = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                    match value.parse::<IpAddr>() {
+                        Ok(addr) => config.capture_peers.push(addr),
+                        Err(_) => return Err(format!("Invalid value for {}: {}", arg, value)),
+                    }
+                },
+                _ => {
+                    return Err(format!("Unknown option {}", arg));
+                },
+            }
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err(String::from("--tls-cert and --tls-key must be given together"));
+        }
+        Ok(config)
+    }
+
+    // Whether the traffic of a connection from the given address should be recorded
+    pub fn should_capture(&self, peer: Option<IpAddr>) -> bool {
+        if self.capture_dir.is_none() {
+            return false;
+        }
+        self.capture_peers.is_empty() || peer.is_some_and(|p| self.capture_peers.contains(&p))
+    }
+}
+
+// Parse the value of a numeric flag
+fn parse_number<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", flag))?;
+    value.parse::<T>().map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+// Parse the value of a flag that sets a limit; zero means no limit
+fn parse_limit<T: FromStr + Default + PartialEq>(flag: &str, value: Option<String>)
+        -> Result<Option<T>, String> {
+    let limit = parse_number(flag, value)?;
+    Ok(if limit == T::default() { None } else { Some(limit) })
+}
+
+// Parse the value of a timeout flag, given in seconds; zero means no timeout
+fn parse_timeout(flag: &str, value: Option<String>) -> Result<Option<Duration>, String> {
+    Ok(parse_limit(flag, value)?.map(Duration::from_secs))
+}
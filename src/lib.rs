@@ -0,0 +1,28 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+// Romp as a library: the STOMP protocol types, the broker, and the server that ties them to
+// the network. The romp binary is a thin command-line wrapper around server::run().
+#[macro_use]
+extern crate log;
+extern crate ctrlc;
+extern crate sha1;
+extern crate base64;
+extern crate socket2;
+extern crate rustls;
+extern crate rustls_pemfile;
+#[cfg(unix)]
+extern crate libc;
+
+pub mod stomp;
+pub mod client;
+pub mod config;
+pub mod broker;
+pub mod auth;
+pub mod metrics;
+pub mod server;
+pub mod logger;
+
+mod pool;
@@ -0,0 +1,51 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::io::Write;
+use std::sync::Mutex;
+
+use log::{self, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+
+// Writes each log line at or above a level to a writer (stdout for the romp binary)
+// A failed write (e.g. the log consumer exited and closed the pipe) is dropped, since there's
+// nowhere to report it; logging must never take the server down.
+pub struct SimpleLogger<W> {
+    level: LogLevelFilter,
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send + 'static> SimpleLogger<W> {
+    pub fn new(level: LogLevelFilter, out: W) -> SimpleLogger<W> {
+        SimpleLogger {
+            level,
+            out: Mutex::new(out),
+        }
+    }
+
+    // Install a logger as the one the log macros write to
+    pub fn init(level: LogLevelFilter, out: W) -> Result<(), SetLoggerError> {
+        log::set_logger(|max_log_level| {
+            max_log_level.set(level);
+            Box::new(SimpleLogger::new(level, out))
+        })
+    }
+}
+
+impl<W: Write + Send> log::Log for SimpleLogger<W> {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            // A writer that panicked while logging can still take the lines after it
+            let mut out = match self.out.lock() {
+                Ok(out) => out,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            writeln!(out, "{} - {}", record.level(), record.args()).ok();
+        }
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counters describing the server's activity, shared by the broker and client threads
+pub struct Metrics {
+    connections: AtomicUsize,           // Connections being serviced or waiting for a worker
+    frames_processed: AtomicUsize,      // Frames handled by the broker
+    messages_delivered: AtomicUsize,    // MESSAGE frames queued for subscribers
+    messages_waiting: AtomicUsize,      // Messages held for queues with no subscribers
+    messages_unacked: AtomicUsize,      // Messages delivered but not acknowledged yet
+    connection_memory: AtomicUsize,     // Approximate bytes held for all connections
+    subscribers: Mutex<HashMap<String, usize>>,
+    messages: Mutex<HashMap<String, MessageCounts>>,
+}
+
+// How many messages have gone through one destination
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MessageCounts {
+    pub published: usize,   // SENDs accepted for the destination
+    pub delivered: usize,   // MESSAGE frames queued for its subscribers, counting redeliveries
+}
+
+// The value of every counter at one moment
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    pub connections: usize,
+    pub frames_processed: usize,
+    pub messages_delivered: usize,
+    pub messages_waiting: usize,
+    pub messages_unacked: usize,
+    pub connection_memory: usize,
+    pub subscribers: HashMap<String, usize>,    // Subscriber count for each destination
+    pub messages: HashMap<String, MessageCounts>,   // Message counts for each destination
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            connections: AtomicUsize::new(0),
+            frames_processed: AtomicUsize::new(0),
+            messages_delivered: AtomicUsize::new(0),
+            messages_waiting: AtomicUsize::new(0),
+            messages_unacked: AtomicUsize::new(0),
+            connection_memory: AtomicUsize::new(0),
+            subscribers: Mutex::new(HashMap::new()),
+            messages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn frame_processed(&self) {
+        self.frames_processed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn message_delivered(&self) {
+        self.messages_delivered.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn message_held(&self) {
+        self.messages_waiting.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn messages_released(&self, count: usize) {
+        self.messages_waiting.fetch_sub(count, Ordering::SeqCst);
+    }
+
+    // Replace the count of unacknowledged messages with the broker's current one
+    pub fn set_messages_unacked(&self, count: usize) {
+        self.messages_unacked.store(count, Ordering::SeqCst);
+    }
+
+    // Replace the connection memory total with the broker's current one
+    pub fn set_connection_memory(&self, bytes: usize) {
+        self.connection_memory.store(bytes, Ordering::SeqCst);
+    }
+
+    // Replace the subscriber counts with the broker's current ones
+    pub fn set_subscribers(&self, counts: HashMap<String, usize>) {
+        *self.subscribers.lock().unwrap() = counts;
+    }
+
+    // Update one destination's message counts with the broker's current ones
+    pub fn set_message_counts(&self, destination: &str, counts: MessageCounts) {
+        let mut messages = self.messages.lock().unwrap();
+        match messages.get_mut(destination) {
+            Some(current) => *current = counts,
+            None => {
+                messages.insert(String::from(destination), counts);
+            },
+        }
+    }
+
+    // Read every counter
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections: self.connections.load(Ordering::SeqCst),
+            frames_processed: self.frames_processed.load(Ordering::SeqCst),
+            messages_delivered: self.messages_delivered.load(Ordering::SeqCst),
+            messages_waiting: self.messages_waiting.load(Ordering::SeqCst),
+            messages_unacked: self.messages_unacked.load(Ordering::SeqCst),
+            connection_memory: self.connection_memory.load(Ordering::SeqCst),
+            subscribers: self.subscribers.lock().unwrap().clone(),
+            messages: self.messages.lock().unwrap().clone(),
+        }
+    }
+}
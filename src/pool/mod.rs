@@ -0,0 +1,99 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Runs jobs on a fixed number of worker threads, or on a new thread each if no size is given
+// Jobs are only taken while a worker is free to start them, so none sit waiting in a queue.
+pub struct ThreadPool {
+    jobs: Option<Sender<Job>>,
+    size: usize,
+    busy: Arc<AtomicUsize>,     // Jobs submitted and not yet finished
+}
+
+// Lets the submitter of a job wait for it to finish
+pub struct TaskHandle {
+    done: Receiver<()>,
+}
+
+impl TaskHandle {
+    // Wait for the job to finish; returns an error if it panicked
+    pub fn join(self) -> Result<(), ()> {
+        self.done.recv().map_err(|_| ())
+    }
+}
+
+impl ThreadPool {
+    pub fn new(size: Option<usize>) -> ThreadPool {
+        let busy = Arc::new(AtomicUsize::new(0));
+        let size = match size {
+            Some(size) => size,
+            None => return ThreadPool { jobs: None, size: 0, busy },
+        };
+
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..size {
+            let rx = rx.clone();
+            let busy = busy.clone();
+            thread::spawn(move|| {
+                loop {
+                    // The lock is only held while waiting for the next job
+                    let job = match rx.lock() {
+                        Ok(rx) => rx.recv(),
+                        Err(_) => break,
+                    };
+                    match job {
+                        // A panicking job shouldn't take the worker down with it
+                        Ok(job) => {
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                error!("Job panicked on worker thread");
+                            }
+                            busy.fetch_sub(1, Ordering::SeqCst);
+                        },
+                        // The pool has been dropped and the queue is empty
+                        Err(_) => break,
+                    }
+                }
+            });
+        }
+        ThreadPool { jobs: Some(tx), size, busy }
+    }
+
+    // Run a job on the pool if a worker is free to start it now
+    // Returns None, dropping the job, if every worker is busy.
+    pub fn try_execute<F: FnOnce() + Send + 'static>(&self, f: F) -> Option<TaskHandle> {
+        if self.jobs.is_some() {
+            let size = self.size;
+            self.busy.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |busy| {
+                if busy < size { Some(busy + 1) } else { None }
+            }).ok()?;
+        }
+        let (done_tx, done_rx) = mpsc::channel();
+        let job = move|| {
+            f();
+            if done_tx.send(()).is_err() {
+                debug!("Nobody is waiting on the finished job");
+            }
+        };
+        match self.jobs {
+            Some(ref jobs) => {
+                if jobs.send(Box::new(job)).is_err() {
+                    error!("Thread pool has no workers left");
+                }
+            },
+            None => {
+                thread::spawn(job);
+            },
+        }
+        Some(TaskHandle { done: done_rx })
+    }
+}
@@ -0,0 +1,554 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
+use std::sync::mpsc;
+
+use super::stomp::Frame;
+use super::client::{handle_client, peer_name, BrokerSender, ClientEvent, ClientSender};
+use super::client::stream::Stream;
+use super::client::tls::{self, TlsStream};
+use super::client::websocket::WsStream;
+use super::config::Config;
+use super::broker::Broker;
+use super::metrics::{Metrics, MetricsSnapshot};
+use super::pool::{ThreadPool, TaskHandle};
+
+use rustls::ServerConfig;
+use socket2::{Domain, Protocol, Socket, Type};
+
+const DEFAULT_HOST: Ipv4Addr = Ipv4Addr::LOCALHOST;
+const SHUTDOWN_GRACE_MS: u64 = 1000;    // How long clients get to receive their last frames
+const SHUTDOWN_CHECK_MS: u64 = 100;     // How often an idle server checks the shutdown flag
+const ACCEPT_POLL_MS: u64 = 100;        // How often an idle listener checks the shutdown flag
+
+// Start a server with the given configuration and run it until SIGINT/SIGTERM
+pub fn run(config: Config) -> Result<(), String> {
+    let server = Server::bind(config)?;
+
+    // Stop cleanly on SIGINT/SIGTERM
+    let signal_flag = server.shutdown_flag();
+    if let Err(e) = ctrlc::set_handler(move || signal_flag.store(true, Ordering::SeqCst)) {
+        warn!("Failed to install signal handler: {}", e);
+    }
+
+    server.run();
+    Ok(())
+}
+
+// A client object containing the communication channel
+struct Client {
+    id: usize,
+    thread: TaskHandle,
+    tx: ClientSender,
+    stream: Box<dyn Stream>,    // Kept so the connection can be closed on shutdown
+}
+
+impl Client {
+    // Create a new client
+    pub fn new(id: usize, h: TaskHandle, t: ClientSender, stream: Box<dyn Stream>) -> Client {
+        Client {
+            id,
+            thread: h,
+            tx: t,
+            stream,
+        }
+    }
+}
+
+// A server whose listeners are bound but which isn't accepting clients yet
+pub struct Server {
+    config: Config,
+    listener: TcpListener,
+    ws_listener: Option<TcpListener>,
+    unix_listener: Option<UnixSocket>,
+    addr: SocketAddr,
+    ws_addr: Option<SocketAddr>,
+    tls: Option<Arc<ServerConfig>>,
+    shutdown: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+}
+
+impl Server {
+    // Bind all of the listeners the configuration asks for
+    pub fn bind(config: Config) -> Result<Server, String> {
+        let listener = bind_tcp(config.port, &config)
+            .map_err(|e| format!("Failed to bind to {}:{}: {}", DEFAULT_HOST, config.port, e))?;
+        // With port 0 the OS picks the port, so this is the only way to know it
+        let addr = listener.local_addr()
+            .map_err(|e| format!("Failed to get listening address: {}", e))?;
+        let ws_listener = match config.ws_port {
+            Some(port) => Some(bind_tcp(port, &config)
+                .map_err(|e| format!("Failed to bind to {}:{}: {}", DEFAULT_HOST, port, e))?),
+            None => None,
+        };
+        let unix_listener = match config.unix_socket {
+            Some(ref path) => Some(bind_unix(path)
+                .map_err(|e| format!("Failed to bind to {}: {}", path.display(), e))?),
+            None => None,
+        };
+        let ws_addr = match ws_listener {
+            Some(ref listener) => Some(listener.local_addr()
+                .map_err(|e| format!("Failed to get WebSocket listening address: {}", e))?),
+            None => None,
+        };
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+            _ => None,
+        };
+
+        Ok(Server {
+            config,
+            listener,
+            ws_listener,
+            unix_listener,
+            addr,
+            ws_addr,
+            tls,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            metrics: Arc::new(Metrics::new()),
+        })
+    }
+
+    // The address STOMP clients can connect to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // The address WebSocket clients can connect to, if there's a WebSocket listener
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_addr
+    }
+
+    // The server's counters, which keep updating while it runs
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    // A flag that stops the server once it's set
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    // Accept and serve clients until the shutdown flag is set
+    pub fn run(self) {
+        let config = self.config;
+        let shutdown = self.shutdown;
+
+        // Keep track of all our clients
+        let mut clients: Vec<Client> = Vec::new();
+
+        let metrics = self.metrics;
+        let mut broker = Broker::new(config.clone(), metrics.clone());
+        let metrics_interval = config.metrics_interval;
+
+        // Spin up threads for connection management
+        let (client_tx, client_rx) = mpsc::channel::<Client>();
+        let (event_tx, event_rx) = mpsc::channel::<ClientEvent>();
+        let acceptor = Acceptor {
+            tx: client_tx,
+            events: event_tx,
+            config: config.clone(),
+            shutdown: shutdown.clone(),
+            metrics: metrics.clone(),
+            pool: Arc::new(ThreadPool::new(config.worker_threads)),
+            next_id: Arc::new(AtomicUsize::new(0)),
+        };
+        let tls = self.tls;
+        let mut listen_threads = vec![listen_tcp(self.listener, tls.clone(), acceptor.clone())];
+        if let Some(listener) = self.ws_listener {
+            listen_threads.push(listen_ws(listener, tls, acceptor.clone()));
+        }
+        if let Some(listener) = self.unix_listener {
+            listen_threads.push(listen_unix(listener, acceptor.clone()));
+        }
+        info!("Started listener threads.");
+
+        let mut last_report = (Instant::now(), metrics.snapshot());
+
+        // Handle frames from clients
+        while !shutdown.load(Ordering::SeqCst) {
+            let mut wait = Duration::from_millis(SHUTDOWN_CHECK_MS);
+            if let Some(interval) = metrics_interval {
+                let elapsed = last_report.0.elapsed();
+                if elapsed >= interval {
+                    let snapshot = metrics.snapshot();
+                    log_metrics(&snapshot, &last_report.1, elapsed);
+                    last_report = (Instant::now(), snapshot);
+                } else {
+                    wait = wait.min(interval - elapsed);
+                }
+            }
+            if let Some(next_poll) = broker.next_poll() {
+                wait = wait.min(next_poll);
+            }
+
+            // Sleep until a client has something for us or there's work to do
+            let event = event_rx.recv_timeout(wait);
+
+            // The acceptor hands a client over after its thread has started, so a client's
+            // first frame can get here first; it's added to the broker before anything else
+            while let Ok(c) = client_rx.try_recv() {
+                broker.add_client(c.id, c.tx.clone());
+                clients.push(c);
+            }
+            match event {
+                Ok(ClientEvent::Frame(id, r)) => {
+                    if let Some(c) = wait_for_client(id, &mut clients, &client_rx, &mut broker) {
+                        info!("[client {}] Got request {:?}", c.tx.name(), r);
+                        broker.handle_frame(id, r);
+                    }
+                },
+                // Forget about clients whose threads have hung up
+                Ok(ClientEvent::Closed(id)) => {
+                    if wait_for_client(id, &mut clients, &client_rx, &mut broker).is_some() {
+                        broker.remove_client(id);
+                        clients.retain(|c| c.id != id);
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => { },
+            }
+
+            broker.poll();
+        }
+
+        info!("Shutting down.");
+        // The listeners see the flag the next time they check for connections
+        for t in listen_threads {
+            if t.join().is_err() {
+                error!("Listener thread panicked");
+            }
+        }
+        if let Some(ref path) = config.unix_socket {
+            remove_unix(path);
+        }
+        while let Ok(c) = client_rx.try_recv() {
+            broker.add_client(c.id, c.tx.clone());
+            clients.push(c);
+        }
+
+        // Tell everyone we're going away and give the writers a moment to deliver it
+        broker.drain();
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_GRACE_MS);
+        while Instant::now() < deadline && clients.iter().any(|c| !c.tx.is_closed()) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Anyone still connected (e.g. mid-handshake) is cut off so their threads can finish
+        for c in &clients {
+            if c.stream.shutdown().is_err() {
+                debug!("[client {}] Connection was already closed", c.tx.name());
+            }
+        }
+        for c in clients {
+            if c.thread.join().is_err() {
+                error!("[client {}] Thread panicked", c.tx.name());
+            }
+        }
+        info!("Shutdown complete.");
+    }
+}
+
+// Bind a TCP listener with the socket options from the configuration
+fn bind_tcp(port: u16, config: &Config) -> io::Result<TcpListener> {
+    let addr = SocketAddr::from((DEFAULT_HOST, port));
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // On Windows SO_REUSEADDR lets another process take the port from us, so it's left off
+    if cfg!(unix) {
+        socket.set_reuse_address(config.reuse_address)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(config.listen_backlog)?;
+    // Accepting is polled so the listener can stop when the server shuts down
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+// Connections accepted from a polled TCP listener
+// Some platforms hand out connections that are non-blocking like their listener, but clients
+// are served with blocking reads and writes.
+fn incoming_tcp(listener: &TcpListener) -> impl Iterator<Item = io::Result<TcpStream>> + '_ {
+    listener.incoming().map(|s| s.and_then(|s| s.set_nonblocking(false).map(|_| s)))
+}
+
+// Connections accepted from a polled TCP listener, each starting a TLS session
+fn incoming_tls(listener: &TcpListener, tls: Arc<ServerConfig>)
+        -> impl Iterator<Item = io::Result<TlsStream<TcpStream>>> + '_ {
+    incoming_tcp(listener).map(move |s| s.and_then(|s| TlsStream::new(s, tls.clone())))
+}
+
+// Find a client the main thread has heard from, waiting for the acceptor to hand it over if
+// it hasn't yet. Returns None if the acceptor has gone without doing so.
+fn wait_for_client<'a>(id: usize, clients: &'a mut Vec<Client>, client_rx: &Receiver<Client>,
+                       broker: &mut Broker) -> Option<&'a Client> {
+    while !clients.iter().any(|c| c.id == id) {
+        let c = client_rx.recv().ok()?;
+        broker.add_client(c.id, c.tx.clone());
+        clients.push(c);
+    }
+    clients.iter().find(|c| c.id == id)
+}
+
+// Log a one-line summary of the server's activity since the last report
+fn log_metrics(now: &MetricsSnapshot, then: &MetricsSnapshot, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let delivered = (now.messages_delivered - then.messages_delivered) as f64 / secs;
+    let processed = (now.frames_processed - then.frames_processed) as f64 / secs;
+    info!("{} connections, {:.1} frames/sec, {:.1} messages/sec, {} destinations, \
+           {} messages waiting in queues",
+          now.connections, processed, delivered, now.subscribers.len(), now.messages_waiting);
+}
+
+// State shared by the threads accepting connections
+#[derive(Clone)]
+struct Acceptor {
+    tx: Sender<Client>,             // Hands new clients to the main thread
+    events: Sender<ClientEvent>,    // Carries every client's frames to the main thread
+    config: Config,
+    shutdown: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    pool: Arc<ThreadPool>,
+    next_id: Arc<AtomicUsize>,
+}
+
+// Accept STOMP clients on a TCP port, over TLS if it's configured
+fn listen_tcp(listener: TcpListener, tls: Option<Arc<ServerConfig>>, acceptor: Acceptor)
+        -> JoinHandle<()> {
+    match (listener.local_addr(), &tls) {
+        (Ok(addr), Some(_)) => info!("Listening for TLS clients on {}", addr),
+        (Ok(addr), None) => info!("Listening on {}", addr),
+        (Err(e), _) => warn!("Listening on unknown address: {}", e),
+    }
+    thread::spawn(move || {
+        let wait = || wait_for_connection(&listener);
+        match tls {
+            Some(tls) => listen(incoming_tls(&listener, tls), wait, &acceptor),
+            None => listen(incoming_tcp(&listener), wait, &acceptor),
+        }
+    })
+}
+
+// Accept STOMP-over-WebSocket clients on a TCP port, over TLS if it's configured
+fn listen_ws(listener: TcpListener, tls: Option<Arc<ServerConfig>>, acceptor: Acceptor)
+        -> JoinHandle<()> {
+    match listener.local_addr() {
+        Ok(addr) => info!("Listening for WebSocket clients on {}", addr),
+        Err(e) => warn!("Listening for WebSocket clients on unknown address: {}", e),
+    }
+    thread::spawn(move || {
+        let wait = || wait_for_connection(&listener);
+        match tls {
+            Some(tls) => {
+                let incoming = incoming_tls(&listener, tls).map(|s| s.map(WsStream::new));
+                listen(incoming, wait, &acceptor);
+            },
+            None => listen(incoming_tcp(&listener).map(|s| s.map(WsStream::new)), wait, &acceptor),
+        }
+    })
+}
+
+// A listener bound to a Unix domain socket
+#[cfg(unix)]
+type UnixSocket = UnixListener;
+
+// Unix domain sockets can't be bound on this platform, so there's never a listener
+#[cfg(not(unix))]
+enum UnixSocket { }
+
+// Bind a listener to a Unix domain socket
+// A socket file left behind by a server that didn't shut down cleanly is removed first, but a
+// socket something is still listening on, or a file that isn't a socket, is left alone.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<UnixSocket> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      "File exists and isn't a socket"));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                      "Another server is listening on the socket"));
+        }
+        info!("Removing stale socket file {}", path.display());
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // Accepting is polled so the listener can stop when the server shuts down
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> io::Result<UnixSocket> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       "Unix domain sockets aren't supported on this platform"))
+}
+
+// Accept clients on a Unix domain socket
+#[cfg(unix)]
+fn listen_unix(listener: UnixSocket, acceptor: Acceptor) -> JoinHandle<()> {
+    match listener.local_addr() {
+        Ok(addr) => info!("Listening on {:?}", addr),
+        Err(e) => warn!("Listening on unknown Unix socket: {}", e),
+    }
+    thread::spawn(move || {
+        // Like TCP connections, Unix connections may come out non-blocking like their listener
+        let incoming = listener.incoming()
+            .map(|s| s.and_then(|s| s.set_nonblocking(false).map(|_| s)));
+        listen(incoming, || wait_for_connection(&listener), &acceptor);
+    })
+}
+
+#[cfg(not(unix))]
+fn listen_unix(listener: UnixSocket, _acceptor: Acceptor) -> JoinHandle<()> {
+    match listener { }
+}
+
+// Remove the Unix socket file once nothing is listening on it
+#[cfg(unix)]
+fn remove_unix(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to remove {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(not(unix))]
+fn remove_unix(_path: &Path) { }
+
+// Wait until a connection is ready to accept, or long enough that the shutdown flag is due to
+// be checked again
+#[cfg(unix)]
+fn wait_for_connection<L: AsRawFd>(listener: &L) {
+    let mut fds = libc::pollfd { fd: listener.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    // Waking up early, because of a signal or an error, only means checking the flag early
+    let ready = unsafe { libc::poll(&mut fds, 1, ACCEPT_POLL_MS as libc::c_int) };
+    if ready < 0 {
+        debug!("Failed to wait for connections: {}", io::Error::last_os_error());
+    }
+}
+
+// Without poll the listener sleeps between checks, so connections can wait a little
+#[cfg(not(unix))]
+fn wait_for_connection<L>(_listener: &L) {
+    thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+}
+
+// Turn a connection away with an ERROR before it's handed to a worker
+fn refuse<S: Stream>(stream: &mut S, error: &Frame) {
+    if stream.write_all(&error.to_bytes()[..]).is_err() {
+        debug!("[client {}] Failed to send refusal", peer_name(stream));
+    }
+    if stream.shutdown().is_err() {
+        debug!("[client {}] Connection was already closed", peer_name(stream));
+    }
+}
+
+// Counts a connection as open until it's dropped
+// Dropping it at the end of the client's job means even a client thread that panics gives its
+// place back, so max_connections isn't used up by connections that are already gone.
+struct OpenConnection {
+    metrics: Arc<Metrics>,
+}
+
+impl OpenConnection {
+    fn new(metrics: Arc<Metrics>) -> OpenConnection {
+        metrics.connection_opened();
+        OpenConnection { metrics }
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.metrics.connection_closed();
+    }
+}
+
+// Accept connections and hand them to the main thread until the server shuts down
+// `wait` is called whenever nobody is waiting to connect, and returns when someone might be.
+fn listen<S, I, W>(incoming: I, wait: W, acceptor: &Acceptor)
+        where S: Stream, I: Iterator<Item = io::Result<S>>, W: Fn() {
+    let config = &acceptor.config;
+    let metrics = &acceptor.metrics;
+    // Handle incoming connections
+    for stream in incoming {
+        if acceptor.shutdown.load(Ordering::SeqCst) {
+            info!("No longer accepting connections.");
+            break;
+        }
+        if let Err(ref e) = stream {
+            if e.kind() == ErrorKind::WouldBlock {
+                wait();
+                continue;
+            }
+        }
+        info!("Incoming stream.");
+        match stream {
+            Ok(mut stream) => {
+                info!("[client {}] Open stream", peer_name(&stream));
+                if config.max_connections.is_some_and(|max| metrics.connections() >= max) {
+                    warn!("[client {}] Too many connections; refusing", peer_name(&stream));
+                    let error = Frame::error("too many connections", "Too many connections.");
+                    refuse(&mut stream, &error);
+                    continue;
+                }
+                let mut handle = match stream.try_clone() {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("[client {}] Failed to clone stream: {}", peer_name(&stream), e);
+                        continue;
+                    },
+                };
+                let (client_tx, client_rx) = mpsc::channel::<Frame>();
+                let id = acceptor.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+                let events = acceptor.events.clone();
+                let session = id.to_string();
+                let name = format!("{}#{}", peer_name(&stream), session);
+                let client_tx = ClientSender::new(client_tx, &name);
+
+                let client_config = config.clone();
+                let out = client_tx.clone();
+                let open = OpenConnection::new(metrics.clone());
+                // A connection waiting for a worker would get no answer until another client
+                // left, so it's turned away instead. The broker only hears about the client
+                // once its job starts.
+                let job = acceptor.pool.try_execute(move|| {
+                    let _open = open;
+                    let server_tx = BrokerSender::new(id, events);
+                    handle_client(stream, &session, server_tx, client_rx, out, client_config);
+                });
+                let t = match job {
+                    Some(t) => t,
+                    None => {
+                        warn!("[client {}] No worker free; refusing", peer_name(&handle));
+                        refuse(&mut handle, &Frame::error("server busy", "Server is busy."));
+                        continue;
+                    },
+                };
+                let c = Client::new(id, t, client_tx, Box::new(handle));
+                // Send the client back to the main thread
+                acceptor.tx.send(c).unwrap();
+            }
+            Err(e) => {
+                error!("Error in incoming stream: {}", e);
+            }
+        }
+        info!("Done handling incoming stream.");
+    }
+}
@@ -4,16 +4,155 @@
  * Licensed under the GPLv3, see the LICENSE file for details
  */
 use std::char;
+use std::slice;
+use std::vec;
+use std::iter::FromIterator;
 use std::str;
-use std::fmt::{self, Display};
+use std::fmt;
+use std::collections::HashMap;
 
 pub mod parse;
+pub use self::parse::parse_frame;
 
-pub const PROTO_VERS: &'static str = "1.2";         // Supported protocol version
-pub const SERVER_STR: &'static str = "Romp/0.1";    // Server version string
+pub const SUPPORTED_VERSIONS: [StompVersion; 1] = [StompVersion::V1_2];   // Versions we can speak
+pub const SERVER_STR: &str = "Romp/0.1";    // Server version string
 
-// Possible STOMP commands
+// Header names are case-sensitive, except for these headers that the server reads itself,
+// which the parser lowercases so that e.g. Content-Length is treated as content-length
+pub const NORMALIZED_HEADERS: [&str; 6] = [
+    "accept-version",
+    "host",
+    "content-length",
+    "receipt",
+    "destination",
+    "id",
+];
+
+// Headers defined by the STOMP spec
+pub const RESERVED_HEADERS: [&str; 19] = [
+    "accept-version",
+    "ack",
+    "content-length",
+    "content-type",
+    "destination",
+    "heart-beat",
+    "host",
+    "id",
+    "login",
+    "message",
+    "message-id",
+    "passcode",
+    "receipt",
+    "receipt-id",
+    "server",
+    "session",
+    "subscription",
+    "transaction",
+    "version",
+];
+
+// Headers used by Romp's own extensions
+pub const ROMP_HEADERS: [&str; 7] = [
+    "romp-error-code",
+    "romp-exclusive",
+    "romp-redirect",
+    "romp-sync",
+    "redelivery-count",
+    "redelivered",
+    "prefetch-count",
+];
+
+// Determine whether a header has a meaning to STOMP or to Romp, rather than being one an
+// application made up
+pub fn is_reserved_header(key: &str) -> bool {
+    RESERVED_HEADERS.contains(&key) || ROMP_HEADERS.contains(&key)
+}
+
+// Protocol versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StompVersion {
+    V1_0,
+    V1_1,
+    V1_2,
+}
+
+impl StompVersion {
+    // Create a StompVersion from a string
+    pub fn from_string(string: &str) -> Option<StompVersion> {
+        match string {
+            "1.0" => Some(StompVersion::V1_0),
+            "1.1" => Some(StompVersion::V1_1),
+            "1.2" => Some(StompVersion::V1_2),
+            _ => None,
+        }
+    }
+
+    // Get the representation used in the accept-version and version headers
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            StompVersion::V1_0 => "1.0",
+            StompVersion::V1_1 => "1.1",
+            StompVersion::V1_2 => "1.2",
+        }
+    }
+}
+
+// Pick the highest version that both we and the client support from an accept-version header
+pub fn negotiate_version(accept_version: &str) -> Option<StompVersion> {
+    negotiate_version_from(accept_version, &SUPPORTED_VERSIONS)
+}
+
+// Pick the highest version in both an accept-version header and the given list
+pub fn negotiate_version_from(accept_version: &str, supported: &[StompVersion])
+        -> Option<StompVersion> {
+    accept_version.split(',')
+        .filter_map(|v| StompVersion::from_string(v.trim()))
+        .filter(|v| supported.contains(v))
+        .max()
+}
+
+// The versions we support as a version header value, e.g. for an ERROR when negotiation fails
+pub fn supported_versions() -> String {
+    SUPPORTED_VERSIONS.iter().map(|v| v.as_str()).collect::<Vec<&str>>().join(",")
+}
+
+// The newest version we speak, used until a client has negotiated one
+pub fn newest_version() -> StompVersion {
+    SUPPORTED_VERSIONS.iter().cloned().max().unwrap()
+}
+
+impl fmt::Display for StompVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// A frame that breaks the rules of the protocol
 #[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    MissingHeader(StompCommand, &'static str),
+    UnsupportedCommand(StompCommand, StompVersion),
+    BodyNotAllowed(StompCommand),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::MissingHeader(command, header) => {
+                write!(f, "{} is missing the required '{}' header.", command, header)
+            },
+            ProtocolError::UnsupportedCommand(command, version) => {
+                write!(f, "{} is not supported in STOMP {}.", command, version)
+            },
+            ProtocolError::BodyNotAllowed(command) => {
+                write!(f, "{} frames may not have a body.", command)
+            },
+        }
+    }
+}
+
+// Possible STOMP commands
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StompCommand {
     // Client commands
     Stomp,
@@ -31,6 +170,8 @@ pub enum StompCommand {
     Message,
     Receipt,
     Error,
+    // Application-specific command from the extension registry
+    Extension(&'static str),
 }
 
 impl StompCommand {
@@ -57,41 +198,120 @@ impl StompCommand {
         }
     }
 
-    // Create a string from a StompCommand
-    pub fn to_string(&self) -> &'static str {
+    // Get the wire representation of a StompCommand
+    pub fn as_str(&self) -> &'static str {
+        use self::StompCommand::*;
+        match *self {
+            Send => "SEND",
+            Subscribe => "SUBSCRIBE",
+            Unsubscribe => "UNSUBSCRIBE",
+            Begin => "BEGIN",
+            Commit => "COMMIT",
+            Abort => "ABORT",
+            Ack => "ACK",
+            Nack => "NACK",
+            Disconnect => "DISCONNECT",
+            Stomp => "STOMP",
+            Connected => "CONNECTED",
+            Message => "MESSAGE",
+            Receipt => "RECEIPT",
+            Error => "ERROR",
+            Extension(name) => name,
+        }
+    }
+
+    // Determine whether clients are allowed to send this command
+    pub fn is_client_command(&self) -> bool {
         use self::StompCommand::*;
-        match self {
-            &Send => "SEND",
-            &Subscribe => "SUBSCRIBE",
-            &Unsubscribe => "UNSUBSCRIBE",
-            &Begin => "BEGIN",
-            &Commit => "COMMIT",
-            &Abort => "ABORT",
-            &Ack => "ACK",
-            &Nack => "NACK",
-            &Disconnect => "DISCONNECT",
-            &Stomp => "STOMP",
-            &Connected => "CONNECTED",
-            &Message => "MESSAGE",
-            &Receipt => "RECEIPT",
-            &Error => "ERROR",
+        match *self {
+            Stomp | Send | Subscribe | Unsubscribe | Ack | Nack |
+            Begin | Commit | Abort | Disconnect | Extension(_) => true,
+            Connected | Message | Receipt | Error => false,
         }
     }
 
+    // Determine whether servers are allowed to send this command
+    pub fn is_server_command(&self) -> bool {
+        !self.is_client_command()
+    }
+
+    // Determine whether frames with this command may have a body
+    // Extension commands are up to the application, so they may
+    pub fn allows_body(&self) -> bool {
+        use self::StompCommand::*;
+        matches!(*self, Send | Message | Error | Extension(_))
+    }
+
+    // Determine whether header lines in frames with this command use escape sequences
+    // CONNECT and CONNECTED are left alone so that they can be read by clients of any version
+    pub fn escapes_headers(&self) -> bool {
+        use self::StompCommand::*;
+        !matches!(*self, Stomp | Connected)
+    }
+
     // Create a StompCommand from a slice of bytes
     pub fn from_bytes(bytes: &[u8]) -> Option<StompCommand> {
-        let string = match str::from_utf8(bytes) {
-            Ok(s) => s,
-            Err(_) => "INVALID",
-        };
+        let string = str::from_utf8(bytes).unwrap_or("INVALID");
         StompCommand::from_string(string)
     }
 }
 
+impl fmt::Display for StompCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Handler for an application-specific command; returns the frame to send back, if any
+pub type ExtensionHandler = fn(&Frame) -> Option<Frame>;
+
+// Application-specific commands accepted in addition to the STOMP ones
+// Empty by default, in which case unknown commands are errors as usual
+#[derive(Debug, Clone)]
+pub struct ExtensionRegistry {
+    handlers: HashMap<&'static str, ExtensionHandler>,
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> ExtensionRegistry {
+        ExtensionRegistry::new()
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> ExtensionRegistry {
+        ExtensionRegistry {
+            handlers: HashMap::new(),
+        }
+    }
+
+    // Add a command, e.g. register("PING", handle_ping)
+    pub fn register(&mut self, name: &'static str, handler: ExtensionHandler) {
+        self.handlers.insert(name, handler);
+    }
+
+    // Look up a registered command by name
+    pub fn command(&self, name: &[u8]) -> Option<StompCommand> {
+        let name = str::from_utf8(name).ok()?;
+        self.handlers.get_key_value(name).map(|(name, _)| StompCommand::Extension(name))
+    }
+
+    // Get the handler for a registered command
+    pub fn handler(&self, name: &str) -> Option<ExtensionHandler> {
+        self.handlers.get(name).cloned()
+    }
+}
+
 // Frame header
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct Header {
-    pub store: Vec<(String, String)>,
+    store: Vec<(String, String)>,
+}
+
+impl Default for Header {
+    fn default() -> Header {
+        Header::new()
+    }
 }
 
 impl Header {
@@ -101,20 +321,31 @@ impl Header {
         }
     }
     
-    // Write the header as a string
-    pub fn to_string(&self) -> String {
-        let mut string = String::new();
-        for pair in self.store.iter() {
-            string = format!("{}{}:{}\r\n", string, pair.0, pair.1);
-        }
-        string
-    }
-
     // Store a value
     pub fn set(&mut self, key: &str, value: &str) {
         self.store.push((String::from(key), String::from(value)));
     }
 
+    // Store a value only if the key isn't already present
+    // When a frame repeats a header, only the first occurrence is significant, so the parser
+    // uses this to drop the later values instead of storing (and re-sending) them.
+    pub fn set_if_absent(&mut self, key: &str, value: &str) {
+        if !self.contains_key(key) {
+            self.set(key, value);
+        }
+    }
+
+    // Store a value, replacing any existing values for the key
+    pub fn replace(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.set(key, value);
+    }
+
+    // Remove all values for a key
+    pub fn remove(&mut self, key: &str) {
+        self.store.retain(|pair| pair.0 != key);
+    }
+
     // Retrieve a value
     pub fn get(&self, key: &str) -> Option<&String> {
         for pair in self.store.iter() {
@@ -125,6 +356,30 @@ impl Header {
         None
     }
 
+    // Retrieve every value stored for a key, in the order they were set
+    pub fn get_all(&self, key: &str) -> Vec<&String> {
+        self.store.iter()
+            .filter(|pair| pair.0 == key)
+            .map(|pair| &pair.1)
+            .collect()
+    }
+
+    // Iterate over every (key, value) pair, in the order they were set
+    pub fn iter(&self) -> HeaderIter<'_> {
+        HeaderIter {
+            inner: self.store.iter(),
+        }
+    }
+
+    // Number of (key, value) pairs, counting repeated keys once per value
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
     // Determine whether the header contains the given key
     pub fn contains_key(&self, key: &str) -> bool {
         for pair in self.store.iter() {
@@ -136,12 +391,139 @@ impl Header {
     }
 }
 
+// Headers are equal if they hold the same k/v pairs in any order, except that the values
+// of a repeated key must be in the same order since the first one is significant
+impl PartialEq for Header {
+    fn eq(&self, other: &Header) -> bool {
+        if self.store.len() != other.store.len() {
+            return false;
+        }
+        let mut mine: Vec<&(String, String)> = self.store.iter().collect();
+        let mut theirs: Vec<&(String, String)> = other.store.iter().collect();
+        mine.sort_by(|a, b| a.0.cmp(&b.0));
+        theirs.sort_by(|a, b| a.0.cmp(&b.0));
+        mine == theirs
+    }
+}
+
+// Iterator over the (key, value) pairs in a header
+#[derive(Debug, Clone)]
+pub struct HeaderIter<'a> {
+    inner: slice::Iter<'a, (String, String)>,
+}
+
+impl<'a> Iterator for HeaderIter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.inner.next().map(|pair| (&pair.0[..], &pair.1[..]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for HeaderIter<'a> { }
+
+impl<'a> IntoIterator for &'a Header {
+    type Item = (&'a str, &'a str);
+    type IntoIter = HeaderIter<'a>;
+
+    fn into_iter(self) -> HeaderIter<'a> {
+        self.iter()
+    }
+}
+
+impl IntoIterator for Header {
+    type Item = (String, String);
+    type IntoIter = vec::IntoIter<(String, String)>;
+
+    fn into_iter(self) -> vec::IntoIter<(String, String)> {
+        self.store.into_iter()
+    }
+}
+
+// Build a header from (key, value) pairs; like set(), repeated keys keep every value
+impl FromIterator<(String, String)> for Header {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(pairs: I) -> Header {
+        Header {
+            store: pairs.into_iter().collect(),
+        }
+    }
+}
+
+impl From<Vec<(String, String)>> for Header {
+    fn from(pairs: Vec<(String, String)>) -> Header {
+        Header {
+            store: pairs,
+        }
+    }
+}
+
+// Passcodes are left out so that frames can be logged as they are
+impl fmt::Debug for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.store.iter().map(|(key, value)| {
+                (key, if key == "passcode" { "<redacted>" } else { &value[..] })
+            }))
+            .finish()
+    }
+}
+
+// Write the header in wire format, one CRLF-terminated line per k/v pair
+// Line breaks, colons and backslashes are escaped so that a value can't end its line early
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_lines(f, true)
+    }
+}
+
+impl Header {
+    // Write the header lines, with or without escape sequences
+    fn write_lines(&self, f: &mut fmt::Formatter, escaped: bool) -> fmt::Result {
+        for pair in self.store.iter() {
+            if escaped {
+                write_escaped(f, &pair.0)?;
+                f.write_str(":")?;
+                write_escaped(f, &pair.1)?;
+                f.write_str("\r\n")?;
+            } else {
+                write!(f, "{}:{}\r\n", pair.0, pair.1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Write a header key or value with the STOMP 1.2 escape sequences
+fn write_escaped(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    for c in text.chars() {
+        match c {
+            '\r' => f.write_str("\\r")?,
+            '\n' => f.write_str("\\n")?,
+            ':' => f.write_str("\\c")?,
+            '\\' => f.write_str("\\\\")?,
+            _ => write!(f, "{}", c)?,
+        }
+    }
+    Ok(())
+}
+
 // STOMP frame
-#[derive(Debug)]
+// Build frames with the constructors or Frame::builder, and read them through the accessors
+#[derive(Clone, PartialEq)]
 pub struct Frame {
-    pub command: StompCommand,
-    pub header: Header,
-    pub body: String,
+    command: StompCommand,
+    header: Header,
+    body: Vec<u8>,
+}
+
+impl Default for Frame {
+    fn default() -> Frame {
+        Frame::new()
+    }
 }
 
 impl Frame {
@@ -150,7 +532,7 @@ impl Frame {
         Frame {
             command: StompCommand::Error,
             header: Header::new(),
-            body: String::new(),
+            body: Vec::new(),
         }
     }
 
@@ -159,33 +541,217 @@ impl Frame {
         Frame {
             command: c,
             header: Header::new(),
-            body: String::new(),
+            body: Vec::new(),
         }
     }
 
     // Create a frame with the given command and body
-    // Automatically adds content-length header
-    pub fn with_body(c: StompCommand, b: &str) -> Frame {
+    // Automatically adds content-length header (and content-type for an ERROR)
+    pub fn with_body(c: StompCommand, b: &[u8]) -> Frame {
         let mut f = Frame {
             command: c,
             header: Header::new(),
-            body: String::from(b),
+            body: b.to_vec(),
         };
         f.header.set("content-length", &b.len().to_string()[..]);
+        f.describe_error();
         f
     }
 
-    // Represent a frame as a String
-    pub fn to_string(&self) -> String {
-        let c = self.command.to_string();
-        let h = self.header.to_string();
-        let nul = char::from_u32(0u32).unwrap();
+    // Mark the body of an ERROR as text, since some clients won't read it otherwise
+    fn describe_error(&mut self) {
+        if self.command == StompCommand::Error && !self.body.is_empty() {
+            self.header.set_if_absent("content-type", "text/plain");
+        }
+    }
 
-        format!("{}\r\n{}\r\n\r\n{}{}", c, h, self.body, nul)
+    pub fn command(&self) -> StompCommand {
+        self.command
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // Headers can be changed after a frame is built, e.g. to add the ack id to a MESSAGE
+    pub fn header_mut(&mut self) -> &mut Header {
+        &mut self.header
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    // Create an ERROR with a short summary in the message header and the details in the body
+    pub fn error(message: &str, detail: &str) -> Frame {
+        Frame::builder(StompCommand::Error)
+            .header("message", message)
+            .body(detail.as_bytes())
+            .build()
+    }
+
+    // Get the body length given by the content-length header (None if there isn't a valid one)
+    pub fn content_length(&self) -> Option<usize> {
+        self.header.get("content-length").and_then(|length| length.parse().ok())
+    }
+
+    // Get the actual length of the body in bytes
+    pub fn body_len(&self) -> usize {
+        self.body.len()
+    }
+
+    // Check that the frame has the headers its command requires in the given protocol version
+    // and only has a body if its command allows one
+    pub fn validate(&self, version: StompVersion) -> Result<(), ProtocolError> {
+        for header in required_headers(self.command, version)? {
+            if !self.header.contains_key(header) {
+                return Err(ProtocolError::MissingHeader(self.command, header));
+            }
+        }
+        if !self.body.is_empty() && !self.command.allows_body() {
+            return Err(ProtocolError::BodyNotAllowed(self.command));
+        }
+        Ok(())
+    }
+
+    // Start building a frame with the given command
+    pub fn builder(c: StompCommand) -> FrameBuilder {
+        FrameBuilder {
+            frame: Frame::from_command(c),
+            content_length: true,
+        }
     }
 
     // Represent a frame as a vec of bytes
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.to_string().into_bytes()
+        let mut bytes = FrameHead(self).to_string().into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes.push(0);
+        bytes
+    }
+}
+
+// Headers that a command must carry in a given protocol version
+fn required_headers(command: StompCommand, version: StompVersion)
+        -> Result<&'static [&'static str], ProtocolError> {
+    use self::StompCommand::*;
+    use self::StompVersion::*;
+    let required: &'static [&'static str] = match (command, version) {
+        (Stomp, V1_0) => &[],
+        (Stomp, _) => &["accept-version", "host"],
+        (Connected, V1_0) => &[],
+        (Connected, _) => &["version"],
+        (Send, _) => &["destination"],
+        (Subscribe, V1_0) => &["destination"],
+        (Subscribe, _) => &["destination", "id"],
+        (Unsubscribe, V1_0) => &[],
+        (Unsubscribe, _) => &["id"],
+        (Ack, V1_0) => &["message-id"],
+        (Ack, V1_1) | (Nack, V1_1) => &["message-id", "subscription"],
+        (Ack, V1_2) | (Nack, V1_2) => &["id"],
+        (Nack, V1_0) => return Err(ProtocolError::UnsupportedCommand(command, version)),
+        (Begin, _) | (Commit, _) | (Abort, _) => &["transaction"],
+        (Message, V1_0) => &["destination", "message-id"],
+        (Message, _) => &["destination", "message-id", "subscription"],
+        (Receipt, _) => &["receipt-id"],
+        (Disconnect, _) | (Error, _) | (Extension(_), _) => &[],
+    };
+    Ok(required)
+}
+
+// Fluent builder for frames
+// e.g. Frame::builder(StompCommand::Connected).header("version", "1.2").build()
+pub struct FrameBuilder {
+    frame: Frame,
+    content_length: bool,       // Whether build() adds a content-length header
+}
+
+impl FrameBuilder {
+    // Add a header
+    pub fn header(mut self, key: &str, value: &str) -> FrameBuilder {
+        self.frame.header.set(key, value);
+        self
+    }
+
+    // Set the body
+    pub fn body(mut self, body: &[u8]) -> FrameBuilder {
+        self.frame.body = body.to_vec();
+        self
+    }
+
+    // Leave the content-length header off so the body is only terminated by its NUL
+    // A body that contains a NUL can't be sent that way, so build() still adds the header then.
+    pub fn without_content_length(mut self) -> FrameBuilder {
+        self.content_length = false;
+        self
+    }
+
+    // Finish the frame, adding a content-length header if there's a body
+    pub fn build(mut self) -> Frame {
+        let needs_length = self.content_length || self.frame.body.contains(&0);
+        if needs_length && !self.frame.body.is_empty() {
+            let length = self.frame.body_len().to_string();
+            self.frame.header.replace("content-length", &length);
+        }
+        self.frame.describe_error();
+        self.frame
+    }
+}
+
+// Write the frame in wire format, including the terminating NUL
+// A body that isn't UTF-8 can't be written as text, so use to_bytes() to send frames
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nul = char::from_u32(0u32).unwrap();
+        write!(f, "{}{}{}", FrameHead(self), String::from_utf8_lossy(&self.body), nul)
+    }
+}
+
+// The command and header lines of a frame, up to and including the blank line before the body
+struct FrameHead<'a>(&'a Frame);
+
+impl<'a> fmt::Display for FrameHead<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\r\n", self.0.command)?;
+        self.0.header.write_lines(f, self.0.command.escapes_headers())?;
+        // Every header line ends with its own line break, so one more ends the header block
+        f.write_str("\r\n")
+    }
+}
+
+// Bodies are summarized so binary frames don't garble the logs
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("command", &self.command)
+            .field("header", &self.header)
+            .field("body", &BodySummary(&self.body))
+            .finish()
+    }
+}
+
+// Debug representation of a frame body
+// Printable text is shown as a string; anything else as its length and leading bytes in hex
+struct BodySummary<'a>(&'a [u8]);
+
+const BODY_SUMMARY_BYTES: usize = 16;      // Number of bytes of a binary body to log
+
+impl<'a> fmt::Debug for BodySummary<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match str::from_utf8(self.0) {
+            Ok(text) if text.chars().all(|c| !c.is_control() || c.is_whitespace()) => {
+                write!(f, "{:?}", text)
+            },
+            _ => {
+                write!(f, "<{} bytes:", self.0.len())?;
+                for b in self.0.iter().take(BODY_SUMMARY_BYTES) {
+                    write!(f, " {:02x}", b)?;
+                }
+                if self.0.len() > BODY_SUMMARY_BYTES {
+                    write!(f, " ...")?;
+                }
+                write!(f, ">")
+            },
+        }
     }
 }
@@ -4,52 +4,352 @@
  * Licensed under the GPLv3, see the LICENSE file for details
  */
 
-use std::net::TcpStream;
-use std::io::Read;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead, Cursor, Read, ErrorKind};
+use std::str::{self, FromStr};
+use std::num::{IntErrorKind, ParseIntError};
 
-use super::{Frame,StompCommand};
+use super::{Frame, StompCommand, ExtensionRegistry, NORMALIZED_HEADERS};
 
 const ESCAPE_CHAR: u8 = 92;                 // Backslash is the escape character
+const DEFAULT_MAX_HEADERS: usize = 1000;            // Default limit on header lines in a frame
+const DEFAULT_MAX_HEADER_LINE: usize = 64 * 1024;   // Default limit on bytes in a header line
 
-// Parse a stream into a Frame object
-pub fn parse_frame(stream: &TcpStream) -> Result<Frame, &'static str> {
+// Limits on the parts of a frame
+// These are checked as the frame is read, so a client can't make the parser buffer without
+// bound by sending endless headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseLimits {
+    pub max_headers: usize,
+    pub max_header_line: usize,     // Bytes in a header line, not counting the line break
+    pub max_body_size: Option<usize>,   // Bytes in a body (None for no limit)
+}
+
+impl Default for ParseLimits {
+    fn default() -> ParseLimits {
+        ParseLimits::new()
+    }
+}
+
+impl ParseLimits {
+    // Generous limits that no reasonable client will reach
+    pub fn new() -> ParseLimits {
+        ParseLimits {
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_header_line: DEFAULT_MAX_HEADER_LINE,
+            max_body_size: None,
+        }
+    }
+}
+
+// Ways that parsing a frame can fail
+// The Display text is what gets sent back to the client in an ERROR frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseError {
+    InvalidCommand,
+    MalformedHeader(&'static str),
+    BodyNotUtf8,
+    // A text body has characters its content-type's charset can't represent
+    BodyNotInCharset,
+    BodyNotAllowed,
+    // The frame's terminating NUL wasn't where its content-length said it would be
+    ContentLengthMismatch,
+    FrameTooLarge(&'static str),
+    // The read timeout fired after part of a frame had been received
+    ReadTimeout,
+    // The read timeout fired before any of a frame had been received; the connection is
+    // just idle, so this isn't necessarily a problem
+    IdleTimeout,
+    // The stream ended between frames; the client has gone away
+    Disconnected,
+    // Reading from the stream failed for any other reason
+    Io(ErrorKind),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::InvalidCommand => write!(f, "Invalid command"),
+            ParseError::MalformedHeader(message) => write!(f, "{}", message),
+            ParseError::BodyNotUtf8 => write!(f, "Error decoding body."),
+            ParseError::BodyNotInCharset => write!(f, "Body does not match its charset."),
+            ParseError::BodyNotAllowed => write!(f, "This type of frame may not have a body."),
+            ParseError::ContentLengthMismatch => write!(f, "content-length does not match body"),
+            ParseError::FrameTooLarge(message) => write!(f, "{}", message),
+            ParseError::ReadTimeout => write!(f, "read timeout while parsing frame"),
+            ParseError::IdleTimeout => write!(f, "read timeout while waiting for frame"),
+            ParseError::Disconnected => write!(f, "stream ended"),
+            ParseError::Io(kind) => write!(f, "error reading from stream: {:?}", kind),
+        }
+    }
+}
+
+// Parse a buffered stream into a Frame object
+pub fn parse_frame<R: BufRead>(reader: &mut R) -> Result<Frame, ParseError> {
+    parse_frame_with_extensions(reader, &ExtensionRegistry::new(), &ParseLimits::new())
+}
+
+// Parse a frame that's already in memory, e.g. Frame::try_from(&bytes[..])
+// Anything after the frame's terminating NUL is ignored
+impl<'a> TryFrom<&'a [u8]> for Frame {
+    type Error = ParseError;
+
+    fn try_from(bytes: &'a [u8]) -> Result<Frame, ParseError> {
+        parse_frame(&mut Cursor::new(bytes))
+    }
+}
+
+impl FromStr for Frame {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Frame, ParseError> {
+        Frame::try_from(s.as_bytes())
+    }
+}
+
+// Parse a buffered stream into a Frame object, accepting registered extension commands
+// The reader is consumed one byte at a time, so all of the parser state (partial buffers,
+// colon and escape flags) carries over no matter where the underlying reads are split.
+// Lines may end in LF or CRLF; carriage returns are dropped from the command and headers, so
+// both give the same frame. The body is taken as-is.
+pub fn parse_frame_with_extensions<R: BufRead>(reader: &mut R, extensions: &ExtensionRegistry,
+                                               limits: &ParseLimits)
+        -> Result<Frame, ParseError> {
+    let mut frame = parse_head(reader, extensions, limits)?;
+
+    let content_length = match frame.header.get("content-length") {
+        Some(length) => Some(parse_content_length(length)?),
+        None => None,
+    };
+
+    // Try to parse the body
+    let too_large = ParseError::FrameTooLarge("body exceeds maximum size");
+    let mut body_buf: Vec<u8> = Vec::new();
+
+    // With a content-length, the body is exactly that many bytes and may contain NULs
+    if let Some(length) = content_length {
+        // A body that's too big is refused before any of it is read
+        if limits.max_body_size.is_some_and(|max| length > max) {
+            return Err(too_large);
+        }
+        match reader.by_ref().take(length as u64).read_to_end(&mut body_buf) {
+            Ok(n) if n < length => {
+                return Err(ParseError::Io(ErrorKind::UnexpectedEof));
+            },
+            Ok(_) => { },
+            Err(ref e) if is_timeout(e) => {
+                return Err(ParseError::ReadTimeout);
+            },
+            Err(e) => {
+                return Err(ParseError::Io(e.kind()));
+            },
+        }
+        // The NUL has to come right after the body
+        match reader.by_ref().bytes().next() {
+            Some(Ok(0)) => { },
+            Some(Ok(_)) => {
+                return Err(ParseError::ContentLengthMismatch);
+            },
+            Some(Err(ref e)) if is_timeout(e) => {
+                return Err(ParseError::ReadTimeout);
+            },
+            Some(Err(e)) => {
+                return Err(ParseError::Io(e.kind()));
+            },
+            None => {
+                return Err(ParseError::Io(ErrorKind::UnexpectedEof));
+            },
+        }
+    } else {
+        // Without a content-length, the body ends at the first NUL
+        for byte in reader.by_ref().bytes() {
+            match byte {
+                // Body ends on NUL
+                Ok(0) => {
+                    break;
+                },
+                Ok(_) if limits.max_body_size.is_some_and(|max| body_buf.len() >= max) => {
+                    return Err(too_large);
+                },
+                Ok(b) => {
+                    body_buf.push(b);
+                },
+                Err(ref e) if is_timeout(e) => {
+                    return Err(ParseError::ReadTimeout);
+                },
+                Err(e) => {
+                    return Err(ParseError::Io(e.kind()));
+                }
+            }
+        }
+    }
+    frame.body = body_buf;
+
+    // Only certain kinds of frames are allowed to have a body
+    if !frame.body.is_empty() && !frame.command.allows_body() {
+        return Err(ParseError::BodyNotAllowed);
+    }
+
+    if let Some(content_type) = frame.header.get("content-type") {
+        check_charset(content_type, &frame.body)?;
+    }
+
+    // Frame is parsed and valid
+    Ok(frame)
+}
+
+// Parse a frame's command and headers, and return a reader for its body instead of reading it
+// This is for bodies too large to hold in memory. The body is read straight off the stream,
+// so there are some constraints:
+// - The frame must have a content-length; without one the end of the body can't be found
+//   without reading all of it.
+// - The body isn't checked against its charset, since it's never all in one place.
+// - The body has to be read to the end before the next frame is parsed from the stream. The
+//   reader checks for the terminating NUL once the last byte of the body has been read.
+// This is for programs using the parser directly. The server doesn't stream bodies to
+// subscribers: the broker hands whole frames between threads and a message can go to several
+// subscribers, so bodies are buffered and held to --max-body-size instead.
+pub fn parse_frame_streaming<'a, R: BufRead>(reader: &'a mut R, extensions: &ExtensionRegistry,
+                                             limits: &ParseLimits)
+        -> Result<(Frame, BodyReader<'a, R>), ParseError> {
+    let frame = parse_head(reader, extensions, limits)?;
+    let length = match frame.header.get("content-length") {
+        Some(length) => parse_content_length(length)?,
+        None => return Err(ParseError::MalformedHeader("Streaming a body needs a content-length.")),
+    };
+    if limits.max_body_size.is_some_and(|max| length > max) {
+        return Err(ParseError::FrameTooLarge("body exceeds maximum size"));
+    }
+    if length > 0 && !frame.command.allows_body() {
+        return Err(ParseError::BodyNotAllowed);
+    }
+    let body = BodyReader {
+        reader,
+        remaining: length,
+        finished: false,
+    };
+    Ok((frame, body))
+}
+
+// Reads the body of a frame parsed by parse_frame_streaming
+// Reading fails with InvalidData if the NUL isn't where the content-length said it would be, and
+// with UnexpectedEof if the stream ends inside the body.
+#[derive(Debug)]
+pub struct BodyReader<'a, R: 'a> {
+    reader: &'a mut R,
+    remaining: usize,       // Bytes of the body not read yet
+    finished: bool,         // The terminating NUL has been read
+}
+
+impl<'a, R: BufRead> BodyReader<'a, R> {
+    // Bytes of the body that haven't been read yet
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    // Read past the end of the body, checking that the frame ends where it should
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let mut nul = [0];
+        self.reader.read_exact(&mut nul)?;
+        if nul[0] != 0 {
+            return Err(io::Error::new(ErrorKind::InvalidData,
+                                      ParseError::ContentLengthMismatch.to_string()));
+        }
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<'a, R: BufRead> Read for BodyReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            self.finish()?;
+            return Ok(0);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let max = buf.len().min(self.remaining);
+        let n = self.reader.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "stream ended inside a body"));
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+// Read just the command line of a frame, leaving the headers and body on the stream
+// This is quicker than a full parse when only the command matters, e.g. for routing or a quick
+// check of what a client sent. Leading line breaks (heart-beats) are skipped and errors are the
+// same as parse_frame's, since parse_frame reads the command the same way.
+pub fn parse_command_only<R: BufRead>(reader: &mut R) -> Result<StompCommand, ParseError> {
+    parse_command(reader, &ExtensionRegistry::new(), &ParseLimits::new())
+}
+
+// Read the command line of a frame, accepting registered extension commands
+// The command line is held to the same length limit as a header line.
+pub fn parse_command<R: BufRead>(reader: &mut R, extensions: &ExtensionRegistry,
+                                 limits: &ParseLimits)
+        -> Result<StompCommand, ParseError> {
     let mut cmd_buf: Vec<u8> = Vec::new();
     // The STOMP spec says to ignore trailing line breaks, but it's easier to ignore leading ones
     // Shouldn't make a difference though.
 
-    let c_stream = stream.try_clone().unwrap();
-
     // Try to parse the command
-    for b in (&c_stream).bytes() {
+    let mut eof = true;
+    for b in reader.by_ref().bytes() {
         // Add the byte to the command buffer
         match b {
             Ok(10) => {
-                // Command ends on \n
-                if cmd_buf.len() > 0 {
+                // Command ends on \n; an EOL before any command bytes is a heart-beat
+                if !cmd_buf.is_empty() {
+                    eof = false;
                     break;
                 }
             },
             Ok(13) => { },
+            Ok(_) if cmd_buf.len() >= limits.max_header_line => {
+                return Err(ParseError::FrameTooLarge("command line too long"));
+            },
             Ok(b) => {
                 cmd_buf.push(b);
             },
-            Err(_) => {
-                break;
+            Err(ref e) if is_timeout(e) && cmd_buf.is_empty() => {
+                return Err(ParseError::IdleTimeout);
+            },
+            Err(ref e) if is_timeout(e) => {
+                return Err(ParseError::ReadTimeout);
+            },
+            Err(e) => {
+                return Err(ParseError::Io(e.kind()));
             },
         }
     }
+    // The stream ended before the command did, so the client went away rather than sending
+    // something invalid
+    if eof && cmd_buf.is_empty() {
+        return Err(ParseError::Disconnected);
+    }
+    if eof {
+        return Err(ParseError::Io(ErrorKind::UnexpectedEof));
+    }
+
     // Parse the command
-    let command = StompCommand::from_bytes(&cmd_buf[..]);
+    StompCommand::from_bytes(&cmd_buf[..])
+        .or_else(|| extensions.command(&cmd_buf[..]))
+        .ok_or(ParseError::InvalidCommand)
+}
 
+// Parse a frame's command and headers, leaving the body on the stream
+fn parse_head<R: BufRead>(reader: &mut R, extensions: &ExtensionRegistry, limits: &ParseLimits)
+        -> Result<Frame, ParseError> {
     let mut frame = Frame::new();
-    match command {
-        Some(c) => {
-            frame.command = c;
-        },
-        None => {
-            return Err("Invalid command");
-        },
-    }
+    frame.command = parse_command(reader, extensions, limits)?;
 
     // Try to parse the header
     let mut eol_seen = 1;
@@ -57,10 +357,33 @@ pub fn parse_frame(stream: &TcpStream) -> Result<Frame, &'static str> {
     let mut value_buf: Vec<u8> = Vec::new();
     let mut found_colon = false;
     let mut escape = false;
+    // CONNECT and CONNECTED headers are taken literally, the same way they're written
+    let escaped = frame.command.escapes_headers();
+    let mut line_len = 0;
+    let mut header_count = 0;
 
-    for byte in (&c_stream).bytes() {
-        // Write the k/v pair on line break
+    for byte in reader.by_ref().bytes() {
+        if let Ok(b) = byte {
+            if b != 10 && b != 13 {
+                line_len += 1;
+                if line_len > limits.max_header_line {
+                    return Err(ParseError::FrameTooLarge("header line too long"));
+                }
+            }
+        }
         match byte {
+            // Handle escape sequence -- returns an error immediately if it's invalid
+            Ok(byte) if escape => {
+                eol_seen = 0;
+                let byte = unescape(byte)?;
+                if found_colon {
+                    value_buf.push(byte);
+                } else {
+                    key_buf.push(byte);
+                }
+                escape = false;
+            },
+            // Write the k/v pair on line break
             Ok(10) => {
                 eol_seen += 1;
                 // Once we hit two line breaks, the headers are over
@@ -68,115 +391,150 @@ pub fn parse_frame(stream: &TcpStream) -> Result<Frame, &'static str> {
                     break;
                 }
 
-                if key_buf.len() > 0 {
+                if !key_buf.is_empty() {
                     // Malformed k/v pair
                     if !found_colon {
-                        return Err("Failed to parse header.");
+                        return Err(ParseError::MalformedHeader("Failed to parse header."));
+                    }
+                    header_count += 1;
+                    if header_count > limits.max_headers {
+                        return Err(ParseError::FrameTooLarge("too many headers"));
                     }
 
-                    let key = String::from_utf8(key_buf).unwrap();
-                    let value = String::from_utf8(value_buf).unwrap();
-                    frame.header.set(&key, &value);
+                    // Header lines have to be UTF-8; anything else is the client's mistake,
+                    // so it gets an ERROR rather than taking the connection down with it
+                    let not_utf8 = ParseError::MalformedHeader("Header is not valid UTF-8.");
+                    let key = String::from_utf8(key_buf).map_err(|_| not_utf8)?;
+                    let value = String::from_utf8(value_buf).map_err(|_| not_utf8)?;
+                    let key = normalize_key(key);
+                    // Repeated headers are ignored; the first value wins
+                    frame.header.set_if_absent(&key, &value);
                 }
                 key_buf = Vec::new();
                 value_buf = Vec::new();
                 found_colon = false;
+                line_len = 0;
             },
             // Ignore \r
             Ok(13) => { },
-            // Colon separates key from value
-            Ok(58) => {
+            // The first colon separates key from value
+            Ok(58) if !found_colon => {
+                eol_seen = 0;
                 found_colon = true;
             },
             // Start escape sequence
-            Ok(ESCAPE_CHAR) => {
+            Ok(ESCAPE_CHAR) if escaped => {
+                eol_seen = 0;
                 escape = true;
             },
             // Add the byte to the correct buffer
             Ok(byte) => {
                 eol_seen = 0;
-                // Handle escape sequence -- returns an error immediately if it's invalid
-                if escape {
-                    match unescape(byte) {
-                        Ok(byte) => {
-                            if found_colon {
-                                value_buf.push(byte);
-                            } else {
-                                key_buf.push(byte);
-                            }
-                        },
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    };
-                    escape = false;
+                if found_colon {
+                    value_buf.push(byte);
                 } else {
-                    if found_colon {
-                        value_buf.push(byte);
-                    } else {
-                        key_buf.push(byte);
-                    }
+                    key_buf.push(byte);
                 }
             },
-            Err(_) => {
-                break;
+            Err(ref e) if is_timeout(e) => {
+                return Err(ParseError::ReadTimeout);
+            },
+            Err(e) => {
+                return Err(ParseError::Io(e.kind()));
             },
         }
     }
-    // If there weren't two line breaks after the header, the frame is malformed
+    // The header only ends early on a blank line, so without one the stream ended
     if eol_seen != 2 {
-        return Err("Missing line breaks after header.");
+        return Err(ParseError::Io(ErrorKind::UnexpectedEof));
     }
 
-    // Try to parse the body
-    // TODO: Implement content-length header
-    let mut body_buf: Vec<u8> = Vec::new();
+    Ok(frame)
+}
 
-    for byte in (&c_stream).bytes() {
-        match byte {
-            // Body ends on NUL
-            Ok(0) => {
-                break;
-            },
-            Ok(b) => {
-                body_buf.push(b);
-            },
-            Err(_) => {
-                break;
-            }
+// Parse the value of a content-length header
+pub fn parse_content_length(value: &str) -> Result<usize, ParseError> {
+    value.parse::<usize>().map_err(|e| match *e.kind() {
+        IntErrorKind::PosOverflow => ParseError::FrameTooLarge("Content-length is too large."),
+        _ => ParseError::MalformedHeader("Invalid content-length."),
+    })
+}
+
+// Get the charset parameter of a content-type header, lowercased (None if there isn't one)
+// e.g. "text/plain;charset=UTF-8" has the charset "utf-8"
+pub fn parse_charset(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("charset") {
+            Some(value.trim().trim_matches('"').to_lowercase())
+        } else {
+            None
         }
+    })
+}
+
+// Check that a text body is encoded in the charset its content-type names
+// Text is UTF-8 unless the content-type says otherwise, and US-ASCII is checked as well; any
+// other charset is taken on trust. Bodies that aren't text may hold any bytes at all.
+fn check_charset(content_type: &str, body: &[u8]) -> Result<(), ParseError> {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if !media_type.to_lowercase().starts_with("text/") {
+        return Ok(());
     }
-    let content = String::from_utf8(body_buf);
-    match content {
-        Ok(c) => {
-            frame.body = c;
+    match parse_charset(content_type).as_ref().map(|c| &c[..]) {
+        None | Some("utf-8") if str::from_utf8(body).is_err() => Err(ParseError::BodyNotUtf8),
+        Some("us-ascii") | Some("ascii") if !body.is_ascii() => Err(ParseError::BodyNotInCharset),
+        _ => Ok(()),
+    }
+}
+
+// Parse the value of a heart-beat header into its two intervals in milliseconds
+pub fn parse_heart_beat(value: &str) -> Result<(u64, u64), &'static str> {
+    let too_large = "Heart-beat interval is too large.";
+    let invalid = "Invalid heart-beat.";
+    let mut parts = value.split(',');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(x), Some(y), None) => {
+            let x = x.parse::<u64>().map_err(|e| number_error(&e, too_large, invalid))?;
+            let y = y.parse::<u64>().map_err(|e| number_error(&e, too_large, invalid))?;
+            Ok((x, y))
         },
-        Err(_) => {
-            return Err("Error decoding body.");
-        }
+        _ => Err(invalid),
     }
+}
 
-    // Only certain kinds of frames are allowed to have a body
-    if frame.body.len() > 0 {
-        if frame.command != StompCommand::Send && 
-           frame.command != StompCommand::Message &&
-           frame.command != StompCommand::Error {
-            return Err("This type of frame may not have a body.");
-        }
+// Pick the error message for a number that failed to parse
+// Overflow gets its own message since the value is otherwise well-formed
+fn number_error(e: &ParseIntError, too_large: &'static str, invalid: &'static str) -> &'static str {
+    match *e.kind() {
+        IntErrorKind::PosOverflow => too_large,
+        _ => invalid,
     }
+}
 
-    // Frame is parsed and valid
-    Ok(frame)
+// Lowercase the name of a header the server reads; leave any other name alone
+fn normalize_key(key: String) -> String {
+    let lower = key.to_lowercase();
+    if NORMALIZED_HEADERS.contains(&&lower[..]) {
+        lower
+    } else {
+        key
+    }
 }
 
 // Unescape a byte
-fn unescape(b: u8) -> Result<u8, &'static str> {
+fn unescape(b: u8) -> Result<u8, ParseError> {
     // Carriage return
     match b {
         114 => Ok(13),
         110 => Ok(10),
         99 => Ok(58),
         92 => Ok(92),
-        _ => Err("Invalid escape sequence")
+        _ => Err(ParseError::MalformedHeader("Invalid escape sequence"))
     }
 }
+
+// Determine whether an IO error was caused by a read timeout
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
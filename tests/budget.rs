@@ -0,0 +1,56 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use common::{TestClient, TestServer};
+
+// Start a server with a budget of 4, and a client that has 2 messages it hasn't acknowledged
+fn client_with_unacked_messages() -> (TestServer, TestClient, Vec<String>) {
+    let server = TestServer::start_with_args(&["--connection-budget", "4"]);
+    let mut client = server.subscriber("/queue/budget", &[("ack", "client-individual")]);
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/budget")], "1");
+    sender.send("SEND", &[("destination", "/queue/budget")], "2");
+    let acks = (0..2).map(|_| {
+        let message = client.recv();
+        assert_eq!(message.command, "MESSAGE");
+        message.header("ack").unwrap().to_string()
+    }).collect();
+    (server, client, acks)
+}
+
+#[test]
+fn acks_and_transactions_share_the_budget() {
+    let (_server, mut client, _) = client_with_unacked_messages();
+
+    // The transaction and its first frame fit alongside the two messages...
+    client.send("BEGIN", &[("transaction", "t"), ("receipt", "begun")], "");
+    assert_eq!(client.recv().header("receipt-id"), Some("begun"));
+    client.send("SEND", &[("destination", "/queue/other"), ("transaction", "t")], "1");
+
+    // ...but a second frame doesn't
+    client.send("SEND", &[("destination", "/queue/other"), ("transaction", "t")], "2");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("romp-error-code"), Some("BUDGET_TRANSACTIONS"));
+    assert_eq!(error.body, b"Connection budget used up by transactions.");
+}
+
+#[test]
+fn acknowledging_frees_up_budget() {
+    let (_server, mut client, acks) = client_with_unacked_messages();
+    for ack in &acks {
+        client.send("ACK", &[("id", ack)], "");
+    }
+
+    client.send("BEGIN", &[("transaction", "t")], "");
+    for body in &["1", "2", "3"] {
+        client.send("SEND", &[("destination", "/queue/other"), ("transaction", "t")], body);
+    }
+    client.send("COMMIT", &[("transaction", "t"), ("receipt", "done")], "");
+    assert_eq!(client.recv().header("receipt-id"), Some("done"));
+}
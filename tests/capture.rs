@@ -0,0 +1,80 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use romp::client::capture::{Capture, Direction};
+
+use common::{wait_until, TestServer};
+
+// A capture directory no other test (or test run) will use
+fn capture_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("romp-{}-{}-capture", process::id(), name));
+    // Clear out anything left over from an earlier run
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    dir
+}
+
+// The contents of every capture file in a directory
+fn captures(dir: &Path) -> Vec<String> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.map(|entry| entry.unwrap().path()).collect(),
+        Err(_) => return Vec::new(),
+    };
+    files.sort();
+    files.iter()
+        .map(|file| String::from_utf8_lossy(&fs::read(file).unwrap()).into_owned())
+        .collect()
+}
+
+#[test]
+fn handshake_is_recorded_for_each_connection() {
+    let dir = capture_dir("handshake");
+    let server = TestServer::start_with_args(&["--capture", dir.to_str().unwrap()]);
+
+    for _ in 0..2 {
+        let mut client = server.login();
+        client.send("DISCONNECT", &[("receipt", "bye")], "");
+        assert_eq!(client.recv().command, "RECEIPT");
+    }
+
+    // Both connections come from the same address but get a file each
+    assert!(wait_until(|| {
+        let files = captures(&dir);
+        files.len() == 2 && files.iter().all(|f| f.contains("RECEIPT\r\nreceipt-id:bye\r\n"))
+    }), "{:?}", captures(&dir));
+    for capture in captures(&dir) {
+        assert!(capture.starts_with("> "), "{}", capture);
+        assert!(capture.contains("\nCONNECT\naccept-version:1.2\nhost:localhost\n\n\0"),
+                "{}", capture);
+        assert!(capture.contains("\n< "), "{}", capture);
+        assert!(capture.contains("\nCONNECTED\r\nversion:1.2\r\n"), "{}", capture);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn capture_files_are_never_shared() {
+    let dir = capture_dir("names");
+    let first = Capture::create(&dir, "127.0.0.1:5000#1").unwrap();
+    let second = Capture::create(&dir, "127.0.0.1:5000#1").unwrap();
+    first.record(Direction::Received, b"first");
+    second.record(Direction::Sent, b"second");
+
+    let files = captures(&dir);
+    assert_eq!(files.len(), 2);
+    assert!(files.contains(&"> 5\nfirst\n".to_string()), "{:?}", files);
+    assert!(files.contains(&"< 6\nsecond\n".to_string()), "{:?}", files);
+    fs::remove_dir_all(&dir).unwrap();
+}
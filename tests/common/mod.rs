@@ -0,0 +1,251 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+// Helpers for driving a real server over TCP, or a broker directly, from the integration tests
+#![allow(dead_code)]
+
+extern crate romp;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use self::romp::broker::Broker;
+use self::romp::client::ClientSender;
+use self::romp::config::Config;
+use self::romp::metrics::Metrics;
+use self::romp::server::Server;
+use self::romp::stomp::Frame;
+
+// How long a test waits on the server before giving up
+const TIMEOUT_SECS: u64 = 5;
+
+// A server running on an ephemeral port, shut down when dropped
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub ws_addr: Option<SocketAddr>,
+    pub metrics: Arc<Metrics>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        TestServer::start_with_args(&[])
+    }
+
+    // Start the server with options given as they would be on the command line
+    pub fn start_with_args(args: &[&str]) -> TestServer {
+        let mut config = Config::from_args(args.iter().map(|a| a.to_string()))
+            .expect("Bad server arguments");
+        config.port = 0;
+        TestServer::start_with_config(config)
+    }
+
+    pub fn start_with_config(config: Config) -> TestServer {
+        let server = Server::bind(config).expect("Failed to start server");
+        let addr = server.local_addr();
+        let ws_addr = server.ws_addr();
+        let metrics = server.metrics();
+        let shutdown = server.shutdown_flag();
+        let thread = thread::spawn(move || server.run());
+        TestServer {
+            addr,
+            ws_addr,
+            metrics,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    // Signal shutdown and wait for the server to stop, returning false if it takes too long
+    pub fn stop(&mut self) -> bool {
+        self.shutdown.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return true,
+        };
+        while !thread.is_finished() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread.join().is_ok()
+    }
+
+    // Open a raw TCP connection to the server
+    pub fn connect(&self) -> TestClient {
+        let stream = TcpStream::connect(self.addr).expect("Failed to connect");
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS))).unwrap();
+        TestClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream,
+        }
+    }
+
+    // Open a connection and get through CONNECT/CONNECTED
+    pub fn login(&self) -> TestClient {
+        let mut client = self.connect();
+        client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], "");
+        let frame = client.recv();
+        assert_eq!(frame.command, "CONNECTED", "{:?}", frame);
+        client
+    }
+
+    // Open a connection and subscribe it to a destination, with any extra SUBSCRIBE headers
+    // (e.g. an ack mode)
+    pub fn subscriber(&self, destination: &str, extra: &[(&str, &str)]) -> TestClient {
+        let mut client = self.login();
+        client.subscribe(destination, extra);
+        client
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Wait for a condition that the server brings about in its own time, returning false if it
+// doesn't happen before the timeout
+pub fn wait_until<F: Fn() -> bool>(condition: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+// A frame read back from the server
+#[derive(Debug)]
+pub struct TestFrame {
+    pub command: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestFrame {
+    // Get the first value of a header
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.iter().find(|&(k, _)| k == key).map(|(_, v)| &v[..])
+    }
+}
+
+// One client connection to a test server
+pub struct TestClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    // Write a frame to the server
+    pub fn send(&mut self, command: &str, headers: &[(&str, &str)], body: &str) {
+        let mut frame = format!("{}\n", command);
+        for &(key, value) in headers {
+            frame.push_str(&format!("{}:{}\n", key, value));
+        }
+        frame.push('\n');
+        frame.push_str(body);
+        frame.push('\0');
+        self.stream.write_all(frame.as_bytes()).unwrap();
+    }
+
+    // Send a SUBSCRIBE with id 0 and any extra headers, returning the reply: a RECEIPT once the
+    // broker has the subscription, or an ERROR if it was refused
+    pub fn try_subscribe(&mut self, destination: &str, extra: &[(&str, &str)]) -> TestFrame {
+        let mut headers = vec![("id", "0"), ("destination", destination), ("receipt", "sub")];
+        headers.extend_from_slice(extra);
+        self.send("SUBSCRIBE", &headers, "");
+        self.recv()
+    }
+
+    // Subscribe and wait until the broker has the subscription
+    pub fn subscribe(&mut self, destination: &str, extra: &[(&str, &str)]) {
+        let reply = self.try_subscribe(destination, extra);
+        assert_eq!(reply.command, "RECEIPT", "{:?}", reply);
+    }
+
+    // Write bytes to the server as they are, for frames send can't express
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    // Whether anything arrives from the server within the given time, without reading it
+    pub fn has_data(&mut self, wait: Duration) -> bool {
+        self.stream.set_read_timeout(Some(wait)).unwrap();
+        let available = match self.reader.fill_buf() {
+            Ok(buf) => !buf.is_empty(),
+            Err(_) => false,
+        };
+        self.stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS))).unwrap();
+        available
+    }
+
+    // Read the next frame, skipping heart-beats
+    pub fn recv(&mut self) -> TestFrame {
+        let mut line = String::new();
+        while line.trim_end().is_empty() {
+            line.clear();
+            if self.reader.read_line(&mut line).expect("Timed out waiting for a frame") == 0 {
+                panic!("Connection closed while waiting for a frame");
+            }
+        }
+        let command = line.trim_end().to_owned();
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            self.reader.read_line(&mut line).unwrap();
+            let header = line.trim_end_matches(['\n', '\r']);
+            if header.is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            let key = parts.next().unwrap().to_owned();
+            let value = parts.next().unwrap_or("").to_owned();
+            headers.push((key, value));
+        }
+
+        let mut body = Vec::new();
+        let length = headers.iter().find(|&(k, _)| k == "content-length")
+            .map(|(_, v)| v.parse::<usize>().unwrap());
+        match length {
+            Some(length) => {
+                body.resize(length, 0);
+                self.reader.read_exact(&mut body).unwrap();
+                let mut nul = [0];
+                self.reader.read_exact(&mut nul).unwrap();
+            },
+            None => {
+                self.reader.read_until(0, &mut body).unwrap();
+                body.pop();
+            },
+        }
+
+        TestFrame {
+            command,
+            headers,
+            body,
+        }
+    }
+}
+
+// Add a client to a broker driven directly by a test, returning what the broker sends it
+pub fn add_client(broker: &mut Broker, client: usize) -> Receiver<Frame> {
+    let (tx, rx) = mpsc::channel();
+    broker.add_client(client, ClientSender::new(tx, &format!("client#{}", client)));
+    rx
+}
@@ -0,0 +1,174 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use romp::config::Config;
+use romp::stomp::parse::ParseLimits;
+
+// Parse options given as they would be on the command line
+fn parse_args(args: &[&str]) -> Result<Config, String> {
+    Config::from_args(args.iter().map(|a| a.to_string()))
+}
+
+#[test]
+fn parse_limits_are_set_by_flags() {
+    let defaults = ParseLimits::new();
+    let config = parse_args(&[]).unwrap();
+    assert_eq!(config.parse_limits, defaults);
+
+    let config = parse_args(&["--max-headers", "10", "--max-header-line", "256",
+                              "--max-body-size", "1024"]).unwrap();
+    assert_eq!(config.parse_limits.max_headers, 10);
+    assert_eq!(config.parse_limits.max_header_line, 256);
+    assert_eq!(config.parse_limits.max_body_size, Some(1024));
+
+    // Zero turns a limit off rather than refusing every frame
+    let config = parse_args(&["--max-headers", "0", "--max-header-line", "0",
+                              "--max-body-size", "0"]).unwrap();
+    assert_eq!(config.parse_limits.max_headers, usize::MAX);
+    assert_eq!(config.parse_limits.max_header_line, usize::MAX);
+    assert_eq!(config.parse_limits.max_body_size, None);
+
+    assert!(parse_args(&["--max-headers", "lots"]).is_err());
+    assert!(parse_args(&["--max-header-line"]).is_err());
+}
+
+#[test]
+fn allowed_hosts_are_set_by_flags() {
+    assert_eq!(parse_args(&[]).unwrap().allowed_hosts, None);
+
+    let config = parse_args(&["--allowed-host", "example.com", "--allowed-host", "localhost"])
+        .unwrap();
+    assert_eq!(config.allowed_hosts,
+               Some(vec!["example.com".to_string(), "localhost".to_string()]));
+
+    assert!(parse_args(&["--allowed-host"]).is_err());
+}
+
+#[test]
+fn max_subscribers_is_set_by_flag() {
+    assert_eq!(parse_args(&[]).unwrap().max_subscribers_per_destination, None);
+    let config = parse_args(&["--max-subscribers", "3"]).unwrap();
+    assert_eq!(config.max_subscribers_per_destination, Some(3));
+    let config = parse_args(&["--max-subscribers", "0"]).unwrap();
+    assert_eq!(config.max_subscribers_per_destination, None);
+    assert!(parse_args(&["--max-subscribers", "-1"]).is_err());
+    assert!(parse_args(&["--max-subscribers"]).is_err());
+}
+
+#[test]
+fn max_connection_memory_is_set_by_flag() {
+    assert_eq!(parse_args(&[]).unwrap().max_connection_memory, None);
+    let config = parse_args(&["--max-connection-memory", "65536"]).unwrap();
+    assert_eq!(config.max_connection_memory, Some(65536));
+    assert_eq!(parse_args(&["--max-connection-memory", "0"]).unwrap().max_connection_memory, None);
+    assert!(parse_args(&["--max-connection-memory", "64k"]).is_err());
+}
+
+#[test]
+fn connection_budget_is_set_by_flag() {
+    assert_eq!(parse_args(&[]).unwrap().max_connection_budget, None);
+    let config = parse_args(&["--connection-budget", "100"]).unwrap();
+    assert_eq!(config.max_connection_budget, Some(100));
+    assert_eq!(parse_args(&["--connection-budget", "0"]).unwrap().max_connection_budget, None);
+    assert!(parse_args(&["--connection-budget", "none"]).is_err());
+}
+
+#[test]
+fn default_prefetch_is_set_by_flag() {
+    assert_eq!(parse_args(&[]).unwrap().default_prefetch, None);
+    assert_eq!(parse_args(&["--prefetch", "10"]).unwrap().default_prefetch, Some(10));
+    assert_eq!(parse_args(&["--prefetch", "0"]).unwrap().default_prefetch, None);
+    assert!(parse_args(&["--prefetch", "many"]).is_err());
+}
+
+#[test]
+fn connection_options_are_set_by_flags() {
+    let config = parse_args(&[]).unwrap();
+    assert_eq!(config.max_connections, None);
+    assert_eq!(config.worker_threads, None);
+    assert!(!config.detailed_connect_errors);
+    assert!(!config.error_on_read_timeout);
+    assert_eq!(config.redirect, None);
+
+    let config = parse_args(&["--max-connections", "50", "--workers", "4",
+                              "--detailed-connect-errors", "--error-on-read-timeout",
+                              "--redirect", "backup:61616"]).unwrap();
+    assert_eq!(config.max_connections, Some(50));
+    assert_eq!(config.worker_threads, Some(4));
+    assert!(config.detailed_connect_errors);
+    assert!(config.error_on_read_timeout);
+    assert_eq!(config.redirect, Some("backup:61616".to_string()));
+
+    let config = parse_args(&["--max-connections", "0", "--workers", "0"]).unwrap();
+    assert_eq!(config.max_connections, None);
+    assert_eq!(config.worker_threads, None);
+    assert!(parse_args(&["--workers", "some"]).is_err());
+    assert!(parse_args(&["--redirect"]).is_err());
+}
+
+#[test]
+fn destination_options_are_set_by_flags() {
+    let config = parse_args(&[]).unwrap();
+    assert!(!config.case_insensitive_destinations);
+    assert_eq!(config.max_queue_depth, Some(1000));
+    assert_eq!(config.max_redeliveries, Some(5));
+
+    let config = parse_args(&["--case-insensitive", "--max-queue-depth", "10",
+                              "--max-redeliveries", "0"]).unwrap();
+    assert!(config.case_insensitive_destinations);
+    assert_eq!(config.max_queue_depth, Some(10));
+    assert_eq!(config.max_redeliveries, Some(0));
+
+    assert_eq!(parse_args(&["--max-queue-depth", "0"]).unwrap().max_queue_depth, None);
+    assert!(parse_args(&["--max-redeliveries", "-1"]).is_err());
+}
+
+#[test]
+fn tls_needs_both_certificate_and_key() {
+    let config = parse_args(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"]).unwrap();
+    assert_eq!(config.tls_cert, Some(PathBuf::from("cert.pem")));
+    assert_eq!(config.tls_key, Some(PathBuf::from("key.pem")));
+    assert_eq!(parse_args(&[]).unwrap().tls_cert, None);
+    assert!(parse_args(&["--tls-cert", "cert.pem"]).is_err());
+    assert!(parse_args(&["--tls-key", "key.pem"]).is_err());
+}
+
+#[test]
+fn ack_timeout_is_set_by_flag() {
+    assert_eq!(parse_args(&[]).unwrap().ack_timeout, None);
+    let config = parse_args(&["--ack-timeout", "30"]).unwrap();
+    assert_eq!(config.ack_timeout, Some(Duration::from_secs(30)));
+    assert_eq!(parse_args(&["--ack-timeout", "0"]).unwrap().ack_timeout, None);
+}
+
+#[test]
+fn metrics_interval_is_set_by_flag() {
+    assert_eq!(parse_args(&[]).unwrap().metrics_interval, Some(Duration::from_secs(60)));
+    let config = parse_args(&["--metrics-interval", "5"]).unwrap();
+    assert_eq!(config.metrics_interval, Some(Duration::from_secs(5)));
+    assert_eq!(parse_args(&["--metrics-interval", "0"]).unwrap().metrics_interval, None);
+    assert!(parse_args(&["--metrics-interval", "1m"]).is_err());
+}
+
+#[test]
+fn slow_consumer_limits_are_set_by_flags() {
+    let config = parse_args(&[]).unwrap();
+    assert_eq!(config.max_in_flight, None);
+    assert_eq!(config.max_backlog, Some(1000));
+
+    let config = parse_args(&["--max-in-flight", "8", "--max-backlog", "16"]).unwrap();
+    assert_eq!(config.max_in_flight, Some(8));
+    assert_eq!(config.max_backlog, Some(16));
+
+    let config = parse_args(&["--max-in-flight", "0", "--max-backlog", "0"]).unwrap();
+    assert_eq!(config.max_in_flight, None);
+    assert_eq!(config.max_backlog, None);
+    assert!(parse_args(&["--max-backlog", "lots"]).is_err());
+}
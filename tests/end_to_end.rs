@@ -0,0 +1,371 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::time::Duration;
+
+use romp::config::Config;
+use romp::stomp::{Frame, StompCommand, SERVER_STR};
+
+use common::{wait_until, TestServer};
+
+#[test]
+fn message_reaches_subscriber() {
+    let server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/test"),
+                                   ("receipt", "sub")], "");
+    let receipt = subscriber.recv();
+    assert_eq!(receipt.command, "RECEIPT");
+    assert_eq!(receipt.header("receipt-id"), Some("sub"));
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/test")], "hello");
+
+    let message = subscriber.recv();
+    assert_eq!(message.command, "MESSAGE");
+    assert_eq!(message.header("destination"), Some("/queue/test"));
+    assert_eq!(message.header("subscription"), Some("0"));
+    assert_eq!(message.body, b"hello");
+}
+
+#[test]
+fn acknowledged_message_is_no_longer_pending() {
+    let mut server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/acked"),
+                                   ("ack", "client"), ("receipt", "sub")], "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/acked")], "hello");
+
+    let message = subscriber.recv();
+    assert_eq!(message.body, b"hello");
+    let ack = message.header("ack").expect("MESSAGE has no ack header");
+    assert!(wait_until(|| server.metrics.snapshot().messages_unacked == 1));
+
+    subscriber.send("ACK", &[("id", ack), ("receipt", "acked")], "");
+    assert_eq!(subscriber.recv().header("receipt-id"), Some("acked"));
+    let snapshot = server.metrics.snapshot();
+    assert_eq!(snapshot.messages_unacked, 0);
+    assert_eq!(snapshot.messages_waiting, 0);
+
+    assert!(server.stop(), "Server didn't stop after shutdown was signaled");
+}
+
+#[test]
+fn client_cannot_forge_reserved_headers() {
+    let server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/test"),
+                                   ("receipt", "sub")], "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/test"), ("message-id", "forged"),
+                          ("subscription", "forged"), ("session", "forged"),
+                          ("redelivery-count", "7"), ("x-order", "42")], "hello");
+
+    let message = subscriber.recv();
+    assert_eq!(message.command, "MESSAGE");
+    // Reserved headers are the broker's, each appearing once with its own value
+    for key in &["message-id", "subscription"] {
+        let values: Vec<_> = message.headers.iter().filter(|&(k, _)| k == key).collect();
+        assert_eq!(values.len(), 1, "{:?}", message.headers);
+        assert_ne!(values[0].1, "forged");
+    }
+    assert_eq!(message.header("session"), None);
+    assert_eq!(message.header("redelivery-count"), None);
+    // The application's own headers go through
+    assert_eq!(message.header("x-order"), Some("42"));
+}
+
+#[test]
+fn header_line_breaks_stay_escaped() {
+    let server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/escaped"),
+                                   ("receipt", "sub")], "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    // The value has a line break in it, which must not split the MESSAGE's header line
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/escaped"), ("foo", "a\\nb")], "hello");
+
+    let message = subscriber.recv();
+    assert_eq!(message.command, "MESSAGE");
+    assert_eq!(message.header("foo"), Some("a\\nb"));
+    assert_eq!(message.header("b"), None);
+    assert_eq!(message.body, b"hello");
+    assert!(!subscriber.has_data(Duration::from_millis(200)), "Got more than one frame");
+}
+
+#[test]
+fn binary_body_reaches_subscriber() {
+    let server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/binary"),
+                                   ("receipt", "sub")], "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    let mut sender = server.login();
+    sender.send_raw(b"SEND\ndestination:/queue/binary\ncontent-length:4\n\n\xff\0\xfe\x80\0");
+
+    let message = subscriber.recv();
+    assert_eq!(message.command, "MESSAGE");
+    assert_eq!(message.body, b"\xff\0\xfe\x80");
+}
+
+#[test]
+fn connected_reports_negotiated_version() {
+    let server = TestServer::start();
+
+    // The highest version both sides speak is chosen, wherever the client lists it
+    for accept in &["1.0,1.1,1.2", "1.2,1.0", "1.1, 1.2, 2.0"] {
+        let mut client = server.connect();
+        client.send("CONNECT", &[("accept-version", accept), ("host", "localhost")], "");
+        let connected = client.recv();
+        assert_eq!(connected.command, "CONNECTED", "{}", accept);
+        assert_eq!(connected.header("version"), Some("1.2"), "{}", accept);
+    }
+}
+
+#[test]
+fn version_mismatch_lists_supported_versions() {
+    let server = TestServer::start();
+
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "0.9,1.0"), ("host", "localhost")], "");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("version"), Some("1.2"));
+}
+
+#[test]
+fn error_body_is_described() {
+    let server = TestServer::start();
+
+    let mut client = server.login();
+    client.send("SEND", &[], "no destination");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("content-type"), Some("text/plain"));
+    assert_eq!(error.header("content-length"), Some(&error.body.len().to_string()[..]));
+    assert!(!error.body.is_empty());
+}
+
+#[test]
+fn error_summarizes_in_message_header() {
+    let server = TestServer::start();
+
+    let mut client = server.login();
+    client.send("SEND", &[], "no destination");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("message"), Some("malformed frame"));
+    assert_eq!(error.body, b"SEND is missing the required 'destination' header.");
+}
+
+#[test]
+fn non_utf8_header_gets_an_error() {
+    let server = TestServer::start();
+
+    let mut client = server.login();
+    client.send_raw(b"SEND\ndestination:/queue/\xff\xfe\n\nhello\0");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.body, b"Header is not valid UTF-8.");
+
+    // The connection is closed properly and no longer counts against max_connections
+    drop(client);
+    assert!(wait_until(|| server.metrics.connections() == 0), "Connection was never released");
+}
+
+#[test]
+fn server_commands_from_a_client_are_rejected() {
+    let server = TestServer::start();
+
+    let frames: [(&str, &[(&str, &str)]); 3] = [
+        ("MESSAGE", &[("destination", "/queue/a"), ("message-id", "1"), ("subscription", "0")]),
+        ("RECEIPT", &[("receipt-id", "1")]),
+        ("CONNECTED", &[("version", "1.2")]),
+    ];
+    for &(command, headers) in frames.iter() {
+        let mut client = server.login();
+        client.send(command, headers, "");
+        let error = client.recv();
+        assert_eq!(error.command, "ERROR", "{}", command);
+        assert_eq!(error.header("message"), Some("unexpected command"));
+        assert_eq!(error.body, format!("Clients may not send {} frames.", command).as_bytes());
+    }
+}
+
+// Answer a PING with a PONG carrying the same body
+fn pong(ping: &Frame) -> Option<Frame> {
+    Some(Frame::builder(StompCommand::Extension("PONG")).body(ping.body()).build())
+}
+
+#[test]
+fn registered_command_is_answered_by_its_handler() {
+    let mut config = Config::new();
+    config.port = 0;
+    config.extensions.register("PING", pong);
+    let server = TestServer::start_with_config(config);
+
+    let mut client = server.login();
+    client.send("PING", &[], "are you there?");
+    let response = client.recv();
+    assert_eq!(response.command, "PONG");
+    assert_eq!(response.body, b"are you there?");
+}
+
+#[test]
+fn unregistered_command_is_invalid() {
+    let server = TestServer::start();
+
+    let mut client = server.login();
+    client.send("PING", &[], "are you there?");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.body, b"Invalid command");
+}
+
+#[test]
+fn destinations_differing_in_case_are_the_same_when_case_insensitive() {
+    let server = TestServer::start_with_args(&["--case-insensitive"]);
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/Topic/A"), ("receipt", "sub")],
+                    "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/topic/a")], "hello");
+    let message = subscriber.recv();
+    assert_eq!(message.command, "MESSAGE");
+    assert_eq!(message.body, b"hello");
+}
+
+#[test]
+fn destinations_are_case_sensitive_by_default() {
+    let server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/Topic/A"), ("receipt", "sub")],
+                    "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/topic/a"), ("receipt", "sent")], "hello");
+    assert_eq!(sender.recv().command, "RECEIPT");
+    assert!(!subscriber.has_data(Duration::from_millis(200)));
+}
+
+#[test]
+fn every_connection_gets_its_own_session() {
+    let server = TestServer::start();
+
+    let mut sessions = Vec::new();
+    for _ in 0..5 {
+        let mut client = server.connect();
+        client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], "");
+        let connected = client.recv();
+        assert_eq!(connected.command, "CONNECTED");
+        let session = connected.header("session").expect("CONNECTED has no session").to_owned();
+        assert!(!session.is_empty());
+        assert!(!sessions.contains(&session), "{} was given out twice", session);
+        sessions.push(session);
+    }
+}
+
+#[test]
+fn content_type_is_relayed_as_sent() {
+    let server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/typed"),
+                                   ("receipt", "sub")], "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/typed"),
+                          ("content-type", "text/plain; charset=UTF-8")], "caf\u{e9}");
+    let message = subscriber.recv();
+    assert_eq!(message.header("content-type"), Some("text/plain; charset=UTF-8"));
+    assert_eq!(message.body, "caf\u{e9}".as_bytes());
+
+    // A body that isn't in its charset is refused
+    sender.send_raw(b"SEND\ndestination:/queue/typed\ncontent-type:text/plain;charset=us-ascii\n\n\
+                      caf\xc3\xa9\0");
+    let error = sender.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.body, b"Body does not match its charset.");
+}
+
+#[test]
+fn invalid_first_frame_gets_an_error_and_is_closed() {
+    let server = TestServer::start();
+
+    let mut client = server.connect();
+    client.send_raw(b"HELLO\nhost:localhost\n\n\0");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("message"), Some("malformed frame"));
+    assert_eq!(error.body, b"Invalid command");
+
+    // The server hangs up rather than waiting for anything more
+    assert!(!client.has_data(Duration::from_secs(1)));
+    assert!(wait_until(|| server.metrics.connections() == 0), "Connection was never released");
+}
+
+#[test]
+fn connected_names_the_server() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], "");
+    assert_eq!(client.recv().header("server"), Some(SERVER_STR));
+
+    let server = TestServer::start_with_args(&["--server-name", "Broker/2.0"]);
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], "");
+    assert_eq!(client.recv().header("server"), Some("Broker/2.0"));
+
+    // An empty name leaves the header out altogether
+    let server = TestServer::start_with_args(&["--server-name", ""]);
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], "");
+    let connected = client.recv();
+    assert_eq!(connected.command, "CONNECTED");
+    assert_eq!(connected.header("server"), None);
+}
+
+#[test]
+fn connect_with_receipt_gets_a_receipt_after_connected() {
+    let server = TestServer::start();
+
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost"),
+                             ("receipt", "hello")], "");
+    assert_eq!(client.recv().command, "CONNECTED");
+    let receipt = client.recv();
+    assert_eq!(receipt.command, "RECEIPT");
+    assert_eq!(receipt.header("receipt-id"), Some("hello"));
+
+    // A refused connection only gets the ERROR
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "0.9"), ("host", "localhost"),
+                             ("receipt", "hello")], "");
+    assert_eq!(client.recv().command, "ERROR");
+    assert!(!client.has_data(Duration::from_millis(200)));
+}
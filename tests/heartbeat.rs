@@ -0,0 +1,141 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use romp::client::heartbeat::{HeartBeatClock, ReceiveClock};
+use romp::stomp::parse::parse_heart_beat;
+
+use common::TestServer;
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+#[test]
+fn heart_beat_is_due_after_a_quiet_interval() {
+    let start = Instant::now();
+    let clock = HeartBeatClock::new(Some(ms(100)), start);
+    assert_eq!(clock.until_due(start), Some(ms(100)));
+    assert_eq!(clock.until_due(start + ms(40)), Some(ms(60)));
+    assert_eq!(clock.until_due(start + ms(100)), Some(ms(0)));
+    // Running late doesn't make the wait negative
+    assert_eq!(clock.until_due(start + ms(250)), Some(ms(0)));
+}
+
+#[test]
+fn writing_a_frame_puts_off_the_heart_beat() {
+    let start = Instant::now();
+    let mut clock = HeartBeatClock::new(Some(ms(100)), start);
+    clock.wrote(start + ms(90));
+    assert_eq!(clock.until_due(start + ms(100)), Some(ms(90)));
+    assert_eq!(clock.until_due(start + ms(190)), Some(ms(0)));
+}
+
+#[test]
+fn heart_beat_is_never_due_when_off() {
+    let start = Instant::now();
+    let clock = HeartBeatClock::new(None, start);
+    assert_eq!(clock.until_due(start + ms(1_000_000)), None);
+}
+
+#[test]
+fn client_expires_after_twice_the_interval() {
+    let start = Instant::now();
+    let clock = ReceiveClock::new(Some(ms(100)), start);
+    assert!(!clock.expired(start + ms(150)));
+    assert!(!clock.expired(start + ms(200)));
+    assert!(clock.expired(start + ms(201)));
+}
+
+#[test]
+fn reading_from_client_puts_off_the_deadline() {
+    let start = Instant::now();
+    let mut clock = ReceiveClock::new(Some(ms(100)), start);
+    clock.read(start + ms(150));
+    assert!(!clock.expired(start + ms(300)));
+    assert!(clock.expired(start + ms(351)));
+    // An older read doesn't move the deadline back
+    clock.read(start + ms(10));
+    assert!(!clock.expired(start + ms(350)));
+}
+
+#[test]
+fn client_never_expires_when_off() {
+    let start = Instant::now();
+    let clock = ReceiveClock::new(None, start);
+    assert!(!clock.expired(start + ms(1_000_000)));
+}
+
+#[test]
+fn huge_heart_beat_is_too_large() {
+    assert_eq!(parse_heart_beat("1000,18446744073709551615"), Ok((1000, u64::MAX)));
+    assert_eq!(parse_heart_beat("1000,18446744073709551616"),
+               Err("Heart-beat interval is too large."));
+    assert_eq!(parse_heart_beat("99999999999999999999999999999999999999,0"),
+               Err("Heart-beat interval is too large."));
+    assert_eq!(parse_heart_beat("-1,0"), Err("Invalid heart-beat."));
+}
+
+// Connect asking to send heart-beats every 100ms
+fn connect_with_heart_beats(server: &TestServer) -> common::TestClient {
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost"),
+                             ("heart-beat", "100,0")], "");
+    let connected = client.recv();
+    assert_eq!(connected.command, "CONNECTED");
+    assert_eq!(connected.header("heart-beat"), Some("100,100"));
+    client
+}
+
+#[test]
+fn silent_client_is_disconnected() {
+    let server = TestServer::start_with_args(&["--heart-beat", "100"]);
+    let mut client = connect_with_heart_beats(&server);
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("message"), Some("heart-beat timeout"));
+}
+
+#[test]
+fn client_sending_heart_beats_stays_connected() {
+    let server = TestServer::start_with_args(&["--heart-beat", "100"]);
+    let mut client = connect_with_heart_beats(&server);
+    for _ in 0..6 {
+        thread::sleep(ms(80));
+        client.send_raw(b"\n");
+    }
+    client.send("BEGIN", &[("transaction", "t"), ("receipt", "alive")], "");
+    assert_eq!(client.recv().header("receipt-id"), Some("alive"));
+}
+
+#[test]
+fn largest_heart_beat_is_accepted() {
+    let server = TestServer::start_with_args(&["--heart-beat", "100"]);
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost"),
+                             ("heart-beat", "18446744073709551615,18446744073709551615")], "");
+    assert_eq!(client.recv().command, "CONNECTED");
+    // The connection carries on as normal
+    client.send("BEGIN", &[("transaction", "t"), ("receipt", "alive")], "");
+    assert_eq!(client.recv().header("receipt-id"), Some("alive"));
+}
+
+#[test]
+fn heart_beat_too_large_for_a_number_is_refused() {
+    let server = TestServer::start_with_args(&["--heart-beat", "100"]);
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost"),
+                             ("heart-beat", "1234567890123456789012345678901234567890,1")], "");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("message"), Some("invalid heart-beat"));
+    assert_eq!(error.body, b"Heart-beat interval is too large.");
+}
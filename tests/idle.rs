@@ -0,0 +1,44 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use common::TestServer;
+
+// CPU time used by this process so far, in clock ticks
+// Only Linux makes this easy to get without extra dependencies.
+#[cfg(target_os = "linux")]
+fn cpu_ticks() -> u64 {
+    let stat = fs::read_to_string("/proc/self/stat").unwrap();
+    // The command name can contain spaces, so fields are counted from the end of it
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+    let utime: u64 = fields[11].parse().unwrap();
+    let stime: u64 = fields[12].parse().unwrap();
+    utime + stime
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn idle_server_sleeps() {
+    let server = TestServer::start();
+    let mut client = server.login();
+    client.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/idle"), ("receipt", "1")], "");
+    assert_eq!(client.recv().command, "RECEIPT");
+
+    // A server spinning on its clients would use a whole core; one waiting for them uses
+    // next to nothing
+    let before = cpu_ticks();
+    thread::sleep(Duration::from_secs(1));
+    let used = cpu_ticks() - before;
+    assert!(used < 20, "Idle server used {} ticks of CPU in a second", used);
+
+    // It still wakes up for the client
+    client.send("SEND", &[("destination", "/queue/idle")], "hello");
+    assert_eq!(client.recv().body, b"hello");
+}
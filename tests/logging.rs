@@ -0,0 +1,54 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+#[macro_use]
+extern crate log;
+extern crate romp;
+
+mod common;
+
+use std::io::{self, ErrorKind, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::LogLevelFilter;
+
+use romp::logger::SimpleLogger;
+
+use common::TestServer;
+
+// A log consumer that has gone away, counting the writes that failed because of it
+struct Gone {
+    attempts: Arc<AtomicUsize>,
+}
+
+impl Write for Gone {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        Err(io::Error::from(ErrorKind::BrokenPipe))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Err(io::Error::from(ErrorKind::BrokenPipe))
+    }
+}
+
+// There's only one logger per process, so everything is checked in the one test
+#[test]
+fn logging_to_a_broken_writer_does_not_panic() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    SimpleLogger::init(LogLevelFilter::Info, Gone { attempts: attempts.clone() }).unwrap();
+
+    error!("Nobody will read this");
+    debug!("Nor this, which is below the level");
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    // The server logs as it goes, and carries on regardless
+    let server = TestServer::start();
+    let mut client = server.login();
+    client.send("BEGIN", &[("transaction", "t"), ("receipt", "alive")], "");
+    assert_eq!(client.recv().header("receipt-id"), Some("alive"));
+    assert!(attempts.load(Ordering::SeqCst) > 1);
+}
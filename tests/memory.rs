@@ -0,0 +1,80 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use common::{wait_until, TestClient, TestFrame, TestServer};
+
+const BODY_SIZE: usize = 1000;
+
+// Read frames until the server gives up on the client, returning the ERROR
+fn recv_error(client: &mut TestClient) -> TestFrame {
+    loop {
+        let frame = client.recv();
+        if frame.command == "ERROR" {
+            return frame;
+        }
+    }
+}
+
+#[test]
+fn unacknowledged_messages_count_toward_memory_limit() {
+    let server = TestServer::start_with_args(&["--max-connection-memory", "4096"]);
+    let mut subscriber = server.subscriber("/queue/memory", &[("ack", "client-individual")]);
+
+    let mut sender = server.login();
+    let body = "x".repeat(BODY_SIZE);
+    for _ in 0..10 {
+        sender.send("SEND", &[("destination", "/queue/memory")], &body);
+    }
+    let error = recv_error(&mut subscriber);
+    assert_eq!(error.header("message"), Some("disconnected"));
+    assert_eq!(error.body, b"Connection is using too much memory.");
+}
+
+#[test]
+fn acknowledged_messages_stop_counting() {
+    let server = TestServer::start_with_args(&["--max-connection-memory", "4096"]);
+    let mut subscriber = server.subscriber("/queue/memory", &[("ack", "client-individual")]);
+
+    let mut sender = server.login();
+    let body = "x".repeat(BODY_SIZE);
+    for _ in 0..10 {
+        sender.send("SEND", &[("destination", "/queue/memory")], &body);
+        let message = subscriber.recv();
+        assert_eq!(message.command, "MESSAGE");
+        subscriber.send("ACK", &[("id", message.header("ack").unwrap())], "");
+    }
+    subscriber.send("BEGIN", &[("transaction", "t"), ("receipt", "alive")], "");
+    assert_eq!(subscriber.recv().header("receipt-id"), Some("alive"));
+}
+
+#[test]
+fn transaction_frames_count_toward_memory_limit() {
+    let server = TestServer::start_with_args(&["--max-connection-memory", "4096"]);
+    let mut sender = server.login();
+    sender.send("BEGIN", &[("transaction", "t")], "");
+    let body = "x".repeat(BODY_SIZE);
+    for _ in 0..10 {
+        sender.send("SEND", &[("destination", "/queue/memory"), ("transaction", "t")], &body);
+    }
+    let error = recv_error(&mut sender);
+    assert_eq!(error.body, b"Connection is using too much memory.");
+}
+
+#[test]
+fn connection_memory_is_in_snapshot() {
+    let server = TestServer::start();
+    let mut subscriber = server.subscriber("/queue/memory", &[("ack", "client-individual")]);
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/memory")], &"x".repeat(BODY_SIZE));
+    let message = subscriber.recv();
+    // The message is held until it's acknowledged
+    assert!(wait_until(|| server.metrics.snapshot().connection_memory >= BODY_SIZE));
+
+    subscriber.send("ACK", &[("id", message.header("ack").unwrap())], "");
+    assert!(wait_until(|| server.metrics.snapshot().connection_memory == 0));
+}
@@ -0,0 +1,70 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::sync::Arc;
+use std::thread;
+
+use romp::metrics::{MessageCounts, Metrics};
+
+use common::TestServer;
+
+#[test]
+fn destinations_are_counted_separately() {
+    let server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/topic/news"),
+                                   ("receipt", "sub")], "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    // Nobody is consuming from the queue, so its messages are published but not delivered
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/jobs")], "1");
+    sender.send("SEND", &[("destination", "/queue/jobs")], "2");
+    sender.send("SEND", &[("destination", "/topic/news")], "3");
+    sender.send("SEND", &[("destination", "/queue/jobs"), ("receipt", "done")], "4");
+    assert_eq!(sender.recv().command, "RECEIPT");
+    assert_eq!(subscriber.recv().body, b"3");
+
+    let messages = server.metrics.snapshot().messages;
+    assert_eq!(messages.get("/queue/jobs"), Some(&MessageCounts { published: 3, delivered: 0 }));
+    assert_eq!(messages.get("/topic/news"), Some(&MessageCounts { published: 1, delivered: 1 }));
+}
+
+#[test]
+fn counters_add_up_across_threads() {
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 1000;
+    let metrics = Arc::new(Metrics::new());
+
+    let threads: Vec<_> = (0..THREADS).map(|_| {
+        let metrics = metrics.clone();
+        thread::spawn(move || {
+            for _ in 0..ROUNDS {
+                metrics.connection_opened();
+                metrics.frame_processed();
+                metrics.message_delivered();
+                metrics.message_held();
+                metrics.frame_processed();
+                metrics.messages_released(1);
+                metrics.connection_closed();
+            }
+            metrics.connection_opened();
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.connections, THREADS);
+    assert_eq!(snapshot.frames_processed, 2 * THREADS * ROUNDS);
+    assert_eq!(snapshot.messages_delivered, THREADS * ROUNDS);
+    assert_eq!(snapshot.messages_waiting, 0);
+}
@@ -0,0 +1,328 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Cursor, ErrorKind, Read};
+
+use romp::stomp::{parse_frame, ExtensionRegistry, Frame, StompCommand};
+use romp::stomp::parse::{parse_command, parse_content_length, parse_frame_with_extensions,
+                         ParseError, ParseLimits};
+
+// A stream that returns its data in the given pieces, one piece per read at most, like a
+// socket receiving separate TCP segments
+// An empty piece is a read that would block, like a non-blocking socket with nothing to read.
+struct Segments {
+    segments: VecDeque<Vec<u8>>,
+}
+
+impl Segments {
+    fn new(segments: &[&[u8]]) -> Segments {
+        Segments {
+            segments: segments.iter().map(|s| s.to_vec()).collect(),
+        }
+    }
+}
+
+impl Read for Segments {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let segment = match self.segments.front_mut() {
+            Some(segment) => segment,
+            None => return Ok(0),
+        };
+        if segment.is_empty() {
+            self.segments.pop_front();
+            return Err(io::Error::from(ErrorKind::WouldBlock));
+        }
+        let n = buf.len().min(segment.len());
+        buf[..n].copy_from_slice(&segment[..n]);
+        segment.drain(..n);
+        if segment.is_empty() {
+            self.segments.pop_front();
+        }
+        Ok(n)
+    }
+}
+
+// Parse a frame that's been split into pieces
+fn parse_segments(segments: &[&[u8]]) -> Frame {
+    parse_frame(&mut BufReader::new(Segments::new(segments))).unwrap()
+}
+
+#[test]
+fn frame_split_at_header_colon_parses() {
+    let whole = parse_frame(&mut Cursor::new(&b"SEND\ndestination:/queue/a\n\nhi\0"[..])).unwrap();
+    let split = parse_segments(&[b"SEND\ndestination", b":/queue/a\n\nhi\0"]);
+    assert_eq!(split, whole);
+    assert_eq!(split.header().get("destination"), Some(&"/queue/a".to_string()));
+}
+
+#[test]
+fn frame_split_mid_escape_parses() {
+    let split = parse_segments(&[b"SEND\ndestination:/queue/a\nnote:one\\", b"ntwo\\", b"c\n\n\0"]);
+    assert_eq!(split.header().get("note"), Some(&"one\ntwo:".to_string()));
+}
+
+#[test]
+fn frame_read_one_byte_at_a_time_parses() {
+    let bytes = &b"SEND\ndestination:/queue/a\nk\\cey:va\\\\l:ue\n\nbody\0"[..];
+    let whole = parse_frame(&mut Cursor::new(bytes)).unwrap();
+    let mut one_byte = BufReader::with_capacity(1, Cursor::new(bytes));
+    assert_eq!(parse_frame(&mut one_byte).unwrap(), whole);
+    assert_eq!(whole.header().get("k:ey"), Some(&"va\\l:ue".to_string()));
+    assert_eq!(whole.body(), b"body");
+}
+
+#[test]
+fn read_that_would_block_is_a_timeout() {
+    // Between frames there's nothing wrong; the next frame can still be read once it arrives
+    let mut reader = BufReader::new(Segments::new(&[b"", b"SEND\ndestination:/queue/a\n\nhi\0"]));
+    assert_eq!(parse_frame(&mut reader), Err(ParseError::IdleTimeout));
+    assert_eq!(parse_frame(&mut reader).unwrap().body(), b"hi");
+
+    // Partway through a frame it's a timeout rather than a bad or truncated frame
+    for segments in &[&[&b"SE"[..], b""][..],
+                      &[b"SEND\ndestination:/queue/a\n", b""],
+                      &[b"SEND\ndestination:/queue/a\n\nh", b""],
+                      &[b"SEND\ndestination:/queue/a\ncontent-length:2\n\nh", b""]] {
+        let mut reader = BufReader::new(Segments::new(segments));
+        assert_eq!(parse_frame(&mut reader), Err(ParseError::ReadTimeout), "{:?}", segments);
+    }
+}
+
+#[test]
+fn stream_ending_between_frames_is_a_disconnect() {
+    let mut reader = Cursor::new(&b"SEND\ndestination:/queue/a\n\nhi\0\n\n"[..]);
+    assert!(parse_frame(&mut reader).is_ok());
+    // Heart-beats after the last frame don't make it any less clean
+    assert_eq!(parse_frame(&mut reader), Err(ParseError::Disconnected));
+    assert_eq!(parse_frame(&mut Cursor::new(&b""[..])), Err(ParseError::Disconnected));
+}
+
+#[test]
+fn stream_ending_inside_the_command_is_not_an_invalid_command() {
+    for bytes in &[&b"SEN"[..], b"\n\nSEN", b"SEND"] {
+        assert_eq!(parse_frame(&mut Cursor::new(bytes)),
+                   Err(ParseError::Io(ErrorKind::UnexpectedEof)), "{:?}", bytes);
+    }
+    // A whole command line that isn't a command still is
+    assert_eq!(parse_frame(&mut Cursor::new(&b"SEN\n\n\0"[..])), Err(ParseError::InvalidCommand));
+}
+
+#[test]
+fn lf_and_crlf_frames_are_equal() {
+    let lf = parse_frame(&mut Cursor::new(&b"SEND\ndestination:/queue/a\nfoo:bar\n\nline\n\0"[..]));
+    let crlf = parse_frame(&mut Cursor::new(
+        &b"SEND\r\ndestination:/queue/a\r\nfoo:bar\r\n\r\nline\n\0"[..]));
+    assert_eq!(lf.unwrap(), crlf.unwrap());
+}
+
+#[test]
+fn leading_heart_beats_are_skipped() {
+    let mut reader = Cursor::new(&b"\n\r\n\nSTOMP\naccept-version:1.2\nhost:localhost\n\n\0"[..]);
+    let frame = parse_frame(&mut reader).unwrap();
+    assert_eq!(frame.command(), StompCommand::Stomp);
+    assert_eq!(frame.header().get("host"), Some(&"localhost".to_string()));
+}
+
+#[test]
+fn frame_without_headers_survives_a_round_trip() {
+    let frame = Frame::from_command(StompCommand::Disconnect);
+    assert_eq!(frame.header().len(), 0);
+
+    let bytes = frame.to_bytes();
+    // The command line and the blank line that ends the (empty) headers, then the NUL
+    assert_eq!(bytes, b"DISCONNECT\r\n\r\n\0");
+    let parsed = Frame::try_from(&bytes[..]).unwrap();
+    assert_eq!(parsed, frame);
+}
+
+#[test]
+fn every_command_survives_a_round_trip() {
+    use romp::stomp::StompCommand::*;
+    for &command in &[Stomp, Send, Subscribe, Unsubscribe, Ack, Nack, Begin, Commit, Abort,
+                      Disconnect, Connected, Message, Receipt, Error] {
+        assert_eq!(StompCommand::from_string(command.as_str()), Some(command));
+
+        let frame = Frame::builder(command).header("key", "value").build();
+        assert_eq!(Frame::try_from(&frame.to_bytes()[..]), Ok(frame.clone()));
+        assert_eq!(frame.to_string().parse::<Frame>(), Ok(frame));
+    }
+    // CONNECT is the older name for STOMP
+    assert_eq!("CONNECT\n\n\0".parse::<Frame>().map(|f| f.command()), Ok(Stomp));
+    assert_eq!("BOGUS\n\n\0".parse::<Frame>(), Err(ParseError::InvalidCommand));
+}
+
+#[test]
+fn escaped_headers_survive_a_round_trip() {
+    let frame = Frame::builder(StompCommand::Message)
+        .header("destination", "/queue/a")
+        .header("odd:key", "back\\slash\r\nnew:line")
+        .build();
+    let bytes = frame.to_bytes();
+    let line = &b"odd\\ckey:back\\\\slash\\r\\nnew\\cline\r\n"[..];
+    assert!(bytes.windows(line.len()).any(|w| w == line), "{:?}", String::from_utf8_lossy(&bytes));
+    assert_eq!(Frame::try_from(&bytes[..]), Ok(frame));
+}
+
+#[test]
+fn connect_headers_are_taken_literally() {
+    let frame = parse_frame(&mut Cursor::new(
+        &b"CONNECT\naccept-version:1.2\nhost:localhost\npasscode:a\\nb:c\n\n\0"[..])).unwrap();
+    assert_eq!(frame.header().get("passcode"), Some(&"a\\nb:c".to_string()));
+    assert_eq!(Frame::try_from(&frame.to_bytes()[..]), Ok(frame));
+}
+
+#[test]
+fn repeated_header_keeps_its_first_value() {
+    let frame = parse_frame(&mut Cursor::new(
+        &b"SEND\ndestination:/queue/a\nreceipt:1\nreceipt:2\n\n\0"[..])).unwrap();
+    assert_eq!(frame.header().get("receipt"), Some(&"1".to_string()));
+    // The later value is dropped rather than stored, so it isn't sent on either
+    assert_eq!(frame.header().get_all("receipt"), vec!["1"]);
+    assert_eq!(frame.header().len(), 2);
+}
+
+#[test]
+fn reserved_headers_are_found_whatever_their_case() {
+    let frame = parse_frame(&mut Cursor::new(
+        &b"SEND\nDestination:/queue/a\nRECEIPT:r\nContent-Length:5\nX-Custom:y\n\nhello\0"[..]))
+        .unwrap();
+    assert_eq!(frame.header().get("destination"), Some(&"/queue/a".to_string()));
+    assert_eq!(frame.header().get("receipt"), Some(&"r".to_string()));
+    assert_eq!(frame.content_length(), Some(5));
+    // Headers the server doesn't read keep the case they were sent with
+    assert_eq!(frame.header().get("X-Custom"), Some(&"y".to_string()));
+    assert_eq!(frame.header().get("x-custom"), None);
+}
+
+#[test]
+fn binary_body_with_content_length_parses() {
+    let mut reader = Cursor::new(
+        &b"SEND\ndestination:/queue/a\ncontent-length:4\n\n\xff\0\xfe\x80\0"[..]);
+    let frame = parse_frame(&mut reader).unwrap();
+    assert_eq!(frame.body(), b"\xff\0\xfe\x80");
+    assert_eq!(Frame::try_from(&frame.to_bytes()[..]), Ok(frame));
+}
+
+#[test]
+fn nuls_inside_a_content_length_body_are_data() {
+    let mut reader = Cursor::new(&b"SEND\ndestination:/queue/a\ncontent-length:7\n\n\0a\0\0b\0\0\0\
+                                   SEND\ndestination:/queue/b\n\n\0"[..]);
+    assert_eq!(parse_frame(&mut reader).unwrap().body(), b"\0a\0\0b\0\0");
+    // Only the NUL after the declared length ends the frame, so the next one follows on
+    let next = parse_frame(&mut reader).unwrap();
+    assert_eq!(next.header().get("destination"), Some(&"/queue/b".to_string()));
+}
+
+#[test]
+fn content_length_shorter_than_body_is_a_mismatch() {
+    let mut reader = Cursor::new(&b"SEND\ndestination:/queue/a\ncontent-length:3\n\nhello\0"[..]);
+    assert_eq!(parse_frame(&mut reader), Err(ParseError::ContentLengthMismatch));
+}
+
+#[test]
+fn content_length_longer_than_body_runs_out_of_stream() {
+    let mut reader = Cursor::new(&b"SEND\ndestination:/queue/a\ncontent-length:10\n\nhello\0"[..]);
+    assert_eq!(parse_frame(&mut reader), Err(ParseError::Io(ErrorKind::UnexpectedEof)));
+}
+
+#[test]
+fn huge_content_length_is_too_large() {
+    let mut reader = Cursor::new(&b"SEND\ndestination:/queue/a\n\
+        content-length:1234567890123456789012345678901234567890\n\nhello\0"[..]);
+    assert_eq!(parse_frame(&mut reader),
+               Err(ParseError::FrameTooLarge("Content-length is too large.")));
+    assert_eq!(parse_content_length("-5"),
+               Err(ParseError::MalformedHeader("Invalid content-length.")));
+}
+
+#[test]
+fn content_length_matching_body_parses() {
+    let mut reader = Cursor::new(&b"SEND\ndestination:/queue/a\ncontent-length:5\n\nhello\0"[..]);
+    assert_eq!(parse_frame(&mut reader).unwrap().body(), b"hello");
+}
+
+#[test]
+fn text_body_is_checked_against_its_charset() {
+    let parse = |content_type: &str, body: &[u8]| {
+        let mut bytes = format!("SEND\ndestination:/queue/a\ncontent-type:{}\n\n", content_type)
+            .into_bytes();
+        bytes.extend_from_slice(body);
+        bytes.push(0);
+        parse_frame(&mut Cursor::new(bytes)).map(|frame| frame.body().to_vec())
+    };
+    let utf8 = "caf\u{e9} \u{2615}".as_bytes();
+    assert_eq!(parse("text/plain;charset=utf-8", utf8), Ok(utf8.to_vec()));
+    assert_eq!(parse("text/plain", utf8), Ok(utf8.to_vec()));
+    assert_eq!(parse("text/plain;charset=UTF-8", b"caf\xe9"), Err(ParseError::BodyNotUtf8));
+
+    assert_eq!(parse("text/plain; charset=us-ascii", b"plain"), Ok(b"plain".to_vec()));
+    assert_eq!(parse("text/plain; charset=us-ascii", utf8), Err(ParseError::BodyNotInCharset));
+    // Bodies that aren't text can hold anything
+    assert_eq!(parse("application/octet-stream", b"\xff\xfe"), Ok(b"\xff\xfe".to_vec()));
+}
+
+#[test]
+fn command_line_is_limited() {
+    let extensions = ExtensionRegistry::new();
+    let mut limits = ParseLimits::new();
+    limits.max_header_line = 4;
+
+    let mut reader = Cursor::new(&b"SEND\n"[..]);
+    assert_eq!(parse_command(&mut reader, &extensions, &limits), Ok(StompCommand::Send));
+
+    // The parser gives up as soon as the line is too long, rather than reading all of it
+    let mut reader = Cursor::new(&b"SENDS\n"[..]);
+    assert_eq!(parse_command(&mut reader, &extensions, &limits),
+               Err(ParseError::FrameTooLarge("command line too long")));
+
+    let mut endless = io::repeat(b'X').take(1 << 40);
+    let mut reader = BufReader::new(&mut endless);
+    assert_eq!(parse_command(&mut reader, &ExtensionRegistry::new(), &ParseLimits::new()),
+               Err(ParseError::FrameTooLarge("command line too long")));
+}
+
+// Parse a frame under the given limits
+fn parse_limited(bytes: &[u8], limits: &ParseLimits) -> Result<Frame, ParseError> {
+    parse_frame_with_extensions(&mut Cursor::new(bytes), &ExtensionRegistry::new(), limits)
+}
+
+#[test]
+fn header_count_is_limited() {
+    let mut limits = ParseLimits::new();
+    limits.max_headers = 2;
+
+    assert!(parse_limited(b"SEND\ndestination:/queue/a\nb:2\n\n\0", &limits).is_ok());
+    assert_eq!(parse_limited(b"SEND\ndestination:/queue/a\nb:2\nc:3\n\n\0", &limits),
+               Err(ParseError::FrameTooLarge("too many headers")));
+}
+
+#[test]
+fn header_line_length_is_limited() {
+    let mut limits = ParseLimits::new();
+    limits.max_header_line = 16;
+
+    // Escapes count as the bytes sent, and line breaks don't count at all
+    let frame = parse_limited(b"SEND\r\ndestination:/q\\\\\r\n\r\n\0", &limits).unwrap();
+    assert_eq!(frame.header().get("destination"), Some(&"/q\\".to_string()));
+    assert_eq!(parse_limited(b"SEND\ndestination:/q\\\\a\n\n\0", &limits),
+               Err(ParseError::FrameTooLarge("header line too long")));
+}
+
+#[test]
+fn body_size_is_limited() {
+    let mut limits = ParseLimits::new();
+    limits.max_body_size = Some(5);
+
+    assert!(parse_limited(b"SEND\ndestination:/queue/a\n\nhello\0", &limits).is_ok());
+    assert_eq!(parse_limited(b"SEND\ndestination:/queue/a\n\nhello!\0", &limits),
+               Err(ParseError::FrameTooLarge("body exceeds maximum size")));
+    assert_eq!(parse_limited(b"SEND\ndestination:/queue/a\ncontent-length:6\n\nhello!\0",
+                             &limits),
+               Err(ParseError::FrameTooLarge("body exceeds maximum size")));
+}
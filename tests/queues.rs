@@ -0,0 +1,345 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use romp::broker::Broker;
+use romp::config::Config;
+use romp::metrics::Metrics;
+use romp::stomp::{Frame, StompCommand};
+
+use common::{add_client, TestClient, TestServer};
+
+// Check that nothing else is waiting for a client, by making sure a receipt is the next frame
+fn assert_nothing_waiting(client: &mut TestClient) {
+    client.send("BEGIN", &[("transaction", "check"), ("receipt", "check")], "");
+    let frame = client.recv();
+    assert_eq!(frame.command, "RECEIPT", "{:?}", frame);
+}
+
+#[test]
+fn held_messages_drain_to_late_subscriber() {
+    let server = TestServer::start();
+
+    let mut sender = server.login();
+    for body in &["1", "2", "3"] {
+        sender.send("SEND", &[("destination", "/queue/late")], body);
+    }
+    sender.send("SEND", &[("destination", "/queue/late"), ("receipt", "sent")], "4");
+    assert_eq!(sender.recv().command, "RECEIPT");
+    assert_eq!(server.metrics.snapshot().messages_waiting, 4);
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/late")], "");
+    for body in &["1", "2", "3", "4"] {
+        assert_eq!(subscriber.recv().body, body.as_bytes());
+    }
+    assert_eq!(server.metrics.snapshot().messages_waiting, 0);
+}
+
+#[test]
+fn held_messages_are_bounded_by_max_depth() {
+    let mut config = Config::new();
+    config.port = 0;
+    config.max_queue_depth = Some(2);
+    let server = TestServer::start_with_config(config);
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/bounded")], "1");
+    sender.send("SEND", &[("destination", "/queue/bounded")], "2");
+    sender.send("SEND", &[("destination", "/queue/bounded")], "3");
+    let error = sender.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.body, b"Queue is full.");
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/bounded")], "");
+    assert_eq!(subscriber.recv().body, b"1");
+    assert_eq!(subscriber.recv().body, b"2");
+    assert_nothing_waiting(&mut subscriber);
+}
+
+#[test]
+fn queue_subscribers_take_turns() {
+    let server = TestServer::start();
+
+    let mut first = server.subscriber("/queue/work", &[]);
+    let mut second = server.subscriber("/queue/work", &[]);
+
+    let mut sender = server.login();
+    for body in &["1", "2", "3"] {
+        sender.send("SEND", &[("destination", "/queue/work")], body);
+    }
+    sender.send("SEND", &[("destination", "/queue/work"), ("receipt", "sent")], "4");
+    assert_eq!(sender.recv().command, "RECEIPT");
+
+    // Each message goes to one subscriber, alternating between them
+    assert_eq!(first.recv().body, b"1");
+    assert_eq!(first.recv().body, b"3");
+    assert_nothing_waiting(&mut first);
+    assert_eq!(second.recv().body, b"2");
+    assert_eq!(second.recv().body, b"4");
+    assert_nothing_waiting(&mut second);
+}
+
+#[test]
+fn topic_subscribers_all_get_every_message() {
+    let server = TestServer::start();
+
+    let mut first = server.subscriber("/topic/news", &[]);
+    let mut second = server.subscriber("/topic/news", &[]);
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/topic/news")], "hello");
+    assert_eq!(first.recv().body, b"hello");
+    assert_eq!(second.recv().body, b"hello");
+}
+
+#[test]
+fn prefetch_holds_messages_until_acknowledged() {
+    let server = TestServer::start();
+
+    let mut consumer = server.subscriber("/queue/prefetch", &[("ack", "client-individual"),
+                                                              ("prefetch-count", "1")]);
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/prefetch")], "1");
+    sender.send("SEND", &[("destination", "/queue/prefetch"), ("receipt", "sent")], "2");
+    assert_eq!(sender.recv().command, "RECEIPT");
+
+    let first = consumer.recv();
+    assert_eq!(first.body, b"1");
+    assert_nothing_waiting(&mut consumer);
+    assert_eq!(server.metrics.snapshot().messages_waiting, 1);
+
+    // Acknowledging the first message makes room for the second
+    consumer.send("ACK", &[("id", first.header("ack").unwrap())], "");
+    assert_eq!(consumer.recv().body, b"2");
+    assert_eq!(server.metrics.snapshot().messages_waiting, 0);
+}
+
+#[test]
+fn consumer_at_prefetch_limit_is_passed_over() {
+    let server = TestServer::start_with_args(&["--prefetch", "1"]);
+
+    let mut stuck = server.subscriber("/queue/work", &[("ack", "client")]);
+    let mut working = server.subscriber("/queue/work", &[]);
+
+    let mut sender = server.login();
+    for body in &["1", "2", "3"] {
+        sender.send("SEND", &[("destination", "/queue/work")], body);
+    }
+    sender.send("SEND", &[("destination", "/queue/work"), ("receipt", "sent")], "4");
+    assert_eq!(sender.recv().command, "RECEIPT");
+
+    // The consumer that isn't acknowledging gets one message; the other takes the rest
+    assert_eq!(stuck.recv().body, b"1");
+    assert_nothing_waiting(&mut stuck);
+    for body in &["2", "3", "4"] {
+        assert_eq!(working.recv().body, body.as_bytes());
+    }
+}
+
+#[test]
+fn max_depth_holds_with_consumer_at_prefetch_limit() {
+    let server = TestServer::start_with_args(&["--prefetch", "1", "--max-queue-depth", "2"]);
+
+    let mut consumer = server.subscriber("/queue/busy", &[("ack", "client")]);
+
+    // The consumer takes one message, then can't take any more until it acknowledges it, so
+    // the rest wait in the queue until it's full
+    let mut sender = server.login();
+    for body in &["1", "2", "3"] {
+        sender.send("SEND", &[("destination", "/queue/busy")], body);
+    }
+    sender.send("SEND", &[("destination", "/queue/busy")], "4");
+    let error = sender.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.body, b"Queue is full.");
+    assert_eq!(server.metrics.snapshot().messages_waiting, 2);
+
+    let first = consumer.recv();
+    assert_eq!(first.body, b"1");
+    consumer.send("ACK", &[("id", first.header("ack").unwrap())], "");
+    let second = consumer.recv();
+    assert_eq!(second.body, b"2");
+    consumer.send("ACK", &[("id", second.header("ack").unwrap())], "");
+    assert_eq!(consumer.recv().body, b"3");
+    assert_nothing_waiting(&mut consumer);
+}
+
+#[test]
+fn full_queue_sends_to_dead_letter_with_consumer_at_prefetch_limit() {
+    let server = TestServer::start_with_args(&["--prefetch", "1", "--max-queue-depth", "1",
+                                               "--dead-letter", "/queue/dead"]);
+
+    let _consumer = server.subscriber("/queue/busy", &[("ack", "client")]);
+
+    let mut sender = server.login();
+    for body in &["1", "2"] {
+        sender.send("SEND", &[("destination", "/queue/busy")], body);
+    }
+    sender.send("SEND", &[("destination", "/queue/busy"), ("receipt", "sent")], "3");
+    assert_eq!(sender.recv().command, "RECEIPT");
+
+    let mut dead = server.login();
+    dead.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/dead")], "");
+    let letter = dead.recv();
+    assert_eq!(letter.body, b"3");
+    assert_eq!(letter.header("original-destination"), Some("/queue/busy"));
+}
+
+#[test]
+fn full_queue_with_no_subscribers_sends_to_dead_letter() {
+    let server = TestServer::start_with_args(&["--max-queue-depth", "1",
+                                               "--dead-letter", "/queue/dead"]);
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/unread")], "1");
+    sender.send("SEND", &[("destination", "/queue/unread"), ("receipt", "sent")], "2");
+    assert_eq!(sender.recv().command, "RECEIPT");
+
+    let mut dead = server.login();
+    dead.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/dead")], "");
+    let letter = dead.recv();
+    assert_eq!(letter.body, b"2");
+    assert_eq!(letter.header("destination"), Some("/queue/dead"));
+    assert_eq!(letter.header("original-destination"), Some("/queue/unread"));
+
+    // The message that fit is still there for the queue's first subscriber
+    let mut consumer = server.login();
+    consumer.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/unread")], "");
+    assert_eq!(consumer.recv().body, b"1");
+}
+
+#[test]
+fn message_nacked_too_often_goes_to_dead_letter() {
+    let server = TestServer::start_with_args(&["--max-redeliveries", "1",
+                                               "--dead-letter", "/queue/dead"]);
+
+    let mut dead = server.subscriber("/queue/dead", &[]);
+    let mut consumer = server.subscriber("/queue/poison", &[("ack", "client-individual")]);
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/poison")], "bad");
+
+    // Turned down once, it's delivered again; turned down again, it's given up on
+    let first = consumer.recv();
+    assert_eq!(first.body, b"bad");
+    consumer.send("NACK", &[("id", first.header("ack").unwrap())], "");
+    let second = consumer.recv();
+    assert_eq!(second.body, b"bad");
+    assert_eq!(second.header("redelivered"), Some("true"));
+    consumer.send("NACK", &[("id", second.header("ack").unwrap())], "");
+
+    let letter = dead.recv();
+    assert_eq!(letter.body, b"bad");
+    assert_eq!(letter.header("original-destination"), Some("/queue/poison"));
+    assert_nothing_waiting(&mut consumer);
+}
+
+// Subscribe a client straight through the broker
+fn broker_subscribe(broker: &mut Broker, client: usize, ack: &str) {
+    broker.handle_frame(client, Frame::builder(StompCommand::Subscribe)
+        .header("id", "0")
+        .header("destination", "/queue/nack")
+        .header("ack", ack)
+        .build());
+}
+
+#[test]
+fn nacked_message_waits_when_the_other_consumer_is_leaving() {
+    let metrics = Arc::new(Metrics::new());
+    let mut broker = Broker::new(Config::new(), metrics.clone());
+
+    let first = add_client(&mut broker, 1);
+    broker_subscribe(&mut broker, 1, "client-individual");
+    add_client(&mut broker, 3);
+    broker.handle_frame(3, Frame::builder(StompCommand::Send)
+        .header("destination", "/queue/nack")
+        .body(b"hello")
+        .build());
+    let message = first.try_recv().unwrap();
+    assert_eq!(message.body(), b"hello");
+
+    // The other consumer's connection has closed, but the broker hasn't heard yet
+    let second = add_client(&mut broker, 2);
+    broker_subscribe(&mut broker, 2, "auto");
+    drop(second);
+
+    // The NACKed message goes to the other consumer, which can't take it
+    let ack = message.header().get("ack").unwrap().clone();
+    broker.handle_frame(1, Frame::builder(StompCommand::Nack).header("id", &ack).build());
+    assert_eq!(metrics.snapshot().messages_waiting, 1);
+
+    // It's still there for the next consumer
+    broker.remove_client(2);
+    broker.remove_client(1);
+    let third = add_client(&mut broker, 4);
+    broker_subscribe(&mut broker, 4, "auto");
+    let redelivered = third.try_recv().unwrap();
+    assert_eq!(redelivered.body(), b"hello");
+    assert_eq!(redelivered.header().get("redelivery-count"), Some(&"1".to_string()));
+}
+
+#[test]
+fn unacked_message_is_redelivered_after_the_ack_timeout() {
+    let mut config = Config::new();
+    config.ack_timeout = Some(Duration::from_secs(30));
+    let mut broker = Broker::new(config, Arc::new(Metrics::new()));
+
+    let first = add_client(&mut broker, 1);
+    broker_subscribe(&mut broker, 1, "client-individual");
+    let second = add_client(&mut broker, 2);
+    broker_subscribe(&mut broker, 2, "client-individual");
+    add_client(&mut broker, 3);
+    broker.handle_frame(3, Frame::builder(StompCommand::Send)
+        .header("destination", "/queue/nack")
+        .body(b"hello")
+        .build());
+    let message = first.try_recv().unwrap();
+    assert_eq!(message.header().get("redelivered"), None);
+
+    // The broker knows when to check on it, and it isn't redelivered early
+    assert!(broker.next_poll().is_some_and(|wait| wait > Duration::from_secs(29)));
+    broker.expire_unacked(Instant::now() + Duration::from_secs(29));
+    assert!(second.try_recv().is_err());
+
+    // Once the timeout has passed it goes to the other consumer
+    broker.expire_unacked(Instant::now() + Duration::from_secs(31));
+    let redelivered = second.try_recv().unwrap();
+    assert_eq!(redelivered.body(), b"hello");
+    assert_eq!(redelivered.header().get("redelivered"), Some(&"true".to_string()));
+    assert_eq!(redelivered.header().get("redelivery-count"), Some(&"1".to_string()));
+
+    // The first consumer acknowledging it late isn't an error
+    let ack = message.header().get("ack").unwrap().clone();
+    broker.handle_frame(1, Frame::builder(StompCommand::Ack).header("id", &ack).build());
+    assert!(first.try_recv().is_err());
+}
+
+#[test]
+fn unacked_message_comes_back_once_the_ack_timeout_passes() {
+    let server = TestServer::start_with_args(&["--ack-timeout", "1"]);
+
+    let mut consumer = server.subscriber("/queue/slow", &[("ack", "client-individual")]);
+    server.login().send("SEND", &[("destination", "/queue/slow")], "hello");
+    let message = consumer.recv();
+    assert_eq!(message.header("redelivered"), None);
+
+    // With nobody else to take it, it's sent to the same consumer again
+    let redelivered = consumer.recv();
+    assert_eq!(redelivered.body, b"hello");
+    assert_eq!(redelivered.header("redelivered"), Some("true"));
+    assert_ne!(redelivered.header("ack"), message.header("ack"));
+
+    consumer.send("ACK", &[("id", redelivered.header("ack").unwrap()), ("receipt", "ack")], "");
+    assert_eq!(consumer.recv().command, "RECEIPT");
+}
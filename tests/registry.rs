@@ -0,0 +1,86 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+use romp::broker::registry::{is_pattern, matches, DestinationRegistry, Subscription};
+
+// A subscription to a wildcard pattern
+fn pattern(client: usize, id: &str, destination: &str) -> Subscription {
+    let mut sub = Subscription::new(client, id, destination);
+    sub.pattern = true;
+    sub
+}
+
+// An exclusive subscription to a queue
+fn exclusive(client: usize, id: &str, destination: &str) -> Subscription {
+    let mut sub = Subscription::new(client, id, destination);
+    sub.exclusive = true;
+    sub
+}
+
+#[test]
+fn wildcards_match_segments() {
+    assert!(is_pattern("/topic/prices.*"));
+    assert!(!is_pattern("/topic/prices"));
+
+    assert!(matches("/topic/prices.*", "/topic/prices.gold"));
+    assert!(!matches("/topic/prices.*", "/topic/prices.gold.spot"));
+    assert!(!matches("/topic/prices.*", "/topic/prices"));
+    assert!(matches("/topic/prices.#", "/topic/prices.gold"));
+    assert!(matches("/topic/prices.#", "/topic/prices.gold.spot"));
+    // Separators have to line up as well as segments
+    assert!(!matches("/topic/prices.*", "/topic/prices/gold"));
+}
+
+#[test]
+fn pattern_subscription_gets_matching_destinations_only() {
+    let mut registry = DestinationRegistry::new();
+    registry.subscribe(pattern(1, "0", "/topic/prices.*"), None).unwrap();
+    registry.subscribe(Subscription::new(2, "0", "/topic/prices.gold"), None).unwrap();
+
+    let clients: Vec<usize> = registry.subscribers("/topic/prices.gold").iter()
+        .map(|s| s.client).collect();
+    assert_eq!(clients, vec![2, 1]);
+
+    let clients: Vec<usize> = registry.subscribers("/topic/prices.gold.spot").iter()
+        .map(|s| s.client).collect();
+    assert!(clients.is_empty());
+}
+
+#[test]
+fn exclusive_consumer_holds_off_matching_patterns() {
+    let mut registry = DestinationRegistry::new();
+    registry.subscribe(exclusive(1, "0", "/queue/jobs.a"), None).unwrap();
+
+    assert_eq!(registry.subscribe(pattern(2, "0", "/queue/jobs.*"), None),
+               Err("Destination has an exclusive consumer."));
+    // A pattern that doesn't match the exclusive queue is fine
+    registry.subscribe(pattern(2, "1", "/queue/other.*"), None).unwrap();
+
+    registry.unsubscribe(1, "0");
+    registry.subscribe(pattern(2, "0", "/queue/jobs.*"), None).unwrap();
+}
+
+#[test]
+fn matching_pattern_holds_off_exclusive_consumer() {
+    let mut registry = DestinationRegistry::new();
+    registry.subscribe(pattern(1, "0", "/queue/jobs.*"), None).unwrap();
+
+    assert_eq!(registry.subscribe(exclusive(2, "0", "/queue/jobs.a"), None),
+               Err("Destination already has consumers; cannot subscribe exclusively."));
+    registry.subscribe(exclusive(2, "1", "/queue/other.a"), None).unwrap();
+}
+
+#[test]
+fn refused_subscription_leaves_no_entry() {
+    let mut registry = DestinationRegistry::new();
+    assert_eq!(registry.subscribe(Subscription::new(1, "0", "/queue/a"), Some(0)),
+               Err("Too many subscribers for destination."));
+    let mut sub = exclusive(1, "1", "/queue/b.*");
+    sub.pattern = true;
+    assert!(registry.subscribe(sub, None).is_err());
+    assert!(registry.counts().is_empty());
+}
@@ -0,0 +1,53 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use common::TestServer;
+
+#[test]
+fn shutdown_stops_accepting() {
+    let mut server = TestServer::start();
+    let mut client = server.login();
+
+    assert!(server.stop(), "Server didn't stop after shutdown was signaled");
+    assert!(TcpStream::connect(server.addr).is_err(), "Listener is still accepting");
+
+    // Connected clients are told why they're being dropped
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("message"), Some("server shutting down"));
+}
+
+#[test]
+fn listeners_stop_without_being_woken() {
+    let mut server = TestServer::start_with_args(&["--ws-port", "0"]);
+    let ws_addr = server.ws_addr.unwrap();
+
+    // The listeners notice the flag on their own rather than waiting for another connection
+    let start = Instant::now();
+    assert!(server.stop(), "Server didn't stop after shutdown was signaled");
+    assert!(start.elapsed() < Duration::from_secs(1), "Shutdown took {:?}", start.elapsed());
+    assert!(TcpStream::connect(server.addr).is_err(), "Listener is still accepting");
+    assert!(TcpStream::connect(ws_addr).is_err(), "WebSocket listener is still accepting");
+}
+
+#[test]
+fn draining_clients_are_sent_to_the_redirect_target() {
+    let mut server = TestServer::start_with_args(&["--redirect", "backup.example.com:61613"]);
+    let mut first = server.login();
+    let mut second = server.login();
+
+    assert!(server.stop(), "Server didn't stop after shutdown was signaled");
+    for client in [&mut first, &mut second] {
+        let error = client.recv();
+        assert_eq!(error.command, "ERROR");
+        // Headers are read as they are on the wire, where the colon is escaped
+        assert_eq!(error.header("romp-redirect"), Some("backup.example.com\\c61613"));
+    }
+}
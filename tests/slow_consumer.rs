@@ -0,0 +1,102 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use common::{TestClient, TestServer};
+
+// Enough messages of this size to fill the socket buffers of a client that isn't reading
+const MESSAGES: usize = 24;
+const BODY_SIZE: usize = 512 * 1024;
+
+// Start a server with the given options, with a slow and a fast subscriber, then send it every
+// message and wait for the broker to take them all and the fast subscriber to read them
+// Returns the slow subscriber, which hasn't read anything yet
+fn flood(args: &[&str]) -> (TestServer, TestClient) {
+    let server = TestServer::start_with_args(args);
+    let slow = server.subscriber("/topic/firehose", &[]);
+    let mut fast = server.subscriber("/topic/firehose", &[]);
+    let fast = thread::spawn(move || {
+        for i in 0..MESSAGES {
+            assert_eq!(&fast.recv().body[..8], format!("{:08}", i).as_bytes());
+        }
+    });
+
+    let mut sender = server.login();
+    for i in 0..MESSAGES {
+        let body = format!("{:08}", i) + &"x".repeat(BODY_SIZE - 8);
+        let mut headers = vec![("destination", "/topic/firehose")];
+        if i == MESSAGES - 1 {
+            headers.push(("receipt", "sent"));
+        }
+        sender.send("SEND", &headers, &body);
+    }
+    // The broker got through everything without waiting for the slow subscriber
+    assert_eq!(sender.recv().header("receipt-id"), Some("sent"));
+    // A subscriber that keeps up gets everything whatever the policy
+    assert!(fast.join().is_ok(), "Fast subscriber missed messages");
+    (server, slow)
+}
+
+// Read messages until nothing more arrives, returning their numbers
+fn read_all(client: &mut TestClient) -> Vec<usize> {
+    let mut numbers = Vec::new();
+    while client.has_data(Duration::from_millis(500)) {
+        let frame = client.recv();
+        if frame.command != "MESSAGE" {
+            break;
+        }
+        numbers.push(String::from_utf8_lossy(&frame.body[..8]).parse().unwrap());
+    }
+    numbers
+}
+
+// Read messages until the server gives up on the client, which it must do before sending all
+// of them
+fn assert_disconnected(client: &mut TestClient) {
+    let mut received = 0;
+    loop {
+        let frame = client.recv();
+        if frame.command == "ERROR" {
+            assert_eq!(frame.header("message"), Some("disconnected"));
+            break;
+        }
+        received += 1;
+    }
+    assert!(received < MESSAGES);
+}
+
+#[test]
+fn block_holds_messages_until_consumer_catches_up() {
+    let (_server, mut slow) = flood(&["--max-in-flight", "4", "--slow-consumer", "block"]);
+    assert_eq!(read_all(&mut slow), (0..MESSAGES).collect::<Vec<usize>>());
+}
+
+#[test]
+fn block_disconnects_consumer_whose_backlog_is_full() {
+    let (_server, mut slow) = flood(&["--max-in-flight", "4", "--max-backlog", "4",
+                                      "--slow-consumer", "block"]);
+    assert_disconnected(&mut slow);
+}
+
+#[test]
+fn drop_skips_oldest_topic_messages_when_backlog_is_full() {
+    let (_server, mut slow) = flood(&["--max-in-flight", "4", "--max-backlog", "4",
+                                      "--slow-consumer", "drop"]);
+    let received = read_all(&mut slow);
+    assert!(received.len() < MESSAGES, "Nothing was dropped");
+    // What does arrive is in order, and the newest messages made it
+    assert!(received.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", received);
+    assert_eq!(received.last(), Some(&(MESSAGES - 1)), "{:?}", received);
+}
+
+#[test]
+fn disconnect_closes_slow_consumer() {
+    let (_server, mut slow) = flood(&["--max-in-flight", "4", "--slow-consumer", "disconnect"]);
+    assert_disconnected(&mut slow);
+}
@@ -0,0 +1,49 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+
+use romp::client::configure_stream;
+use romp::config::Config;
+
+use common::TestServer;
+
+// Get both ends of a local TCP connection
+fn connected_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    (client, accepted)
+}
+
+#[test]
+fn nodelay_is_set_on_client_connections() {
+    let (_client, accepted) = connected_pair();
+
+    configure_stream(&accepted, &Config::new(), "test");
+    assert!(accepted.nodelay().unwrap());
+}
+
+#[test]
+fn nodelay_can_be_turned_off() {
+    let args = vec!["--no-nodelay".to_string()];
+    let config = Config::from_args(args.into_iter()).unwrap();
+    let (_client, accepted) = connected_pair();
+    accepted.set_nodelay(true).unwrap();
+
+    configure_stream(&accepted, &config, "test");
+    assert!(!accepted.nodelay().unwrap());
+}
+
+#[test]
+fn server_accepts_with_socket_options() {
+    let server = TestServer::start_with_args(&["--backlog", "16", "--no-reuse-address",
+                                                "--no-nodelay"]);
+    server.login();
+}
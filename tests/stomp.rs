@@ -0,0 +1,410 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::sync::Arc;
+
+use romp::stomp::{negotiate_version, negotiate_version_from, parse_frame, Frame, Header,
+                  StompCommand, StompVersion};
+use romp::stomp::parse::{parse_command_only, parse_frame_streaming, ParseError, ParseLimits};
+use romp::stomp::{is_reserved_header, ExtensionRegistry, ProtocolError, RESERVED_HEADERS,
+                  ROMP_HEADERS};
+use romp::auth::StaticCredentials;
+use romp::config::Config;
+
+use common::TestServer;
+
+#[test]
+fn frame_survives_a_round_trip() {
+    let frame = Frame::builder(StompCommand::Send)
+        .header("destination", "/queue/test")
+        .body(b"hello")
+        .build();
+
+    let bytes = frame.to_bytes();
+    let parsed = parse_frame(&mut Cursor::new(&bytes[..])).unwrap();
+    assert_eq!(parsed.command(), StompCommand::Send);
+    assert_eq!(parsed.header().get("destination"), Some(&"/queue/test".to_string()));
+    assert_eq!(parsed.body(), b"hello");
+}
+
+#[test]
+fn header_iterates_in_order() {
+    let mut header = Header::new();
+    header.set("destination", "/queue/test");
+    header.set("foo", "1");
+    header.set("foo", "2");
+
+    let pairs: Vec<(&str, &str)> = header.iter().collect();
+    assert_eq!(pairs, vec![("destination", "/queue/test"), ("foo", "1"), ("foo", "2")]);
+
+    let keys: Vec<&str> = (&header).into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec!["destination", "foo", "foo"]);
+
+    let owned: Vec<(String, String)> = header.into_iter().collect();
+    assert_eq!(owned.len(), 3);
+    assert_eq!(owned[2], ("foo".to_string(), "2".to_string()));
+}
+
+#[test]
+fn header_collects_from_pairs() {
+    let header: Header = vec![("destination", "/queue/test"), ("foo", "1"), ("foo", "2")]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    assert_eq!(header.get("destination"), Some(&"/queue/test".to_string()));
+    assert_eq!(header.get_all("foo"), vec!["1", "2"]);
+
+    let from_vec = Header::from(vec![("foo".to_string(), "1".to_string()),
+                                     ("foo".to_string(), "2".to_string()),
+                                     ("destination".to_string(), "/queue/test".to_string())]);
+    assert_eq!(header, from_vec);
+}
+
+#[test]
+fn replace_overwrites_every_value() {
+    let mut header = Header::new();
+    header.set("content-length", "5");
+    header.set("foo", "1");
+    header.set("content-length", "7");
+    header.replace("content-length", "3");
+    assert_eq!(header.get_all("content-length"), vec!["3"]);
+    assert_eq!(header.get("foo"), Some(&"1".to_string()));
+
+    // Replacing a key that isn't there just sets it
+    header.replace("bar", "2");
+    assert_eq!(header.get("bar"), Some(&"2".to_string()));
+}
+
+#[test]
+fn removed_header_is_gone() {
+    let mut header = Header::new();
+    header.set("foo", "1");
+    header.set("bar", "2");
+    header.set("foo", "3");
+    header.remove("foo");
+    assert_eq!(header.get("foo"), None);
+    assert_eq!(header.len(), 1);
+
+    // Removing a key that isn't there does nothing
+    header.remove("baz");
+    assert_eq!(header.get("bar"), Some(&"2".to_string()));
+}
+
+// A frame with the given command and headers, and no body
+fn frame_with(command: StompCommand, headers: &[(&str, &str)]) -> Frame {
+    let mut frame = Frame::from_command(command);
+    for &(key, value) in headers {
+        frame.header_mut().set(key, value);
+    }
+    frame
+}
+
+#[test]
+fn ack_and_nack_need_the_headers_of_their_version() {
+    use romp::stomp::StompVersion::*;
+    for &command in &[StompCommand::Ack, StompCommand::Nack] {
+        let by_id = frame_with(command, &[("id", "1")]);
+        let by_message = frame_with(command, &[("message-id", "1"), ("subscription", "0")]);
+        assert_eq!(by_id.validate(V1_2), Ok(()));
+        assert_eq!(by_message.validate(V1_2), Err(ProtocolError::MissingHeader(command, "id")));
+        assert_eq!(by_message.validate(V1_1), Ok(()));
+        assert_eq!(by_id.validate(V1_1), Err(ProtocolError::MissingHeader(command, "message-id")));
+        assert_eq!(frame_with(command, &[("message-id", "1")]).validate(V1_1),
+                   Err(ProtocolError::MissingHeader(command, "subscription")));
+    }
+    // 1.0 acknowledges by message-id alone and has no NACK at all
+    assert_eq!(frame_with(StompCommand::Ack, &[("message-id", "1")]).validate(V1_0), Ok(()));
+    assert_eq!(frame_with(StompCommand::Nack, &[("message-id", "1")]).validate(V1_0),
+               Err(ProtocolError::UnsupportedCommand(StompCommand::Nack, V1_0)));
+}
+
+#[test]
+fn subscribe_needs_an_id_after_1_0() {
+    use romp::stomp::StompVersion::*;
+    let without_id = frame_with(StompCommand::Subscribe, &[("destination", "/queue/a")]);
+    assert_eq!(without_id.validate(V1_0), Ok(()));
+    for &version in &[V1_1, V1_2] {
+        assert_eq!(without_id.validate(version),
+                   Err(ProtocolError::MissingHeader(StompCommand::Subscribe, "id")));
+    }
+    let without_destination = frame_with(StompCommand::Subscribe, &[("id", "0")]);
+    for &version in &[V1_0, V1_1, V1_2] {
+        assert_eq!(without_destination.validate(version),
+                   Err(ProtocolError::MissingHeader(StompCommand::Subscribe, "destination")));
+    }
+}
+
+#[test]
+fn negotiation_follows_the_supported_list() {
+    let accept = "1.0,1.1,1.2";
+    assert_eq!(negotiate_version(accept), Some(StompVersion::V1_2));
+
+    let older = [StompVersion::V1_0, StompVersion::V1_1];
+    assert_eq!(negotiate_version_from(accept, &older), Some(StompVersion::V1_1));
+    assert_eq!(negotiate_version_from("1.2", &older), None);
+
+    let all = [StompVersion::V1_0, StompVersion::V1_1, StompVersion::V1_2];
+    assert_eq!(negotiate_version_from("1.0, 1.1", &all), Some(StompVersion::V1_1));
+    assert_eq!(negotiate_version_from("2.0", &all), None);
+}
+
+#[test]
+fn large_body_streams_without_buffering() {
+    const BODY_SIZE: usize = 64 * 1024 * 1024;
+    let head = format!("SEND\ndestination:/queue/big\ncontent-length:{}\n\n", BODY_SIZE);
+    // The body is generated as it's read, so the test never holds it in memory either
+    let stream = Cursor::new(head.into_bytes())
+        .chain(io::repeat(b'x').take(BODY_SIZE as u64))
+        .chain(Cursor::new(&b"\0SEND\ndestination:/queue/small\n\nnext\0"[..]));
+    let mut reader = BufReader::new(stream);
+
+    let extensions = ExtensionRegistry::new();
+    let limits = ParseLimits::new();
+    {
+        let (frame, mut body) = parse_frame_streaming(&mut reader, &extensions, &limits).unwrap();
+        assert_eq!(frame.header().get("destination"), Some(&"/queue/big".to_string()));
+        assert_eq!(body.remaining(), BODY_SIZE);
+        assert_eq!(io::copy(&mut body, &mut io::sink()).unwrap(), BODY_SIZE as u64);
+        assert_eq!(body.remaining(), 0);
+    }
+
+    // The terminating NUL was consumed, so the next frame parses normally
+    let next = parse_frame(&mut reader).unwrap();
+    assert_eq!(next.header().get("destination"), Some(&"/queue/small".to_string()));
+    assert_eq!(next.body(), b"next");
+}
+
+// Stream the body of a frame into memory, or return the error the head or body gave
+fn stream_body(bytes: &[u8], limits: &ParseLimits) -> Result<Vec<u8>, String> {
+    let mut reader = Cursor::new(bytes);
+    let (_, mut body) = parse_frame_streaming(&mut reader, &ExtensionRegistry::new(), limits)
+        .map_err(|e| e.to_string())?;
+    let mut bytes = Vec::new();
+    body.read_to_end(&mut bytes).map_err(|e| format!("{:?}", e.kind()))?;
+    Ok(bytes)
+}
+
+#[test]
+fn streaming_a_body_checks_the_frame() {
+    let limits = ParseLimits::new();
+    assert_eq!(stream_body(b"SEND\ncontent-length:5\n\nhello\0", &limits),
+               Ok(b"hello".to_vec()));
+    assert_eq!(stream_body(b"SEND\ncontent-length:0\n\n\0", &limits), Ok(Vec::new()));
+
+    // Problems with the head are found before any of the body is read
+    assert_eq!(stream_body(b"SEND\n\nhello\0", &limits),
+               Err(ParseError::MalformedHeader("Streaming a body needs a content-length.")
+                   .to_string()));
+    assert_eq!(stream_body(b"BEGIN\ncontent-length:5\n\nhello\0", &limits),
+               Err(ParseError::BodyNotAllowed.to_string()));
+    let mut small = ParseLimits::new();
+    small.max_body_size = Some(4);
+    assert_eq!(stream_body(b"SEND\ncontent-length:5\n\nhello\0", &small),
+               Err(ParseError::FrameTooLarge("body exceeds maximum size").to_string()));
+
+    // Problems with the body come out of the reader
+    assert_eq!(stream_body(b"SEND\ncontent-length:3\n\nhello\0", &limits),
+               Err(String::from("InvalidData")));
+    assert_eq!(stream_body(b"SEND\ncontent-length:10\n\nhello\0", &limits),
+               Err(String::from("UnexpectedEof")));
+}
+
+#[test]
+fn frame_is_read_through_accessors() {
+    let mut frame = Frame::builder(StompCommand::Message)
+        .header("destination", "/topic/news")
+        .body(b"extra")
+        .build();
+    frame.header_mut().set("subscription", "0");
+
+    assert_eq!(frame.command(), StompCommand::Message);
+    assert_eq!(frame.header().get("destination"), Some(&"/topic/news".to_string()));
+    assert_eq!(frame.header().get("subscription"), Some(&"0".to_string()));
+    assert_eq!(frame.header().get("content-length"), Some(&"5".to_string()));
+    assert_eq!(frame.body(), b"extra");
+}
+
+#[test]
+fn lengths_come_from_the_header_and_the_body() {
+    let with_length = Frame::builder(StompCommand::Send)
+        .header("destination", "/queue/a")
+        .body(b"hello")
+        .build();
+    assert_eq!(with_length.content_length(), Some(5));
+    assert_eq!(with_length.body_len(), 5);
+
+    let without_length = Frame::builder(StompCommand::Send)
+        .header("destination", "/queue/a")
+        .body(b"hello")
+        .without_content_length()
+        .build();
+    assert_eq!(without_length.content_length(), None);
+    assert_eq!(without_length.body_len(), 5);
+
+    // The header is taken as it is, even when it disagrees with the body or isn't a number
+    let mut frame = Frame::with_body(StompCommand::Send, b"hi");
+    frame.header_mut().replace("content-length", "7");
+    assert_eq!((frame.content_length(), frame.body_len()), (Some(7), 2));
+    frame.header_mut().replace("content-length", "seven");
+    assert_eq!(frame.content_length(), None);
+}
+
+#[test]
+fn passcode_is_left_out_of_logged_frames() {
+    let frame = Frame::builder(StompCommand::Stomp)
+        .header("login", "guest")
+        .header("passcode", "hunter2")
+        .build();
+
+    let logged = format!("{:?}", frame);
+    assert!(!logged.contains("hunter2"), "{}", logged);
+    assert!(logged.contains("guest"), "{}", logged);
+}
+
+#[test]
+fn binary_body_is_logged_as_hex() {
+    let frame = Frame::builder(StompCommand::Send)
+        .header("destination", "/queue/a")
+        .body(&[0xff, 0x00, 0x10, 0x80])
+        .build();
+
+    let logged = format!("{:?}", frame);
+    assert!(logged.contains("<4 bytes: ff 00 10 80>"), "{}", logged);
+}
+
+#[test]
+fn constructed_frames_read_back_through_accessors() {
+    let frame = Frame::new();
+    assert_eq!(frame.command(), StompCommand::Error);
+    assert!(frame.header().is_empty());
+    assert_eq!(frame.body(), b"");
+    assert_eq!(Frame::default(), frame);
+
+    let frame = Frame::from_command(StompCommand::Receipt);
+    assert_eq!(frame.command(), StompCommand::Receipt);
+    assert!(frame.header().is_empty());
+    assert_eq!(frame.body(), b"");
+
+    let frame = Frame::with_body(StompCommand::Error, b"oops");
+    assert_eq!(frame.command(), StompCommand::Error);
+    assert_eq!(frame.header().get("content-length"), Some(&"4".to_string()));
+    assert_eq!(frame.header().get("content-type"), Some(&"text/plain".to_string()));
+    assert_eq!(frame.body(), b"oops");
+
+    // What the accessors return is what goes over the wire
+    let parsed = parse_frame(&mut Cursor::new(&frame.to_bytes()[..])).unwrap();
+    assert_eq!(parsed.command(), frame.command());
+    assert_eq!(parsed.header(), frame.header());
+    assert_eq!(parsed.body(), frame.body());
+}
+
+#[test]
+fn command_is_read_without_the_rest() {
+    let mut reader = Cursor::new(&b"\r\nSEND\r\ndestination:/queue/test\n\nhello\0"[..]);
+    assert_eq!(parse_command_only(&mut reader), Ok(StompCommand::Send));
+
+    // The headers and body are left where they were
+    let mut rest = String::new();
+    reader.read_line(&mut rest).unwrap();
+    assert_eq!(rest, "destination:/queue/test\n");
+
+    let mut reader = Cursor::new(&b"BOGUS\n\n\0"[..]);
+    assert_eq!(parse_command_only(&mut reader), Err(ParseError::InvalidCommand));
+}
+
+#[test]
+fn non_utf8_header_is_malformed() {
+    let mut reader = Cursor::new(&b"SEND\ndestination:/queue/\xff\n\n\0"[..]);
+    assert_eq!(parse_frame(&mut reader),
+               Err(ParseError::MalformedHeader("Header is not valid UTF-8.")));
+
+    let mut reader = Cursor::new(&b"SEND\n\xc3:value\n\n\0"[..]);
+    assert_eq!(parse_frame(&mut reader),
+               Err(ParseError::MalformedHeader("Header is not valid UTF-8.")));
+}
+
+#[test]
+fn reserved_headers_are_recognized() {
+    for key in RESERVED_HEADERS.iter().chain(ROMP_HEADERS.iter()) {
+        assert!(is_reserved_header(key), "{}", key);
+    }
+    for key in &["x-order", "priority", "Destination", ""] {
+        assert!(!is_reserved_header(key), "{}", key);
+    }
+}
+
+// Start a server that only lets in the given login
+fn server_with_login(login: &str, passcode: &str) -> TestServer {
+    let mut credentials = StaticCredentials::new();
+    credentials.add(login, passcode);
+    let mut config = Config::new();
+    config.port = 0;
+    config.authenticator = Arc::new(credentials);
+    TestServer::start_with_config(config)
+}
+
+// Connect with the given extra headers and return the reply
+fn connect_with(server: &TestServer, headers: &[(&str, &str)]) -> common::TestFrame {
+    let mut client = server.connect();
+    let mut all = vec![("accept-version", "1.2"), ("host", "localhost")];
+    all.extend_from_slice(headers);
+    client.send("CONNECT", &all, "");
+    client.recv()
+}
+
+#[test]
+fn correct_credentials_are_accepted() {
+    let server = server_with_login("guest", "secret");
+    let reply = connect_with(&server, &[("login", "guest"), ("passcode", "secret")]);
+    assert_eq!(reply.command, "CONNECTED");
+}
+
+#[test]
+fn wrong_credentials_are_rejected() {
+    let server = server_with_login("guest", "secret");
+    for headers in &[&[("login", "guest"), ("passcode", "wrong")][..],
+                     &[("login", "intruder"), ("passcode", "secret")][..],
+                     &[("login", "guest")][..],
+                     &[][..]] {
+        let reply = connect_with(&server, headers);
+        assert_eq!(reply.command, "ERROR", "{:?}", headers);
+        assert_eq!(reply.header("message"), Some("authentication failed"));
+    }
+}
+
+#[test]
+fn only_allowed_hosts_are_accepted() {
+    let server = TestServer::start_with_args(&["--allowed-host", "example.com"]);
+
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "example.com")], "");
+    assert_eq!(client.recv().command, "CONNECTED");
+
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "elsewhere.com")], "");
+    let reply = client.recv();
+    assert_eq!(reply.command, "ERROR");
+    assert_eq!(reply.header("message"), Some("unknown host"));
+
+    // A CONNECT has to say which host it wants, whether or not hosts are limited
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2")], "");
+    let reply = client.recv();
+    assert_eq!(reply.command, "ERROR");
+    assert_eq!(reply.header("message"), Some("malformed frame"));
+}
+
+#[test]
+fn any_host_is_accepted_when_hosts_are_not_limited() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    client.send("CONNECT", &[("accept-version", "1.2"), ("host", "anything.example")], "");
+    assert_eq!(client.recv().command, "CONNECTED");
+}
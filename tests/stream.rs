@@ -0,0 +1,381 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::collections::VecDeque;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use romp::client::{handle_client, BrokerSender, ClientEvent, ClientSender};
+use romp::client::stream::Stream;
+use romp::config::Config;
+use romp::stomp::{parse_frame, Frame, StompCommand, StompVersion};
+
+use common::wait_until;
+
+// Bytes going one way through an in-memory connection
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<(VecDeque<u8>, bool)>,     // Unread bytes, and whether the pipe is closed
+    ready: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.ready.notify_all();
+    }
+}
+
+// One end of an in-memory connection
+struct Duplex {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl Duplex {
+    // A connected pair of ends
+    fn pair() -> (Duplex, Duplex) {
+        let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>| Duplex {
+            incoming: incoming.clone(),
+            outgoing: outgoing.clone(),
+            read_timeout: Arc::new(Mutex::new(Some(Duration::from_secs(5)))),
+        };
+        (end(&a, &b), end(&b, &a))
+    }
+
+    // Stop taking bytes from the other end, which then fails to write like it would to a
+    // socket the peer has closed
+    fn stop_reading(&self) {
+        self.incoming.close();
+    }
+
+    // Stop sending to the other end, which then reads the end of the stream
+    fn stop_writing(&self) {
+        self.outgoing.close();
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.lock().unwrap().map(|t| Instant::now() + t);
+        let mut state = self.incoming.state.lock().unwrap();
+        while state.0.is_empty() && !state.1 {
+            state = match deadline {
+                Some(deadline) => {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    if wait == Duration::from_secs(0) {
+                        return Err(io::Error::from(ErrorKind::TimedOut));
+                    }
+                    self.incoming.ready.wait_timeout(state, wait).unwrap().0
+                },
+                None => self.incoming.ready.wait(state).unwrap(),
+            };
+        }
+        let n = buf.len().min(state.0.len());
+        for (i, b) in state.0.drain(..n).enumerate() {
+            buf[i] = b;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.1 {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        state.0.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for Duplex {
+    fn try_clone(&self) -> io::Result<Duplex> {
+        Ok(Duplex {
+            incoming: self.incoming.clone(),
+            outgoing: self.outgoing.clone(),
+            read_timeout: self.read_timeout.clone(),
+        })
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.incoming.close();
+        self.outgoing.close();
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+#[test]
+fn client_is_served_over_in_memory_stream() {
+    let (server_end, client_end) = Duplex::pair();
+    let (to_broker, from_client) = mpsc::channel();
+    let (to_client, client_rx) = mpsc::channel();
+    let out = ClientSender::new(to_client, "memory#1");
+    let writer_out = out.clone();
+    let client = thread::spawn(move || {
+        let to_broker = BrokerSender::new(1, to_broker);
+        handle_client(server_end, "1", to_broker, client_rx, writer_out, Config::new());
+    });
+
+    let mut writer = client_end.try_clone().unwrap();
+    let mut reader = BufReader::new(client_end);
+    writer.write_all(b"CONNECT\naccept-version:1.2\nhost:localhost\n\n\0").unwrap();
+    let connected = parse_frame(&mut reader).unwrap();
+    assert_eq!(connected.command(), StompCommand::Connected);
+    assert_eq!(connected.header().get("session"), Some(&"1".to_string()));
+
+    // Frames from the client go to the broker...
+    writer.write_all(b"SEND\ndestination:/queue/a\n\nhello\0").unwrap();
+    let send = match from_client.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ClientEvent::Frame(1, frame) => frame,
+        _ => panic!("expected a frame from client 1"),
+    };
+    assert_eq!(send.command(), StompCommand::Send);
+    assert_eq!(send.body(), b"hello");
+
+    // ...and frames from the broker go to the client
+    let message = Frame::builder(StompCommand::Message)
+        .header("destination", "/queue/a")
+        .header("message-id", "1")
+        .header("subscription", "0")
+        .body(b"hello")
+        .build();
+    out.send(message.clone()).unwrap();
+    assert_eq!(parse_frame(&mut reader).unwrap(), message);
+
+    writer.write_all(b"DISCONNECT\n\n\0").unwrap();
+    match from_client.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ClientEvent::Frame(1, frame) => assert_eq!(frame.command(), StompCommand::Disconnect),
+        _ => panic!("expected a frame from client 1"),
+    }
+    // The client is done once the broker lets go of it, and says so on its way out
+    drop(out);
+    client.join().unwrap();
+    match from_client.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ClientEvent::Closed(1) => { },
+        _ => panic!("expected client 1 to close"),
+    }
+}
+
+#[test]
+fn invalid_frame_is_skipped_but_counts_as_written() {
+    let (server_end, client_end) = Duplex::pair();
+    let (to_broker, _from_client) = mpsc::channel();
+    let (to_client, client_rx) = mpsc::channel();
+    let out = ClientSender::new(to_client, "memory#2");
+    let writer_out = out.clone();
+    let client = thread::spawn(move || {
+        let to_broker = BrokerSender::new(2, to_broker);
+        handle_client(server_end, "2", to_broker, client_rx, writer_out, Config::new());
+    });
+
+    let mut writer = client_end.try_clone().unwrap();
+    let mut reader = BufReader::new(client_end);
+    writer.write_all(b"CONNECT\naccept-version:1.2\nhost:localhost\n\n\0").unwrap();
+    assert_eq!(parse_frame(&mut reader).unwrap().command(), StompCommand::Connected);
+
+    // A MESSAGE without a destination, message-id or subscription can't be sent
+    let ticket = out.send_tracked(Frame::with_body(StompCommand::Message, b"broken")).unwrap();
+    let receipt = Frame::builder(StompCommand::Receipt).header("receipt-id", "after").build();
+    out.send(receipt.clone()).unwrap();
+    // The client gets the next frame, and nothing waiting on the invalid one is left hanging
+    assert_eq!(parse_frame(&mut reader).unwrap(), receipt);
+    assert!(wait_until(|| out.is_written(ticket) && out.in_flight() == 0));
+    assert_eq!(out.memory(), 0);
+    assert!(!out.is_closed());
+
+    writer.write_all(b"DISCONNECT\n\n\0").unwrap();
+    drop(out);
+    client.join().unwrap();
+}
+
+#[test]
+fn frames_are_checked_against_the_negotiated_version() {
+    let (server_end, client_end) = Duplex::pair();
+    let (to_broker, _from_client) = mpsc::channel();
+    let (to_client, client_rx) = mpsc::channel();
+    let out = ClientSender::new(to_client, "memory#3");
+    let writer_out = out.clone();
+    let client = thread::spawn(move || {
+        let to_broker = BrokerSender::new(3, to_broker);
+        handle_client(server_end, "3", to_broker, client_rx, writer_out, Config::new());
+    });
+
+    let mut writer = client_end.try_clone().unwrap();
+    let mut reader = BufReader::new(client_end);
+    writer.write_all(b"CONNECT\naccept-version:1.0,1.2\nhost:localhost\n\n\0").unwrap();
+    let connected = parse_frame(&mut reader).unwrap();
+    assert_eq!(connected.header().get("version"), Some(&"1.2".to_string()));
+    assert_eq!(out.version(), StompVersion::V1_2);
+
+    // A 1.0 MESSAGE has no subscription header, which 1.2 requires
+    let message = |body: &[u8]| Frame::builder(StompCommand::Message)
+        .header("destination", "/queue/a")
+        .header("message-id", "1")
+        .body(body)
+        .build();
+    let ticket = out.send_tracked(message(b"skipped")).unwrap();
+    assert!(wait_until(|| out.is_written(ticket)));
+    out.set_version(StompVersion::V1_0);
+    out.send(message(b"sent")).unwrap();
+    assert_eq!(parse_frame(&mut reader).unwrap(), message(b"sent"));
+
+    writer.write_all(b"DISCONNECT\n\n\0").unwrap();
+    drop(out);
+    client.join().unwrap();
+}
+
+#[test]
+fn write_to_a_closed_connection_ends_the_client() {
+    let (server_end, client_end) = Duplex::pair();
+    let (to_broker, from_client) = mpsc::channel();
+    let (to_client, client_rx) = mpsc::channel();
+    let out = ClientSender::new(to_client, "memory#4");
+    let writer_out = out.clone();
+    let client = thread::spawn(move || {
+        let to_broker = BrokerSender::new(4, to_broker);
+        handle_client(server_end, "4", to_broker, client_rx, writer_out, Config::new());
+    });
+
+    let mut writer = client_end.try_clone().unwrap();
+    let mut reader = BufReader::new(client_end.try_clone().unwrap());
+    writer.write_all(b"CONNECT\naccept-version:1.2\nhost:localhost\n\n\0").unwrap();
+    assert_eq!(parse_frame(&mut reader).unwrap().command(), StompCommand::Connected);
+
+    // The client goes away while the broker still has something for it
+    client_end.stop_reading();
+    let receipt = Frame::builder(StompCommand::Receipt).header("receipt-id", "lost").build();
+    out.send(receipt).unwrap();
+
+    // The failed write closes the connection, and the client thread ends without panicking
+    assert!(wait_until(|| out.is_closed()));
+    match from_client.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ClientEvent::Closed(4) => { },
+        _ => panic!("expected client 4 to close"),
+    }
+    drop(out);
+    client.join().unwrap();
+}
+
+// Connect a client over an in-memory stream, send it some bytes and hang up, returning
+// everything the server wrote after CONNECTED and what the broker heard
+fn hang_up_after(bytes: &[u8]) -> (Vec<u8>, Vec<ClientEvent>) {
+    let (server_end, client_end) = Duplex::pair();
+    let (to_broker, from_client) = mpsc::channel();
+    let (to_client, client_rx) = mpsc::channel();
+    let out = ClientSender::new(to_client, "memory#5");
+    let writer_out = out.clone();
+    let client = thread::spawn(move || {
+        let to_broker = BrokerSender::new(5, to_broker);
+        handle_client(server_end, "5", to_broker, client_rx, writer_out, Config::new());
+    });
+
+    let mut writer = client_end.try_clone().unwrap();
+    let mut reader = BufReader::new(client_end.try_clone().unwrap());
+    writer.write_all(b"CONNECT\naccept-version:1.2\nhost:localhost\n\n\0").unwrap();
+    assert_eq!(parse_frame(&mut reader).unwrap().command(), StompCommand::Connected);
+    writer.write_all(bytes).unwrap();
+    client_end.stop_writing();
+
+    // The broker sees the frames that were whole, then the client closing
+    let mut events = Vec::new();
+    loop {
+        let event = from_client.recv_timeout(Duration::from_secs(5)).unwrap();
+        let closed = matches!(event, ClientEvent::Closed(_));
+        events.push(event);
+        if closed {
+            break;
+        }
+    }
+    drop(out);
+    client.join().unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    (rest, events)
+}
+
+#[test]
+fn hanging_up_mid_command_closes_quietly() {
+    let (written, events) = hang_up_after(b"SEN");
+    assert!(written.is_empty(), "{:?}", String::from_utf8_lossy(&written));
+    assert!(matches!(events[..], [ClientEvent::Closed(5)]));
+}
+
+#[test]
+fn hanging_up_between_frames_closes_quietly() {
+    let (written, events) = hang_up_after(b"SEND\ndestination:/queue/a\n\nhello\0\n");
+    assert!(written.is_empty(), "{:?}", String::from_utf8_lossy(&written));
+    match events[..] {
+        [ClientEvent::Frame(5, ref frame), ClientEvent::Closed(5)] => {
+            assert_eq!(frame.body(), b"hello");
+        },
+        _ => panic!("expected one frame, then client 5 closing"),
+    }
+}
+
+#[test]
+fn client_is_told_when_the_broker_is_gone() {
+    let (server_end, client_end) = Duplex::pair();
+    let (to_broker, from_client) = mpsc::channel();
+    let (to_client, client_rx) = mpsc::channel();
+    let out = ClientSender::new(to_client, "memory#6");
+    let writer_out = out.clone();
+    let client = thread::spawn(move || {
+        let to_broker = BrokerSender::new(6, to_broker);
+        handle_client(server_end, "6", to_broker, client_rx, writer_out, Config::new());
+    });
+
+    let mut writer = client_end.try_clone().unwrap();
+    let mut reader = BufReader::new(client_end);
+    writer.write_all(b"CONNECT\naccept-version:1.2\nhost:localhost\n\n\0").unwrap();
+    assert_eq!(parse_frame(&mut reader).unwrap().command(), StompCommand::Connected);
+
+    // The broker goes away, e.g. because the server is shutting down
+    drop(from_client);
+    writer.write_all(b"SEND\ndestination:/queue/a\n\nhello\0").unwrap();
+    let error = parse_frame(&mut reader).unwrap();
+    assert_eq!(error.command(), StompCommand::Error);
+    assert_eq!(error.header().get("message"), Some(&"server unavailable".to_string()));
+
+    // The connection is closed and the client thread ends without panicking
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+    drop(out);
+    client.join().unwrap();
+}
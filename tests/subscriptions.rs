@@ -0,0 +1,99 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+mod common;
+
+use std::sync::Arc;
+
+use romp::broker::Broker;
+use romp::config::Config;
+use romp::metrics::Metrics;
+use romp::stomp::{Frame, StompCommand};
+
+use common::{add_client, TestServer};
+
+#[test]
+fn exclusive_consumer_keeps_others_out() {
+    let server = TestServer::start_with_args(&["--wildcards"]);
+    let exclusive = [("romp-exclusive", "true")];
+
+    let mut first = server.login();
+    assert_eq!(first.try_subscribe("/queue/jobs", &exclusive).command, "RECEIPT");
+
+    // Neither a plain subscription nor a pattern that matches the queue gets in
+    assert_eq!(server.login().try_subscribe("/queue/jobs", &[]).command, "ERROR");
+    assert_eq!(server.login().try_subscribe("/queue/*", &[]).command, "ERROR");
+    assert_eq!(server.login().try_subscribe("/queue/other", &[]).command, "RECEIPT");
+
+    // Once the exclusive consumer leaves, anyone can subscribe
+    first.send("UNSUBSCRIBE", &[("id", "0"), ("receipt", "unsub")], "");
+    assert_eq!(first.recv().command, "RECEIPT");
+    assert_eq!(server.login().try_subscribe("/queue/jobs", &[]).command, "RECEIPT");
+}
+
+#[test]
+fn exclusive_consumer_waits_for_pattern_to_leave() {
+    let server = TestServer::start_with_args(&["--wildcards"]);
+    let exclusive = [("romp-exclusive", "true")];
+
+    let mut pattern = server.login();
+    assert_eq!(pattern.try_subscribe("/queue/*", &[]).command, "RECEIPT");
+    assert_eq!(server.login().try_subscribe("/queue/jobs", &exclusive).command, "ERROR");
+
+    pattern.send("UNSUBSCRIBE", &[("id", "0"), ("receipt", "unsub")], "");
+    assert_eq!(pattern.recv().command, "RECEIPT");
+    assert_eq!(server.login().try_subscribe("/queue/jobs", &exclusive).command, "RECEIPT");
+}
+
+#[test]
+fn subscribers_per_destination_are_limited() {
+    let server = TestServer::start_with_args(&["--max-subscribers", "2"]);
+
+    let mut first = server.login();
+    assert_eq!(first.try_subscribe("/topic/busy", &[]).command, "RECEIPT");
+    let mut second = server.login();
+    assert_eq!(second.try_subscribe("/topic/busy", &[]).command, "RECEIPT");
+
+    let mut third = server.login();
+    third.send("SUBSCRIBE", &[("id", "0"), ("destination", "/topic/busy")], "");
+    let error = third.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.body, b"Too many subscribers for destination.");
+    // Other destinations have their own limit
+    assert_eq!(server.login().try_subscribe("/topic/quiet", &[]).command, "RECEIPT");
+
+    // A place opens up when a subscriber leaves
+    first.send("UNSUBSCRIBE", &[("id", "0"), ("receipt", "unsub")], "");
+    assert_eq!(first.recv().command, "RECEIPT");
+    assert_eq!(server.login().try_subscribe("/topic/busy", &[]).command, "RECEIPT");
+}
+
+#[test]
+fn has_subscribers_follows_subscriptions() {
+    let mut config = Config::new();
+    config.wildcard_subscriptions = true;
+    let mut broker = Broker::new(config, Arc::new(Metrics::new()));
+    let _rx = add_client(&mut broker, 1);
+    let subscribe = |id: &str, destination: &str| Frame::builder(StompCommand::Subscribe)
+        .header("id", id)
+        .header("destination", destination)
+        .build();
+
+    assert!(!broker.has_subscribers("/topic/prices"));
+    broker.handle_frame(1, subscribe("0", "/topic/prices"));
+    assert!(broker.has_subscribers("/topic/prices"));
+
+    // A pattern counts for every destination it matches, and only those
+    assert!(!broker.has_subscribers("/topic/news.sport"));
+    broker.handle_frame(1, subscribe("1", "/topic/news.*"));
+    assert!(broker.has_subscribers("/topic/news.sport"));
+    assert!(!broker.has_subscribers("/topic/news.sport.football"));
+    assert!(!broker.has_subscribers("/topic/weather"));
+
+    broker.handle_frame(1, Frame::builder(StompCommand::Unsubscribe).header("id", "0").build());
+    assert!(!broker.has_subscribers("/topic/prices"));
+}
@@ -0,0 +1,52 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use std::time::Duration;
+
+use common::TestServer;
+
+// Bigger than the socket buffers between the server and a client, so writing a message this
+// size can't finish until the client reads it
+const BODY_SIZE: usize = 16 * 1024 * 1024;
+
+#[test]
+fn sync_receipt_waits_for_subscriber_write() {
+    let server = TestServer::start();
+
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/topic/big"),
+                                   ("receipt", "sub")], "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    let mut sender = server.login();
+    let body = "x".repeat(BODY_SIZE);
+    sender.send("SEND", &[("destination", "/topic/big"), ("romp-sync", "true"),
+                          ("receipt", "sent")], &body);
+
+    // The subscriber isn't reading, so the write is stuck; other clients are still served
+    let mut other = server.login();
+    other.send("SUBSCRIBE", &[("id", "0"), ("destination", "/topic/other"),
+                              ("receipt", "other")], "");
+    assert_eq!(other.recv().header("receipt-id"), Some("other"));
+    assert!(!sender.has_data(Duration::from_millis(200)), "Receipt came before the write");
+
+    let message = subscriber.recv();
+    assert_eq!(message.body.len(), BODY_SIZE);
+    let receipt = sender.recv();
+    assert_eq!(receipt.command, "RECEIPT");
+    assert_eq!(receipt.header("receipt-id"), Some("sent"));
+}
+
+#[test]
+fn sync_receipt_with_no_subscribers_is_sent() {
+    let server = TestServer::start();
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/topic/empty"), ("romp-sync", "true"),
+                          ("receipt", "sent")], "hello");
+    assert_eq!(sender.recv().header("receipt-id"), Some("sent"));
+}
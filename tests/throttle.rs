@@ -0,0 +1,60 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+
+use std::time::{Duration, Instant};
+
+use romp::client::throttle::SendThrottle;
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+#[test]
+fn burst_up_to_the_rate_goes_straight_through() {
+    let start = Instant::now();
+    let mut throttle = SendThrottle::new(10, start);
+    for _ in 0..10 {
+        assert_eq!(throttle.delay(start), ms(0));
+    }
+    // The bucket is empty, so the next message waits for a token to come back
+    assert_eq!(throttle.delay(start), ms(100));
+    assert_eq!(throttle.delay(start), ms(200));
+}
+
+#[test]
+fn tokens_refill_with_time() {
+    let start = Instant::now();
+    let mut throttle = SendThrottle::new(10, start);
+    for _ in 0..10 {
+        throttle.delay(start);
+    }
+    // 300ms buys three more messages
+    for _ in 0..3 {
+        assert_eq!(throttle.delay(start + ms(300)), ms(0));
+    }
+    assert_eq!(throttle.delay(start + ms(300)), ms(100));
+}
+
+#[test]
+fn refill_is_capped_at_one_seconds_worth() {
+    let start = Instant::now();
+    let mut throttle = SendThrottle::new(10, start);
+    let later = start + Duration::from_secs(60);
+    for _ in 0..10 {
+        assert_eq!(throttle.delay(later), ms(0));
+    }
+    assert_eq!(throttle.delay(later), ms(100));
+}
+
+#[test]
+fn steady_rate_under_the_limit_is_never_held_up() {
+    let start = Instant::now();
+    let mut throttle = SendThrottle::new(10, start);
+    for i in 0..100 {
+        assert_eq!(throttle.delay(start + ms(100 * i)), ms(0));
+    }
+}
@@ -0,0 +1,53 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use common::TestServer;
+
+#[test]
+fn stall_mid_frame_gets_an_error_when_enabled() {
+    let server = TestServer::start_with_args(&["--read-timeout", "1", "--error-on-read-timeout"]);
+    let mut client = server.login();
+    // Half a frame, then nothing
+    client.send_raw(b"SEND\ndestination:/queue/a\n");
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.body, b"read timeout while parsing frame");
+}
+
+#[test]
+fn stall_mid_frame_closes_quietly_by_default() {
+    let server = TestServer::start_with_args(&["--read-timeout", "1"]);
+    let mut client = server.login();
+    client.send_raw(b"SEND\ndestination:/queue/a\n");
+    // The connection is closed without anything being written to it
+    assert!(!client.has_data(Duration::from_secs(3)));
+}
+
+#[test]
+fn idle_connection_is_closed_with_an_error() {
+    let server = TestServer::start_with_args(&["--idle-timeout", "1"]);
+    let mut client = server.login();
+    let error = client.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("message"), Some("idle timeout"));
+    assert_eq!(error.body, b"Connection was idle for too long.");
+}
+
+#[test]
+fn frames_keep_a_connection_from_going_idle() {
+    let server = TestServer::start_with_args(&["--idle-timeout", "1"]);
+    let mut client = server.login();
+    for i in 0..4 {
+        thread::sleep(Duration::from_millis(400));
+        let receipt = i.to_string();
+        client.send("BEGIN", &[("transaction", &receipt), ("receipt", &receipt)], "");
+        assert_eq!(client.recv().header("receipt-id"), Some(&receipt[..]));
+    }
+}
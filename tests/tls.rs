@@ -0,0 +1,135 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+extern crate romp;
+extern crate rustls;
+extern crate rustls_pemfile;
+
+mod common;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls::crypto::ring;
+use rustls::pki_types::ServerName;
+
+use romp::config::Config;
+use romp::server::Server;
+
+use common::TestServer;
+
+// One of the test certificate files
+fn cert_file(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("certs").join(name)
+}
+
+// Start a server that speaks TLS with the test certificate
+fn start_tls() -> TestServer {
+    let cert = cert_file("cert.pem");
+    let key = cert_file("key.pem");
+    TestServer::start_with_args(&["--tls-cert", cert.to_str().unwrap(),
+                                  "--tls-key", key.to_str().unwrap()])
+}
+
+// A STOMP client talking to the server over TLS, trusting only the test CA
+struct TlsClient {
+    stream: StreamOwned<ClientConnection, TcpStream>,
+}
+
+impl TlsClient {
+    fn connect(addr: SocketAddr) -> TlsClient {
+        let mut roots = RootCertStore::empty();
+        let mut ca = BufReader::new(File::open(cert_file("ca.pem")).unwrap());
+        for cert in rustls_pemfile::certs(&mut ca) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from("localhost").unwrap();
+        let session = ClientConnection::new(Arc::new(config), name).unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        TlsClient { stream: StreamOwned::new(session, socket) }
+    }
+
+    fn send(&mut self, frame: &str) {
+        self.stream.write_all(frame.as_bytes()).unwrap();
+        self.stream.flush().unwrap();
+    }
+
+    // Read the next frame, up to its NUL, skipping heart-beats
+    fn recv(&mut self) -> String {
+        let mut frame = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            self.stream.read_exact(&mut byte).expect("Timed out waiting for a frame");
+            match byte[0] {
+                0 => break,
+                b'\n' if frame.is_empty() => {},
+                b => frame.push(b),
+            }
+        }
+        String::from_utf8(frame).unwrap()
+    }
+}
+
+#[test]
+fn tls_clients_exchange_messages() {
+    let server = start_tls();
+
+    let mut subscriber = TlsClient::connect(server.addr);
+    subscriber.send("CONNECT\naccept-version:1.2\nhost:localhost\n\n\0");
+    assert!(subscriber.recv().starts_with("CONNECTED\r\nversion:1.2\r\n"));
+    subscriber.send("SUBSCRIBE\nid:0\ndestination:/queue/secret\nreceipt:sub\n\n\0");
+    assert!(subscriber.recv().starts_with("RECEIPT\r\nreceipt-id:sub\r\n"));
+
+    // The subscriber's reader is waiting on the socket while the message is written to it
+    let mut sender = TlsClient::connect(server.addr);
+    sender.send("CONNECT\naccept-version:1.2\nhost:localhost\n\n\0");
+    assert!(sender.recv().starts_with("CONNECTED"));
+    sender.send("SEND\ndestination:/queue/secret\n\npasscode inside\0");
+
+    let message = subscriber.recv();
+    assert!(message.starts_with("MESSAGE\r\n"), "{}", message);
+    assert!(message.ends_with("\r\n\r\npasscode inside"), "{}", message);
+}
+
+#[test]
+fn plain_clients_get_nothing_from_a_tls_port() {
+    let server = start_tls();
+
+    // The server takes the frame for a broken TLS handshake and hangs up
+    let mut response = Vec::new();
+    let mut stream = TcpStream::connect(server.addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"CONNECT\naccept-version:1.2\nhost:localhost\n\n\0").unwrap();
+    let _ = stream.read_to_end(&mut response);
+    assert!(!String::from_utf8_lossy(&response).contains("CONNECTED"));
+}
+
+#[test]
+fn missing_certificate_stops_the_server_starting() {
+    let mut config = Config::new();
+    config.port = 0;
+    config.tls_cert = Some(cert_file("missing.pem"));
+    config.tls_key = Some(cert_file("key.pem"));
+    assert!(Server::bind(config).is_err());
+
+    // A key that isn't a key is no better
+    let mut config = Config::new();
+    config.port = 0;
+    config.tls_cert = Some(cert_file("cert.pem"));
+    config.tls_key = Some(cert_file("ca.pem"));
+    assert!(Server::bind(config).is_err());
+}
@@ -0,0 +1,57 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use common::{TestClient, TestFrame, TestServer};
+
+// Subscribe with client acks and get one message sent to the queue
+fn receive_unacked(server: &TestServer) -> (TestClient, TestFrame) {
+    let mut subscriber = server.login();
+    subscriber.send("SUBSCRIBE", &[("id", "0"), ("destination", "/queue/test"),
+                                   ("ack", "client-individual"), ("receipt", "sub")], "");
+    assert_eq!(subscriber.recv().command, "RECEIPT");
+
+    let mut sender = server.login();
+    sender.send("SEND", &[("destination", "/queue/test")], "hello");
+    let message = subscriber.recv();
+    assert_eq!(message.command, "MESSAGE");
+    (subscriber, message)
+}
+
+#[test]
+fn aborted_ack_leaves_message_unacked() {
+    let server = TestServer::start();
+    let (mut subscriber, message) = receive_unacked(&server);
+    let ack = message.header("ack").unwrap();
+
+    subscriber.send("BEGIN", &[("transaction", "tx1")], "");
+    subscriber.send("ACK", &[("id", ack), ("transaction", "tx1")], "");
+    subscriber.send("ABORT", &[("transaction", "tx1")], "");
+
+    // The message is still waiting on an acknowledgement, so a NACK sends it around again
+    subscriber.send("NACK", &[("id", ack)], "");
+    let redelivered = subscriber.recv();
+    assert_eq!(redelivered.command, "MESSAGE");
+    assert_eq!(redelivered.header("message-id"), message.header("message-id"));
+    assert_eq!(redelivered.header("redelivery-count"), Some("1"));
+}
+
+#[test]
+fn committed_ack_takes_effect() {
+    let server = TestServer::start();
+    let (mut subscriber, message) = receive_unacked(&server);
+    let ack = message.header("ack").unwrap();
+
+    subscriber.send("BEGIN", &[("transaction", "tx1")], "");
+    subscriber.send("ACK", &[("id", ack), ("transaction", "tx1")], "");
+    subscriber.send("COMMIT", &[("transaction", "tx1"), ("receipt", "commit")], "");
+    assert_eq!(subscriber.recv().header("receipt-id"), Some("commit"));
+
+    subscriber.send("ACK", &[("id", ack)], "");
+    let error = subscriber.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.body, b"No message with that ack id.");
+}
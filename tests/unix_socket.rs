@@ -0,0 +1,93 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+#![cfg(unix)]
+
+extern crate romp;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use romp::config::Config;
+use romp::server::Server;
+
+use common::TestServer;
+
+// A socket path no other test (or test run) will use
+fn socket_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("romp-{}-{}.sock", process::id(), name));
+    // Clear out anything left over from an earlier run
+    if path.exists() {
+        fs::remove_file(&path).unwrap();
+    }
+    path
+}
+
+// Configuration for a server that also listens on a Unix socket
+fn unix_config(path: &Path) -> Config {
+    let mut config = Config::new();
+    config.port = 0;
+    config.unix_socket = Some(path.to_path_buf());
+    config
+}
+
+// Connect over the Unix socket and return the command of the server's first reply
+fn connect(path: &Path) -> String {
+    let mut stream = UnixStream::connect(path).expect("Failed to connect");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"CONNECT\naccept-version:1.2\nhost:localhost\n\n\0").unwrap();
+    let mut command = String::new();
+    BufReader::new(stream).read_line(&mut command).unwrap();
+    String::from(command.trim_end())
+}
+
+#[test]
+fn clients_connect_over_unix_socket() {
+    let path = socket_path("connect");
+    let mut server = TestServer::start_with_config(unix_config(&path));
+    assert_eq!(connect(&path), "CONNECTED");
+
+    // The socket file is removed when the server stops
+    assert!(server.stop(), "Server didn't stop after shutdown was signaled");
+    assert!(!path.exists());
+}
+
+#[test]
+fn stale_socket_file_is_replaced() {
+    let path = socket_path("stale");
+    // Dropping a listener leaves its socket file behind with nothing listening on it
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let _server = TestServer::start_with_config(unix_config(&path));
+    assert_eq!(connect(&path), "CONNECTED");
+}
+
+#[test]
+fn socket_in_use_is_an_error() {
+    let path = socket_path("in-use");
+    let _listener = UnixListener::bind(&path).unwrap();
+
+    let error = Server::bind(unix_config(&path)).err().expect("Bound a socket that's in use");
+    assert!(error.contains("Another server is listening"), "{}", error);
+}
+
+#[test]
+fn file_that_is_not_a_socket_is_an_error() {
+    let path = socket_path("not-a-socket");
+    fs::write(&path, b"data").unwrap();
+
+    let error = Server::bind(unix_config(&path)).err().expect("Bound over a regular file");
+    assert!(error.contains("isn't a socket"), "{}", error);
+    assert_eq!(fs::read(&path).unwrap(), b"data");
+    fs::remove_file(&path).unwrap();
+}
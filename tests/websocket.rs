@@ -0,0 +1,145 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use common::TestServer;
+
+// Sample key and the answer to it, from RFC 6455
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+// Just enough of a WebSocket client to carry STOMP frames
+struct WsClient {
+    stream: TcpStream,
+    received: Vec<u8>,      // Payload bytes not yet returned as a frame
+    opcodes: Vec<u8>,       // Opcode of each message received
+}
+
+impl WsClient {
+    // Connect and upgrade to WebSocket with the STOMP subprotocol
+    fn connect(addr: SocketAddr) -> WsClient {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let request = format!("GET /stomp HTTP/1.1\r\n\
+                               Host: localhost\r\n\
+                               Upgrade: websocket\r\n\
+                               Connection: Upgrade\r\n\
+                               Sec-WebSocket-Key: {}\r\n\
+                               Sec-WebSocket-Protocol: v12.stomp\r\n\
+                               Sec-WebSocket-Version: 13\r\n\r\n", KEY);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = Vec::new();
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+        assert!(response.contains(&format!("Sec-WebSocket-Accept: {}\r\n", ACCEPT)));
+        assert!(response.contains("Sec-WebSocket-Protocol: v12.stomp\r\n"));
+        WsClient {
+            stream,
+            received: Vec::new(),
+            opcodes: Vec::new(),
+        }
+    }
+
+    // Send a STOMP frame as one masked text message
+    fn send(&mut self, frame: &str) {
+        self.send_message(0x1, frame.as_bytes());
+    }
+
+    // Send a STOMP frame as one masked message with the given opcode
+    fn send_message(&mut self, opcode: u8, payload: &[u8]) {
+        assert!(payload.len() < 126);
+        let mut message = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        message.extend_from_slice(&MASK);
+        message.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        self.stream.write_all(&message).unwrap();
+    }
+
+    // Read messages until a whole text STOMP frame has arrived, and return it without its NUL
+    fn recv(&mut self) -> String {
+        let frame = String::from_utf8(self.recv_bytes()).unwrap();
+        String::from(frame.trim_start_matches(['\r', '\n']))
+    }
+
+    // Read messages until a whole STOMP frame has arrived, and return it without its NUL
+    fn recv_bytes(&mut self) -> Vec<u8> {
+        loop {
+            if let Some(end) = self.received.iter().position(|&b| b == 0) {
+                let mut frame: Vec<u8> = self.received.drain(..=end).collect();
+                frame.pop();
+                return frame;
+            }
+            let mut header = [0u8; 2];
+            self.stream.read_exact(&mut header).unwrap();
+            self.opcodes.push(header[0] & 0x0F);
+            assert_eq!(header[1] & 0x80, 0, "Server messages aren't masked");
+            let length = match header[1] & 0x7F {
+                126 => {
+                    let mut ext = [0u8; 2];
+                    self.stream.read_exact(&mut ext).unwrap();
+                    u16::from_be_bytes(ext) as usize
+                },
+                127 => panic!("Unexpectedly large message"),
+                n => n as usize,
+            };
+            let mut payload = vec![0u8; length];
+            self.stream.read_exact(&mut payload).unwrap();
+            self.received.extend(payload);
+        }
+    }
+}
+
+#[test]
+fn websocket_client_exchanges_frames() {
+    let server = TestServer::start_with_args(&["--ws-port", "0"]);
+    let mut client = WsClient::connect(server.ws_addr.expect("No WebSocket listener"));
+
+    client.send("CONNECT\naccept-version:1.2\nhost:localhost\n\n\0");
+    let connected = client.recv();
+    assert!(connected.starts_with("CONNECTED\r\n"), "{}", connected);
+
+    client.send("SUBSCRIBE\nid:0\ndestination:/topic/ws\nreceipt:sub\n\n\0");
+    assert!(client.recv().starts_with("RECEIPT\r\n"));
+    client.send("SEND\ndestination:/topic/ws\n\nhello\0");
+    let message = client.recv();
+    assert!(message.starts_with("MESSAGE\r\n"), "{}", message);
+    assert!(message.ends_with("\r\n\r\nhello"), "{}", message);
+    // Text goes out as text messages
+    assert!(client.opcodes.iter().all(|&opcode| opcode == 0x1), "{:?}", client.opcodes);
+}
+
+#[test]
+fn binary_body_goes_out_as_binary_message() {
+    let server = TestServer::start_with_args(&["--ws-port", "0"]);
+    let mut client = WsClient::connect(server.ws_addr.expect("No WebSocket listener"));
+
+    client.send("CONNECT\naccept-version:1.2\nhost:localhost\n\n\0");
+    assert!(client.recv().starts_with("CONNECTED\r\n"));
+    client.send("SUBSCRIBE\nid:0\ndestination:/topic/ws\nreceipt:sub\n\n\0");
+    assert!(client.recv().starts_with("RECEIPT\r\n"));
+
+    client.send_message(0x2, b"SEND\ndestination:/topic/ws\ncontent-length:2\n\n\xff\xfe\0");
+    let message = client.recv_bytes();
+    assert!(message.ends_with(b"\r\n\r\n\xff\xfe"), "{:?}", message);
+    assert_eq!(client.opcodes.last(), Some(&0x2));
+}
+
+#[test]
+fn shutdown_wakes_websocket_listener_on_any_port() {
+    // With port 0 the WebSocket listener's port is only known once it's bound
+    let mut server = TestServer::start_with_args(&["--ws-port", "0"]);
+    assert!(server.stop(), "Server didn't stop after shutdown was signaled");
+}
@@ -0,0 +1,73 @@
+/*
+ * Copyright (C) 2016 Peter Beard
+ * This file is part of Romp, the simple Rust STOMP server
+ * Licensed under the GPLv3, see the LICENSE file for details
+ */
+mod common;
+
+use std::fs;
+
+use common::{wait_until, TestServer};
+
+// Number of threads in this process, where the platform makes it easy to find out
+#[cfg(target_os = "linux")]
+fn thread_count() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("Threads:"))?;
+    line["Threads:".len()..].trim().parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_count() -> Option<usize> {
+    None
+}
+
+#[test]
+fn connections_beyond_the_workers_are_refused() {
+    let server = TestServer::start_with_args(&["--workers", "2"]);
+    let mut first = server.login();
+    let _second = server.login();
+
+    let mut third = server.connect();
+    third.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], "");
+    let error = third.recv();
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.header("message"), Some("server busy"));
+
+    // A worker is free again once a client leaves
+    first.send("DISCONNECT", &[("receipt", "bye")], "");
+    assert_eq!(first.recv().command, "RECEIPT");
+    assert!(wait_until(|| {
+        let mut client = server.connect();
+        client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], "");
+        client.recv().command == "CONNECTED"
+    }));
+}
+
+#[test]
+fn many_short_lived_connections_are_served() {
+    let server = TestServer::start_with_args(&["--workers", "4"]);
+
+    let mut connected = 0;
+    for _ in 0..1000 {
+        let mut client = server.connect();
+        client.send("CONNECT", &[("accept-version", "1.2"), ("host", "localhost")], "");
+        let frame = client.recv();
+        // A client can come back before the worker the last one had has finished with it
+        if frame.command == "CONNECTED" {
+            connected += 1;
+            client.send("DISCONNECT", &[("receipt", "bye")], "");
+            assert_eq!(client.recv().command, "RECEIPT");
+        } else {
+            assert_eq!(frame.header("message"), Some("server busy"));
+        }
+    }
+    assert!(connected > 0);
+
+    // Every connection gives its worker back, and no threads are left behind
+    assert!(wait_until(|| server.metrics.connections() == 0));
+    if let Some(threads) = thread_count() {
+        assert!(threads < 50, "{} threads left running", threads);
+    }
+    server.login();
+}